@@ -0,0 +1,140 @@
+use std::{path::PathBuf, time::Duration};
+
+use clap::{Parser, Subcommand};
+use rumqttc::{AsyncClient, Event, EventLoop, Packet, QoS};
+use tokio::time;
+
+use crate::{
+  config::Config,
+  door::{config::DoorConfig, detector::AnyDoorDetector, state::DoorCommand},
+  error::{GarageError, GarageResult},
+  mqtt_client::MqttClient,
+};
+
+/// How long one-shot commands wait on the broker before giving up
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Control garage doors over MQTT
+#[derive(Debug, Parser)]
+#[command(version, about)]
+pub struct Cli {
+  /// The configuration file to use
+  #[arg(short, long, global = true, default_value = "garage-config.toml")]
+  pub config: PathBuf,
+
+  #[command(subcommand)]
+  pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+  /// Run the service, the default if no command is given
+  Run,
+  /// Check the configuration file is valid, reporting any problems
+  CheckConfig,
+  /// Ask a running instance to open a door
+  Open { door: String },
+  /// Ask a running instance to close a door
+  Close { door: String },
+  /// Ask a running instance to stop a door while it's moving
+  Stop { door: String },
+  /// Ask a running instance to keep a door open until it's next closed, rather than closing it automatically
+  HoldOpen { door: String },
+  /// Ask a running instance to open and close a closed door, measuring how long it takes.
+  ///
+  /// The measurements are saved alongside the door's state, and used in place of the configured durations once the
+  /// instance is restarted or reloaded.
+  Calibrate { door: String },
+  /// Print a door's state, as last published by a running instance
+  Status { door: String },
+}
+
+/// Parse and validate the configuration
+pub fn check_config(config: &Config) -> GarageResult<()> {
+  config.validate()?;
+  println!("Configuration is valid ({} doors)", config.doors.len());
+  Ok(())
+}
+
+fn door_config<'a>(config: &'a Config, door: &str) -> GarageResult<&'a DoorConfig<AnyDoorDetector>> {
+  config
+    .doors
+    .get(door)
+    .ok_or_else(|| GarageError::Command(format!("no door named {} is configured", door)))
+}
+
+/// Connect to the broker as a separate client to the running instance, without a last will
+fn connect(config: &Config) -> GarageResult<(AsyncClient, EventLoop)> {
+  let id = format!("mqtt-garage-cli-{}", std::process::id());
+  Ok(AsyncClient::new(MqttClient::options(&id, &config.mqtt_client)?, 10))
+}
+
+/// Poll the event loop until `until` returns a value, or the command times out
+async fn poll_until<T>(event_loop: &mut EventLoop, mut until: impl FnMut(Packet) -> Option<T>) -> GarageResult<T> {
+  time::timeout(COMMAND_TIMEOUT, async {
+    loop {
+      if let Event::Incoming(packet) = event_loop.poll().await? {
+        if let Some(value) = until(packet) {
+          return Ok(value);
+        }
+      }
+    }
+  })
+  .await
+  .map_err(|_| GarageError::Command("timed out waiting for the MQTT broker".to_owned()))?
+}
+
+async fn disconnect(client: AsyncClient, mut event_loop: EventLoop) {
+  // the disconnect is only sent once the event loop is polled
+  if client.disconnect().await.is_ok() {
+    event_loop.poll().await.ok();
+  }
+}
+
+/// Send a command to a door via the broker, returning once the broker has received it
+pub async fn send_command(config: &Config, door: &str, command: DoorCommand) -> GarageResult<()> {
+  let command_topic = &door_config(config, door)?.controller.command_topic;
+  if rumqttc::has_wildcards(command_topic) {
+    return Err(GarageError::Command(format!(
+      "{}'s command topic {} is a wildcard filter, send the command to the door's topic directly",
+      door, command_topic
+    )));
+  }
+
+  let (client, mut event_loop) = connect(config)?;
+  client
+    .publish(command_topic, QoS::AtLeastOnce, false, command.to_string())
+    .await?;
+  poll_until(&mut event_loop, |packet| {
+    matches!(packet, Packet::PubAck(_)).then_some(())
+  })
+  .await?;
+  disconnect(client, event_loop).await;
+
+  println!("Sent {} to {}", command, door);
+  Ok(())
+}
+
+/// Print a door's state from its retained state topic
+pub async fn print_status(config: &Config, door: &str) -> GarageResult<()> {
+  let state_topic = &door_config(config, door)?.controller.state_topic;
+
+  let (client, mut event_loop) = connect(config)?;
+  client.subscribe(state_topic, QoS::AtLeastOnce).await?;
+  let state = poll_until(&mut event_loop, |packet| match packet {
+    Packet::Publish(publish) if &publish.topic == state_topic => {
+      Some(String::from_utf8_lossy(&publish.payload).into_owned())
+    }
+    _ => None,
+  })
+  .await
+  .map_err(|err| match err {
+    // the state is retained, so if it hasn't arrived by now it was never published
+    GarageError::Command(_) => GarageError::Command(format!("{} hasn't published its state", door)),
+    err => err,
+  })?;
+  disconnect(client, event_loop).await;
+
+  println!("{}: {}", door, state);
+  Ok(())
+}
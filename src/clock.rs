@@ -0,0 +1,79 @@
+use std::{fmt, pin::Pin};
+
+use chrono::{DateTime, NaiveDateTime, TimeZone, Utc};
+use chrono_tz::Tz;
+use tokio::time::{self, Instant, Sleep};
+
+/// The wall clock time, which can be replaced to test time of day dependent behaviour
+pub trait Clock: fmt::Debug + Send + Sync {
+  fn now(&self) -> DateTime<Utc>;
+}
+
+/// Time zones are UTC unless configured otherwise
+pub fn default_time_zone() -> Tz {
+  Tz::UTC
+}
+
+/// The system's clock
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+  fn now(&self) -> DateTime<Utc> {
+    Utc::now()
+  }
+}
+
+/// Sleep until `clock` reaches `time`, which may have already passed.
+///
+/// The sleep doesn't follow changes to the clock, so anything woken by it should check the time is as expected.
+pub fn sleep_until(clock: &dyn Clock, time: DateTime<Utc>) -> Pin<Box<Sleep>> {
+  let delay = (time - clock.now()).to_std().unwrap_or_default();
+  Box::pin(time::sleep_until(Instant::now() + delay))
+}
+
+/// The moment a local time happens in `time_zone`.
+///
+/// A time which happens twice as the clocks go back is the first of them, and a time skipped as the clocks go forward
+/// is an hour later, as if the clocks hadn't changed yet.
+pub fn resolve_local(time_zone: Tz, local: NaiveDateTime) -> Option<DateTime<Utc>> {
+  time_zone
+    .from_local_datetime(&local)
+    .earliest()
+    .or_else(|| {
+      time_zone
+        .from_local_datetime(&(local + chrono::Duration::hours(1)))
+        .earliest()
+    })
+    .map(|time| time.with_timezone(&Utc))
+}
+
+/// A clock for tests, which moves on with tokio's clock (so it can be paused and advanced) and can be changed
+#[cfg(test)]
+#[derive(Debug)]
+pub struct FakeClock {
+  /// The time the clock was last set to, and when
+  set: std::sync::Mutex<(DateTime<Utc>, Instant)>,
+}
+
+#[cfg(test)]
+impl FakeClock {
+  pub fn new(time: DateTime<Utc>) -> std::sync::Arc<Self> {
+    std::sync::Arc::new(FakeClock {
+      set: std::sync::Mutex::new((time, Instant::now())),
+    })
+  }
+
+  /// Change the clock, as if it had been corrected
+  pub fn set(&self, time: DateTime<Utc>) {
+    *self.set.lock().unwrap() = (time, Instant::now());
+  }
+}
+
+#[cfg(test)]
+impl Clock for FakeClock {
+  fn now(&self) -> DateTime<Utc> {
+    let (time, set) = *self.set.lock().unwrap();
+    time + chrono::Duration::from_std(set.elapsed()).unwrap()
+  }
+}
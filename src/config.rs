@@ -1,18 +1,76 @@
-use std::collections::HashMap;
+use std::{
+  collections::HashMap,
+  fs,
+  path::{Path, PathBuf},
+  time::Duration,
+};
 
 use serde::Deserialize;
+use serde_with::{serde_as, DurationSeconds};
 
 use crate::{
-  door::{self, detector::AnyDoorDetector},
+  door::{self, controller::calibration::Calibration, detector::AnyDoorDetector, discovery::DiscoveryConfig},
+  error::{GarageError, GarageResult},
   mqtt_client::MqttClientConfig,
 };
 
 pub mod gpio;
+pub mod validation;
+
+fn default_door_initialisation_timeout() -> Duration {
+  Duration::from_secs(10)
+}
+
+fn default_state_directory() -> PathBuf {
+  PathBuf::from(".")
+}
 
+#[serde_as]
 #[derive(Debug, Deserialize)]
 pub struct Config {
   /// The MQTT configuration
   pub mqtt_client: MqttClientConfig,
+  #[serde_as(as = "DurationSeconds<u64>")]
+  #[serde(default = "default_door_initialisation_timeout")]
+  /// How long to wait for a door's detector to report its state before starting with an unknown state, 10 seconds by
+  /// default
+  pub door_initialisation_timeout: Duration,
+  #[serde(default = "default_state_directory")]
+  /// The directory each door's state is saved in so it can be restored after a restart, the working directory by
+  /// default
+  pub state_directory: PathBuf,
+  /// Home Assistant MQTT discovery, disabled if not set
+  pub discovery: Option<DiscoveryConfig>,
   /// A list of all doors to control
   pub doors: HashMap<String, door::config::DoorConfig<AnyDoorDetector>>,
 }
+
+impl Config {
+  /// Read and parse the configuration file, without validating it.
+  ///
+  /// The durations of any doors which have been calibrated are replaced by the calibrated ones.
+  pub fn load(path: &Path) -> GarageResult<Config> {
+    let config = fs::read_to_string(path).map_err(|source| GarageError::ConfigRead {
+      path: path.to_owned(),
+      source,
+    })?;
+    let mut config: Config = toml::from_str(&config).map_err(|source| GarageError::ConfigParse {
+      path: path.to_owned(),
+      source,
+    })?;
+
+    for (identifier, door) in &mut config.doors {
+      let calibration_path = Calibration::path(&config.state_directory, &identifier.clone().into());
+      if let Some(calibration) = Calibration::load(&calibration_path) {
+        log::debug!(
+          "Using door {}'s calibration from {}",
+          identifier,
+          calibration_path.display()
+        );
+        door.controller.apply_calibration(&calibration);
+      }
+    }
+
+    Ok(config)
+  }
+}
@@ -1,8 +1,10 @@
 use serde::Deserialize;
 
+pub mod debounce;
+
 /// Mapping of GPIO pin names to their actual pin number
 /// See: https://pinout.xyz/
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum GpioPin {
   Gpio2,
   Gpio3,
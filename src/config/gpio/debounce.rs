@@ -0,0 +1,214 @@
+//! Debouncing of GPIO input pins without blocking the async runtime.
+//!
+//! Pin changes are detected by interrupts (or polling on the mock GPIO), after which the pin is read repeatedly until it
+//! settles.
+
+use std::time::Duration;
+
+#[cfg(feature = "arm")]
+use rppal::gpio::{InputPin, Trigger};
+use serde::Deserialize;
+use serde_with::{serde_as, DurationSecondsWithFrac};
+use tokio::{
+  select,
+  sync::mpsc::{self, UnboundedReceiver},
+  task::JoinHandle,
+  time::{sleep, Instant},
+};
+
+use crate::error::GarageResult;
+#[cfg(not(feature = "arm"))]
+use crate::mock_gpio::{InputPin, Trigger};
+
+fn default_settle_time() -> Duration {
+  Duration::from_millis(20)
+}
+
+fn default_min_consecutive() -> usize {
+  10
+}
+
+fn default_flapping_time() -> Duration {
+  Duration::from_secs(1)
+}
+
+#[serde_as]
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct DebounceConfig {
+  #[serde_as(as = "DurationSecondsWithFrac<f64>")]
+  #[serde(default = "default_settle_time")]
+  /// How long to wait between readings while the pin settles, 0.02 seconds by default
+  pub settle_time: Duration,
+
+  #[serde(default = "default_min_consecutive")]
+  /// How many identical readings in a row are needed for the pin to be considered settled, 10 by default
+  pub min_consecutive: usize,
+
+  #[serde_as(as = "DurationSecondsWithFrac<f64>")]
+  #[serde(default = "default_flapping_time")]
+  /// If the pin hasn't settled after this long it is considered to be flapping, 1 second by default
+  pub flapping_time: Duration,
+}
+
+impl Default for DebounceConfig {
+  fn default() -> Self {
+    DebounceConfig {
+      settle_time: default_settle_time(),
+      min_consecutive: default_min_consecutive(),
+      flapping_time: default_flapping_time(),
+    }
+  }
+}
+
+/// The debounced state of an input pin
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputState {
+  High,
+  Low,
+  /// The pin didn't settle within the flapping time
+  Flapping,
+}
+
+#[derive(Debug)]
+pub struct DebouncedInput {
+  pin: InputPin,
+  config: DebounceConfig,
+  /// Receives a message each time the pin changes level
+  edge_rx: UnboundedReceiver<()>,
+}
+
+impl DebouncedInput {
+  pub fn new(mut pin: InputPin, config: DebounceConfig) -> GarageResult<Self> {
+    let (edge_tx, edge_rx) = mpsc::unbounded_channel();
+    // the callback is called from rppal's interrupt thread, an unbounded send never blocks it
+    pin.set_async_interrupt(Trigger::Both, move |_| {
+      edge_tx.send(()).ok();
+    })?;
+
+    Ok(DebouncedInput { pin, config, edge_rx })
+  }
+
+  fn read(&self) -> InputState {
+    if self.pin.is_high() {
+      InputState::High
+    }
+    else {
+      InputState::Low
+    }
+  }
+
+  /// Take readings until the pin settles, or return [`InputState::Flapping`] if it doesn't in time
+  pub async fn settle(&mut self) -> InputState {
+    let started = Instant::now();
+    let mut previous_state = self.read();
+    let mut consecutive = 1;
+
+    while consecutive < self.config.min_consecutive {
+      if started.elapsed() >= self.config.flapping_time {
+        return InputState::Flapping;
+      }
+
+      sleep(self.config.settle_time).await;
+      let state = self.read();
+      if state == previous_state {
+        consecutive += 1;
+      }
+      else {
+        previous_state = state;
+        consecutive = 1;
+      }
+    }
+
+    previous_state
+  }
+
+  /// Listen to debounced changes of the pin, sending any changes along the returned channel.
+  ///
+  /// Also returns the initial settled state, and a handle which completes once the pin has been released.
+  pub async fn listen(mut self) -> (InputState, UnboundedReceiver<InputState>, JoinHandle<()>) {
+    let (state_tx, state_rx) = mpsc::unbounded_channel();
+    let initial_state = self.settle().await;
+
+    let handle = tokio::spawn(async move {
+      let mut previous_state = initial_state;
+      loop {
+        // a flapping pin may stop changing part way through, so keep reading until it settles
+        if previous_state == InputState::Flapping {
+          // there's no edge to wait for, so check nothing has stopped listening to us in the meantime
+          if state_tx.is_closed() {
+            break;
+          }
+        }
+        else {
+          select! {
+            edge = self.edge_rx.recv() => if edge.is_none() {
+              // interrupt ended
+              break;
+            },
+            // stop listening (releasing the pin) once nothing is listening to us
+            _ = state_tx.closed() => break,
+          }
+        }
+        // settling reads the pin after the latest edge anyway, so there is no need to handle each queued edge
+        while self.edge_rx.try_recv().is_ok() {}
+
+        let state = self.settle().await;
+        if state != previous_state {
+          previous_state = state;
+          if state_tx.send(state).is_err() {
+            // channel ended
+            break;
+          }
+        }
+      }
+    });
+
+    (initial_state, state_rx, handle)
+  }
+}
+
+#[cfg(all(test, not(feature = "arm")))]
+mod tests {
+  use std::time::Duration;
+
+  use tokio::time::timeout;
+
+  use super::{DebounceConfig, DebouncedInput, InputState};
+  use crate::mock_gpio::{set_test_levels, Gpio, Level};
+
+  fn input(pin: u8, levels: &[Level]) -> DebouncedInput {
+    set_test_levels(pin, levels);
+    let config = DebounceConfig {
+      settle_time: Duration::from_millis(1),
+      min_consecutive: 3,
+      flapping_time: Duration::from_millis(10),
+    };
+    DebouncedInput::new(Gpio::new().unwrap().get(pin).unwrap().into_input_pullup(), config).unwrap()
+  }
+
+  #[tokio::test]
+  async fn settles_on_a_steady_level() {
+    assert_eq!(input(200, &[Level::High]).settle().await, InputState::High);
+    assert_eq!(input(201, &[Level::Low]).settle().await, InputState::Low);
+  }
+
+  #[tokio::test]
+  async fn flapping_pin_doesnt_settle() {
+    assert_eq!(
+      input(202, &[Level::High, Level::Low]).settle().await,
+      InputState::Flapping
+    );
+  }
+
+  #[tokio::test]
+  async fn releases_a_flapping_pin_once_nothing_is_listening() {
+    let (initial_state, state_rx, handle) = input(203, &[Level::High, Level::Low]).listen().await;
+    assert_eq!(initial_state, InputState::Flapping);
+
+    drop(state_rx);
+    timeout(Duration::from_secs(1), handle)
+      .await
+      .expect("the pin wasn't released")
+      .unwrap();
+  }
+}
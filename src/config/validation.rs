@@ -0,0 +1,594 @@
+use std::{collections::BTreeMap, fmt, time::Duration};
+
+use super::{gpio::GpioPin, Config};
+use crate::{
+  door::{
+    controller::schedule::RuleTime,
+    detector::{dual::LimitSensorConfig, gpio::GpioDoorDetectorConfig, DoorDetectorConfig},
+  },
+  error::{GarageError, GarageResult},
+  mqtt_client::receiver::filters_overlap,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+  /// The doors can't work as configured
+  Error,
+  /// The doors might work, but likely not as intended
+  Warning,
+}
+
+/// A problem with the configuration that parsing alone can't catch
+#[derive(Debug)]
+pub struct ConfigIssue {
+  pub severity: Severity,
+  /// The identifiers of the doors the problem involves
+  pub doors: Vec<String>,
+  pub problem: String,
+}
+
+impl fmt::Display for ConfigIssue {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self.severity {
+      Severity::Error => write!(f, "{}: {}", self.doors.join(", "), self.problem),
+      Severity::Warning => write!(f, "{} (warning): {}", self.doors.join(", "), self.problem),
+    }
+  }
+}
+
+/// Collects what uses each value (e.g. a pin), so values used more than once can be reported
+struct Usages<T>(BTreeMap<T, Vec<(String, &'static str)>>);
+
+impl<T: Ord> Usages<T> {
+  fn new() -> Self {
+    Usages(BTreeMap::new())
+  }
+
+  fn add(&mut self, value: T, door: &str, usage: &'static str) {
+    self.0.entry(value).or_default().push((door.to_owned(), usage));
+  }
+
+  /// An issue for each value used more than once, unless it's only used by several doors for the same one of the
+  /// `shared` usages
+  fn duplicates(
+    self,
+    shared: &'static [&'static str],
+    describe: impl Fn(T) -> String,
+  ) -> impl Iterator<Item = ConfigIssue> {
+    self
+      .0
+      .into_iter()
+      .filter(move |(_, users)| {
+        let usage = users[0].1;
+        users.len() > 1 && !(shared.contains(&usage) && users.iter().all(|(_, other_usage)| *other_usage == usage))
+      })
+      .map(move |(value, users)| {
+        let mut doors: Vec<String> = users.iter().map(|(door, _)| door.clone()).collect();
+        doors.dedup();
+        let users: Vec<String> = users
+          .iter()
+          .map(|(door, usage)| format!("{}'s {}", door, usage))
+          .collect();
+        ConfigIssue {
+          severity: Severity::Error,
+          doors,
+          problem: format!("{} is used more than once, by {}", describe(value), users.join(" and ")),
+        }
+      })
+  }
+}
+
+/// The topics several doors can use for the same thing, each of them acting on every message (e.g. to skip every
+/// door's next scheduled move at once).
+///
+/// Command topics aren't shared, as the door they're sent to would be ambiguous (e.g. when sent from the command line
+/// or Home Assistant).
+const SHARED_TOPICS: &[&str] = &["set_position_topic", "schedule.override_topic"];
+
+/// The GPIO sensors of a detector, along with what uses them
+fn gpio_sensors<'a>(detector: &'a DoorDetectorConfig) -> Vec<(&'a GpioDoorDetectorConfig, &'static str)> {
+  let limit_sensor = |sensor: &'a LimitSensorConfig| match sensor {
+    LimitSensorConfig::Gpio(config) => Some(config),
+    LimitSensorConfig::Zigbee2Mqtt(_) => None,
+  };
+
+  match detector {
+    DoorDetectorConfig::Gpio(config) => vec![(config, "detector")],
+    DoorDetectorConfig::Dual(config) => [
+      limit_sensor(&config.closed_sensor).map(|sensor| (sensor, "closed sensor")),
+      limit_sensor(&config.open_sensor).map(|sensor| (sensor, "open sensor")),
+    ]
+    .into_iter()
+    .flatten()
+    .collect(),
+    DoorDetectorConfig::Assumed(_) | DoorDetectorConfig::Zigbee2Mqtt(_) => Vec::new(),
+  }
+}
+
+/// The topics a detector subscribes to, along with what uses them
+fn detector_topics<'a>(detector: &'a DoorDetectorConfig) -> Vec<(&'a String, &'static str)> {
+  let limit_sensor = |sensor: &'a LimitSensorConfig| match sensor {
+    LimitSensorConfig::Gpio(_) => None,
+    LimitSensorConfig::Zigbee2Mqtt(config) => Some(&config.sensor_topic),
+  };
+
+  match detector {
+    DoorDetectorConfig::Zigbee2Mqtt(config) => vec![(&config.sensor_topic, "sensor_topic")],
+    DoorDetectorConfig::Assumed(config) => vec![(&config.override_topic, "detector override_topic")],
+    DoorDetectorConfig::Dual(config) => [
+      limit_sensor(&config.closed_sensor).map(|topic| (topic, "closed_sensor.sensor_topic")),
+      limit_sensor(&config.open_sensor).map(|topic| (topic, "open_sensor.sensor_topic")),
+    ]
+    .into_iter()
+    .flatten()
+    .collect(),
+    DoorDetectorConfig::Gpio(_) => Vec::new(),
+  }
+}
+
+/// Why a pin shouldn't be used, if it has another purpose on the Pi
+fn reserved_pin(pin: GpioPin) -> Option<&'static str> {
+  match pin {
+    GpioPin::Gpio14 | GpioPin::Gpio15 => Some("used by the UART (serial console)"),
+    GpioPin::Gpio0 | GpioPin::Gpio1 => Some("reserved for HAT identification"),
+    _ => None,
+  }
+}
+
+impl Config {
+  /// Check the configuration for conflicts and impossible values, returning every issue found
+  pub fn issues(&self) -> Vec<ConfigIssue> {
+    let mut issues = Vec::new();
+    let mut pins = Usages::new();
+    let mut topics = Usages::new();
+
+    // sorted so issues are always reported in the same order
+    let mut doors: Vec<_> = self.doors.iter().collect();
+    doors.sort_by_key(|(identifier, _)| identifier.as_str());
+
+    for (identifier, door) in &doors {
+      let controller = &door.controller;
+
+      let door_pins = gpio_sensors(&door.detector)
+        .into_iter()
+        .map(|(sensor, usage)| (sensor.pin, usage))
+        .chain(controller.remote.pins());
+      for (pin, usage) in door_pins {
+        pins.add(pin.bcm_number(), identifier, usage);
+        if let Some(reason) = reserved_pin(pin) {
+          issues.push(ConfigIssue {
+            severity: Severity::Warning,
+            doors: vec![identifier.to_string()],
+            problem: format!("{} pin GPIO{} is {}", usage, pin.bcm_number(), reason),
+          });
+        }
+      }
+
+      for (topic, usage) in [
+        (Some(&controller.command_topic), "command_topic"),
+        (Some(&controller.state_topic), "state_topic"),
+        (controller.stuck_topic.as_ref(), "stuck_topic"),
+        (controller.availability_topic.as_ref(), "availability_topic"),
+        (controller.diagnostics_topic.as_ref(), "diagnostics_topic"),
+        (controller.position_topic.as_ref(), "position_topic"),
+        (controller.set_position_topic.as_ref(), "set_position_topic"),
+        (
+          controller
+            .auto_close
+            .as_ref()
+            .and_then(|auto_close| auto_close.warning_topic.as_ref()),
+          "auto_close.warning_topic",
+        ),
+        (
+          controller
+            .schedule
+            .as_ref()
+            .and_then(|schedule| schedule.override_topic.as_ref()),
+          "schedule.override_topic",
+        ),
+      ] {
+        if let Some(topic) = topic {
+          topics.add(topic.clone(), identifier, usage);
+        }
+      }
+
+      for (sensor, usage) in gpio_sensors(&door.detector) {
+        let debounce = &sensor.debounce;
+        // the pin is read `min_consecutive` times, `settle_time` apart, before it has settled
+        if debounce.settle_time.as_secs_f64() * debounce.min_consecutive as f64 > debounce.flapping_time.as_secs_f64() {
+          issues.push(ConfigIssue {
+            severity: Severity::Error,
+            doors: vec![identifier.to_string()],
+            problem: format!(
+              "{}'s debounce can't settle, settle_time × min_consecutive is longer than flapping_time",
+              usage
+            ),
+          });
+        }
+      }
+
+      for (name, duration) in [
+        ("travel_duration", controller.travel_duration),
+        ("remote.pressed_time", Some(controller.remote.pressed_time)),
+        (
+          "max_remote_latency_duration",
+          Some(controller.max_remote_latency_duration),
+        ),
+        ("open_duration", controller.open_duration),
+        ("close_duration", controller.close_duration),
+        (
+          "auto_close.close_after",
+          controller.auto_close.as_ref().map(|auto_close| auto_close.close_after),
+        ),
+      ] {
+        if duration == Some(Duration::ZERO) {
+          issues.push(ConfigIssue {
+            severity: Severity::Error,
+            doors: vec![identifier.to_string()],
+            problem: format!("{} must be greater than zero", name),
+          });
+        }
+      }
+
+      for (name, duration) in [
+        ("open_duration", controller.open_duration),
+        ("close_duration", controller.close_duration),
+      ] {
+        if duration.or(controller.travel_duration).is_none() {
+          issues.push(ConfigIssue {
+            severity: Severity::Error,
+            doors: vec![identifier.to_string()],
+            problem: format!("{} must be set, or travel_duration for both directions", name),
+          });
+        }
+      }
+
+      if let Some(window) = controller
+        .auto_close
+        .as_ref()
+        .and_then(|auto_close| auto_close.window.as_ref())
+      {
+        if window.start == window.end {
+          issues.push(ConfigIssue {
+            severity: Severity::Error,
+            doors: vec![identifier.to_string()],
+            problem: "auto_close.window must start and end at different times".to_owned(),
+          });
+        }
+      }
+
+      if let Some(schedule) = &controller.schedule {
+        if schedule.rules.is_empty() {
+          issues.push(ConfigIssue {
+            severity: Severity::Warning,
+            doors: vec![identifier.to_string()],
+            problem: "schedule has no rules, so it will never move the door".to_owned(),
+          });
+        }
+
+        let uses_sun = schedule
+          .rules
+          .iter()
+          .any(|rule| matches!(rule.at, RuleTime::Sun { .. }));
+        match schedule.location {
+          None if uses_sun => issues.push(ConfigIssue {
+            severity: Severity::Error,
+            doors: vec![identifier.to_string()],
+            problem: "schedule.location must be set for rules relative to sunrise or sunset".to_owned(),
+          }),
+          Some(location) if !(-90.0..=90.0).contains(&location.latitude) => issues.push(ConfigIssue {
+            severity: Severity::Error,
+            doors: vec![identifier.to_string()],
+            problem: "schedule.location.latitude must be between -90 and 90 degrees".to_owned(),
+          }),
+          Some(location) if !(-180.0..=180.0).contains(&location.longitude) => issues.push(ConfigIssue {
+            severity: Severity::Error,
+            doors: vec![identifier.to_string()],
+            problem: "schedule.location.longitude must be between -180 and 180 degrees".to_owned(),
+          }),
+          _ => {}
+        }
+      }
+
+      // commands are published to these topics (e.g. by Home Assistant), so each must be a single topic
+      for (topic, usage) in [
+        (Some(&controller.command_topic), "command_topic"),
+        (controller.set_position_topic.as_ref(), "set_position_topic"),
+      ] {
+        if let Some(topic) = topic.filter(|topic| rumqttc::has_wildcards(topic)) {
+          issues.push(ConfigIssue {
+            severity: Severity::Error,
+            doors: vec![identifier.to_string()],
+            problem: format!("{} {} can't have wildcards, commands are published to it", usage, topic),
+          });
+        }
+      }
+
+      if controller.set_position_topic.is_some() && !controller.remote.can_stop() {
+        issues.push(ConfigIssue {
+          severity: Severity::Error,
+          doors: vec![identifier.to_string()],
+          problem: "set_position_topic requires a remote which can stop the door, i.e. a toggle remote or a stop_pin"
+            .to_owned(),
+        });
+      }
+    }
+
+    // the topics which are only subscribed to, and could receive a door's commands
+    let subscribed_topics: Vec<_> = doors
+      .iter()
+      .flat_map(|(identifier, door)| {
+        let override_topic = door
+          .controller
+          .schedule
+          .as_ref()
+          .and_then(|schedule| schedule.override_topic.as_ref())
+          .map(|topic| (topic, "schedule.override_topic"));
+        detector_topics(&door.detector)
+          .into_iter()
+          .chain(override_topic)
+          .map(move |(topic, usage)| (identifier.as_str(), topic, usage))
+      })
+      .collect();
+    for (identifier, door) in &doors {
+      let command_topics = [
+        (Some(&door.controller.command_topic), "command_topic"),
+        (door.controller.set_position_topic.as_ref(), "set_position_topic"),
+      ];
+      for (command_topic, command_usage) in command_topics
+        .into_iter()
+        .filter_map(|(topic, usage)| Some((topic?, usage)))
+      {
+        for (other_identifier, topic, usage) in &subscribed_topics {
+          // identical controller topics are already reported as being used more than once
+          let reported = *usage == "schedule.override_topic" && topic == &command_topic;
+          if !reported && filters_overlap(topic, command_topic) {
+            let mut doors = vec![identifier.to_string(), other_identifier.to_string()];
+            doors.sort();
+            doors.dedup();
+            issues.push(ConfigIssue {
+              severity: Severity::Error,
+              doors,
+              problem: format!(
+                "{}'s {} {} overlaps {}'s {} {}, so they would receive each other's messages",
+                other_identifier, usage, topic, identifier, command_usage, command_topic
+              ),
+            });
+          }
+        }
+      }
+    }
+
+    issues.extend(pins.duplicates(&[], |pin| format!("pin GPIO{}", pin)));
+    issues.extend(topics.duplicates(SHARED_TOPICS, |topic| format!("topic {}", topic)));
+
+    issues
+  }
+
+  /// Fail with every issue found if the configuration has any errors, logging any warnings
+  pub fn validate(&self) -> GarageResult<()> {
+    let (errors, warnings): (Vec<_>, Vec<_>) = self
+      .issues()
+      .into_iter()
+      .partition(|issue| issue.severity == Severity::Error);
+    for warning in warnings {
+      log::warn!("{}", warning);
+    }
+
+    if errors.is_empty() {
+      Ok(())
+    }
+    else {
+      Err(GarageError::InvalidConfig(errors))
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::time::Duration;
+
+  use toml::Value;
+
+  use crate::config::Config;
+
+  /// Two doors with nothing wrong with them
+  const DOORS: &str = r#"
+    [mqtt_client]
+    broker_domain = "localhost"
+    broker_port = 1883
+    availability_topic = "garage/availability"
+    online_availability = "online"
+    offline_availability = "offline"
+
+    [doors.left.detector]
+    pin = "Gpio4"
+    [doors.left.controller]
+    command_topic = "garage/left/set"
+    state_topic = "garage/left/state"
+    travel_duration = 10
+    max_remote_latency_duration = 2
+    [doors.left.controller.remote]
+    pin = "Gpio17"
+    pressed_time = 0.5
+    wait_time = 0.5
+
+    [doors.right.detector]
+    pin = "Gpio5"
+    [doors.right.controller]
+    command_topic = "garage/right/set"
+    state_topic = "garage/right/state"
+    travel_duration = 10
+    max_remote_latency_duration = 2
+    [doors.right.controller.remote]
+    pin = "Gpio27"
+    pressed_time = 0.5
+    wait_time = 0.5
+  "#;
+
+  /// Merge `changes` into `value`, replacing anything that isn't a table
+  fn merge(value: &mut Value, changes: Value) {
+    match (value, changes) {
+      (Value::Table(table), Value::Table(changes)) => {
+        for (key, change) in changes {
+          match table.get_mut(&key) {
+            Some(value) => merge(value, change),
+            None => {
+              table.insert(key, change);
+            }
+          }
+        }
+      }
+      (value, change) => *value = change,
+    }
+  }
+
+  /// [`DOORS`] with `changes` made to it
+  fn config(changes: &str) -> Config {
+    let mut config: Value = toml::from_str(DOORS).unwrap();
+    merge(&mut config, toml::from_str(changes).unwrap());
+    config.try_into().unwrap()
+  }
+
+  fn issues(config: &Config) -> Vec<String> {
+    config.issues().iter().map(ToString::to_string).collect()
+  }
+
+  #[test]
+  fn issues_found() {
+    for (changes, expected) in [
+      ("", vec![]),
+      (
+        r#"
+        [doors.right.controller.remote]
+        pin = "Gpio4"
+        "#,
+        vec!["left, right: pin GPIO4 is used more than once, by left's detector and right's remote"],
+      ),
+      (
+        r#"
+        [doors.left.detector]
+        pin = "Gpio14"
+        "#,
+        vec!["left (warning): detector pin GPIO14 is used by the UART (serial console)"],
+      ),
+      (
+        r#"
+        [doors.right.controller]
+        command_topic = "garage/left/set"
+        "#,
+        vec![
+          "left, right: topic garage/left/set is used more than once, by left's command_topic and right's \
+           command_topic",
+        ],
+      ),
+      (
+        r#"
+        [doors.left.controller]
+        command_topic = "garage/+/set"
+        "#,
+        vec!["left: command_topic garage/+/set can't have wildcards, commands are published to it"],
+      ),
+      (
+        r#"
+        [doors.left.controller]
+        travel_duration = 0
+        max_remote_latency_duration = 0
+        remote = { pressed_time = 0 }
+        "#,
+        vec![
+          "left: travel_duration must be greater than zero",
+          "left: remote.pressed_time must be greater than zero",
+          "left: max_remote_latency_duration must be greater than zero",
+        ],
+      ),
+      (
+        r#"
+        [doors.left.detector]
+        debounce = { settle_time = 0.1, min_consecutive = 20 }
+        "#,
+        vec!["left: detector's debounce can't settle, settle_time × min_consecutive is longer than flapping_time"],
+      ),
+      (
+        r#"
+        [doors.left.controller.schedule]
+        rules = [{ target_state = "CLOSED", sun = "sunset" }]
+        "#,
+        vec!["left: schedule.location must be set for rules relative to sunrise or sunset"],
+      ),
+      (
+        r#"
+        [doors.middle.detector]
+        pin = "Gpio6"
+        [doors.middle.controller]
+        command_topic = "garage/middle/set"
+        state_topic = "garage/middle/state"
+        set_position_topic = "garage/middle/position/set"
+        travel_duration = 10
+        max_remote_latency_duration = 2
+        [doors.middle.controller.remote]
+        open_pin = "Gpio22"
+        close_pin = "Gpio23"
+        pressed_time = 0.5
+        wait_time = 0.5
+        "#,
+        vec![
+          "middle: set_position_topic requires a remote which can stop the door, i.e. a toggle remote or a stop_pin",
+        ],
+      ),
+      (
+        r#"
+        [doors.left.controller.schedule]
+        override_topic = "garage/+/set"
+        rules = [{ target_state = "CLOSED", time = "22:00" }]
+        "#,
+        vec![
+          "left: left's schedule.override_topic garage/+/set overlaps left's command_topic garage/left/set, so they \
+           would receive each other's messages",
+          "left, right: left's schedule.override_topic garage/+/set overlaps right's command_topic \
+           garage/right/set, so they would receive each other's messages",
+        ],
+      ),
+      (
+        r#"
+        [doors.middle.detector]
+        travel_time = 10
+        override_topic = "garage/right/set"
+        [doors.middle.controller]
+        command_topic = "garage/middle/set"
+        state_topic = "garage/middle/state"
+        travel_duration = 10
+        max_remote_latency_duration = 2
+        [doors.middle.controller.remote]
+        pin = "Gpio22"
+        pressed_time = 0.5
+        wait_time = 0.5
+        "#,
+        vec![
+          "middle, right: middle's detector override_topic garage/right/set overlaps right's command_topic \
+           garage/right/set, so they would receive each other's messages",
+        ],
+      ),
+    ] {
+      assert_eq!(issues(&config(changes)), expected, "{}", changes);
+    }
+  }
+
+  #[test]
+  fn open_and_close_durations_are_required() {
+    let mut config = config(
+      r#"
+      [doors.left.controller]
+      open_duration = 12
+      "#,
+    );
+    config.doors.get_mut("left").unwrap().controller.travel_duration = None;
+    assert_eq!(
+      issues(&config),
+      vec!["left: close_duration must be set, or travel_duration for both directions"]
+    );
+
+    config.doors.get_mut("left").unwrap().controller.close_duration = Some(Duration::from_secs(15));
+    assert_eq!(issues(&config), Vec::<String>::new());
+  }
+}
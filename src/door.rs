@@ -1,24 +1,50 @@
-use std::sync::Arc;
+use std::{path::PathBuf, sync::Arc, time::Duration};
 
-use tokio::sync::mpsc;
+use tokio::{
+  sync::{broadcast, mpsc},
+  time,
+};
 
 use self::{
   config::DoorConfig,
-  controller::{config::DoorControllerConfig, remote::mutex::RemoteMutex, DoorController},
-  detector::DoorDetector,
+  controller::{
+    config::DoorControllerConfig, remote::mutex::RemoteMutex, ControllerChannels, ControllerCommand, DoorController,
+  },
+  detector::{DetectorChannels, DoorDetector, ReleaseGuard},
   identifier::Identifier,
-  state::DetectedState,
 };
 use crate::{
+  clock::Clock,
   error::GarageResult,
-  mqtt_client::{receiver::MqttReceiver, MqttPublish},
+  mqtt_client::{receiver::MqttSubscriber, Availability, MqttPublish},
+  systemd::ServiceMonitor,
 };
 
 pub mod config;
 pub mod controller;
 pub mod detector;
+pub mod discovery;
 pub mod identifier;
 pub mod state;
+pub mod supervisor;
+
+/// Settings shared by every door
+#[derive(Debug, Clone)]
+pub struct DoorContext {
+  pub remote_mutex: Arc<RemoteMutex>,
+  /// The payloads each door's availability is published with
+  pub availability: Availability,
+  /// How long to wait for a detector to report the door's initial state
+  pub initialisation_timeout: Duration,
+  /// Notified each time the MQTT connection is re-established
+  pub reconnected_tx: broadcast::Sender<()>,
+  /// The directory each controller's state is saved in
+  pub state_directory: PathBuf,
+  /// Where each controller reports its state and heartbeat
+  pub monitor: ServiceMonitor,
+  /// The wall clock automatic closing and schedules are run by
+  pub clock: Arc<dyn Clock>,
+}
 
 pub struct Door<D: DoorDetector> {
   pub identifier: Identifier,
@@ -26,8 +52,12 @@ pub struct Door<D: DoorDetector> {
   // we cannot initialise the controller until after the MQTT receiver starts running
   controller_mqtt_tx: mpsc::UnboundedSender<MqttPublish>,
   controller_mqtt_rx: mpsc::UnboundedReceiver<MqttPublish>,
+  controller_position_rx: Option<mpsc::UnboundedReceiver<MqttPublish>>,
+  controller_schedule_rx: Option<mpsc::UnboundedReceiver<MqttPublish>>,
+  controller_control_tx: mpsc::UnboundedSender<ControllerCommand>,
+  controller_control_rx: mpsc::UnboundedReceiver<ControllerCommand>,
   controller_config: DoorControllerConfig,
-  remote_mutex: Arc<RemoteMutex>,
+  context: DoorContext,
 }
 
 impl<D: DoorDetector> Door<D> {
@@ -35,39 +65,110 @@ impl<D: DoorDetector> Door<D> {
     identifier: Identifier,
     door_config: DoorConfig<D>,
     controller_mqtt_tx: mpsc::UnboundedSender<MqttPublish>,
-    remote_mutex: Arc<RemoteMutex>,
-    mqtt_receiver: &mut MqttReceiver,
+    context: DoorContext,
+    mqtt_subscriber: &MqttSubscriber,
   ) -> GarageResult<Self> {
-    let detector = D::new(identifier.clone(), door_config.detector, mqtt_receiver).await?;
+    let detector = D::new(
+      identifier.clone(),
+      door_config.detector,
+      mqtt_subscriber,
+      &context.state_directory,
+    )
+    .await?;
 
-    let controller_mqtt_rx = mqtt_receiver
-      .subscribe(door_config.controller.command_topic.clone(), rumqttc::QoS::AtLeastOnce)
+    let controller_mqtt_rx = mqtt_subscriber
+      .subscribe_exclusive(door_config.controller.command_topic.clone(), rumqttc::QoS::AtLeastOnce)
       .await?;
+    let controller_position_rx = match &door_config.controller.set_position_topic {
+      Some(set_position_topic) => Some(
+        mqtt_subscriber
+          .subscribe_exclusive(set_position_topic.clone(), rumqttc::QoS::AtLeastOnce)
+          .await?,
+      ),
+      None => None,
+    };
+    let override_topic = door_config
+      .controller
+      .schedule
+      .as_ref()
+      .and_then(|schedule| schedule.override_topic.clone());
+    let controller_schedule_rx = match override_topic {
+      Some(override_topic) => Some(
+        mqtt_subscriber
+          .subscribe_exclusive(override_topic, rumqttc::QoS::AtLeastOnce)
+          .await?,
+      ),
+      None => None,
+    };
+    let (controller_control_tx, controller_control_rx) = mpsc::unbounded_channel();
 
     Ok(Door {
       identifier,
       detector,
       controller_mqtt_tx,
       controller_mqtt_rx,
+      controller_position_rx,
+      controller_schedule_rx,
+      controller_control_tx,
+      controller_control_rx,
       controller_config: door_config.controller,
-      remote_mutex,
+      context,
     })
   }
 
-  pub async fn start_detector(self) -> GarageResult<(DoorController, mpsc::UnboundedReceiver<DetectedState>)> {
-    let (initial_state, detector_rx) = self.detector.listen().await?;
+  /// A channel to change the controller once it's running, see [`ControllerCommand`]
+  pub fn controller_sender(&self) -> mpsc::UnboundedSender<ControllerCommand> {
+    self.controller_control_tx.clone()
+  }
+
+  /// Start the detector and create the door's controller.
+  ///
+  /// If the detector doesn't know the door's initial state it is waited on for up to the initialisation timeout, after
+  /// which the controller starts with an unknown state (and is unavailable) until the detector reports one.
+  ///
+  /// The detector holds on to `release_guard` until it has released its pins and topics.
+  pub async fn start_detector(self, release_guard: ReleaseGuard) -> GarageResult<DoorController> {
+    let initialisation_timeout = self.context.initialisation_timeout;
+    let travel_tx = self.detector.travel_sender();
+    let health_rx = self.detector.health_receiver();
+    let (initial_state, mut state_rx) = self.detector.listen(release_guard).await?;
+
+    let initial_state = match initial_state {
+      Some(initial_state) => Some(initial_state),
+      None => {
+        let initial_state = time::timeout(initialisation_timeout, state_rx.recv())
+          .await
+          .ok()
+          .flatten();
+        if initial_state.is_none() {
+          log::warn!(
+            "{:?} detector didn't report a state within {:?}, starting with an unknown state",
+            &self.identifier,
+            initialisation_timeout
+          );
+        }
+        initial_state
+      }
+    };
 
-    Ok((
-      DoorController::new(
-        self.identifier,
-        self.controller_config,
-        self.controller_mqtt_tx,
-        self.controller_mqtt_rx,
-        self.remote_mutex,
-        initial_state.into(),
-      )
-      .await?,
-      detector_rx,
-    ))
+    DoorController::new(
+      self.identifier,
+      self.controller_config,
+      self.context,
+      ControllerChannels {
+        mqtt_tx: self.controller_mqtt_tx,
+        mqtt_rx: self.controller_mqtt_rx,
+        position_rx: self.controller_position_rx,
+        schedule_rx: self.controller_schedule_rx,
+        control_rx: self.controller_control_rx,
+      },
+      DetectorChannels {
+        state_rx,
+        health_rx,
+        travel_tx,
+      },
+      initial_state,
+    )
+    .await
   }
 }
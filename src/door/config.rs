@@ -8,3 +8,25 @@ pub struct DoorConfig<D: DoorDetector> {
   pub detector: D::Config,
   pub controller: DoorControllerConfig,
 }
+
+// derived impls would require the detector itself to be `Clone`/`PartialEq`, rather than its config
+impl<D: DoorDetector> Clone for DoorConfig<D>
+where
+  D::Config: Clone,
+{
+  fn clone(&self) -> Self {
+    DoorConfig {
+      detector: self.detector.clone(),
+      controller: self.controller.clone(),
+    }
+  }
+}
+
+impl<D: DoorDetector> PartialEq for DoorConfig<D>
+where
+  D::Config: PartialEq,
+{
+  fn eq(&self, other: &Self) -> bool {
+    self.detector == other.detector && self.controller == other.controller
+  }
+}
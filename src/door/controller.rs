@@ -1,29 +1,74 @@
-use std::{fmt, str::FromStr, sync::Arc, time::Duration};
+use std::{cmp::Ordering, fmt, ops::ControlFlow, path::PathBuf, pin::Pin, str::FromStr, time::Duration};
 
+use chrono::{DateTime, SecondsFormat};
+use chrono_tz::Tz;
 use rumqttc::QoS;
 use tokio::{
   select,
-  sync::mpsc::{self, UnboundedReceiver},
+  sync::{broadcast, mpsc::UnboundedReceiver},
+  time::{self, Sleep},
 };
 
 use self::{
-  config::DoorControllerConfig,
-  remote::{mutex::RemoteMutex, DoorRemote},
+  auto_close::{AutoClose, AutoCloseEvent},
+  calibration::Calibration,
+  config::{DoorControllerConfig, DoorTimings},
+  position::{PositionEstimate, CLOSED_POSITION, OPEN_POSITION},
+  remote::DoorRemote,
+  saved_state::{SavedDoorState, SavedState},
+  schedule::{Schedule, ScheduleEvent, ScheduleOverride},
 };
 use super::{
+  detector::{
+    health::{HealthProblem, SensorHealth},
+    DetectorChannels,
+  },
   identifier::Identifier,
-  state::{DetectedState, State, TargetState},
+  state::{DetectedState, DoorCommand, State, TargetState},
 };
 use crate::{
-  door::state::{AssumedTravel, ConfirmedTravel},
+  door::{
+    state::{AssumedTravel, ConfirmedTravel},
+    DoorContext,
+  },
   error::{GarageError, GarageResult},
-  mqtt_client::{sender::PublishSender, MqttPublish},
+  mqtt_client::{receiver::topic_matches, sender::PublishSender, Availability, MqttPublish},
+  systemd::{Heartbeat, ServiceMonitor},
 };
 
+pub mod auto_close;
+pub mod calibration;
 pub mod config;
+pub mod position;
 pub mod remote;
+pub mod saved_state;
+pub mod schedule;
 
 const MAX_STUCK_REATTEMPTS: u8 = 5;
+/// How often the estimated position is published while the door is moving
+const POSITION_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Changes made to a controller while it's running
+#[derive(Debug)]
+pub enum ControllerCommand {
+  /// Use new timings, a travel already in progress keeps its original duration
+  UpdateTimings(DoorTimings),
+  /// Stop controlling the door, marking it as unavailable
+  Stop,
+}
+
+/// The channels connecting a controller to MQTT and the rest of the service
+#[derive(Debug)]
+pub struct ControllerChannels {
+  pub mqtt_tx: PublishSender,
+  /// Publishes received on the command topic
+  pub mqtt_rx: UnboundedReceiver<MqttPublish>,
+  /// Publishes received on the set position topic, if there is one
+  pub position_rx: Option<UnboundedReceiver<MqttPublish>>,
+  /// Publishes received on the schedule's override topic, if there is one
+  pub schedule_rx: Option<UnboundedReceiver<MqttPublish>>,
+  pub control_rx: UnboundedReceiver<ControllerCommand>,
+}
 
 #[derive(Debug)]
 pub struct DoorController {
@@ -34,10 +79,46 @@ pub struct DoorController {
   command_topic: String,
   state_topic: String,
   stuck_topic: Option<String>,
-  initial_target_state: Option<TargetState>,
-  travel_duration: Duration,
+  availability_topic: Option<String>,
+  availability: Availability,
+  /// The availability last published, if any
+  published_availability: Option<bool>,
+  diagnostics_topic: Option<String>,
+  position_topic: Option<String>,
+  /// The position last published, if any
+  published_position: Option<u8>,
+  sensor_health: SensorHealth,
+  /// The state to move to once the door is no longer travelling
+  next_target_state: Option<TargetState>,
+  /// The position to move to once the door is no longer travelling
+  next_position: Option<u8>,
+  position: PositionEstimate,
+  /// The position the door is travelling to, if it's being stopped part way
+  target_position: Option<u8>,
+  /// When the door is estimated to reach the target position
+  stop_at: Option<Pin<Box<Sleep>>>,
+  auto_close: Option<AutoClose>,
+  schedule: Option<Schedule>,
   max_remote_latency_duration: Duration,
+  /// How long the door can take to open once it has started, before it has failed
+  open_limit: Duration,
+  /// How long the door can take to close once it has started, before it has failed
+  close_limit: Duration,
   mqtt_rx: UnboundedReceiver<MqttPublish>,
+  position_rx: Option<UnboundedReceiver<MqttPublish>>,
+  schedule_rx: Option<UnboundedReceiver<MqttPublish>>,
+  /// Notified when the MQTT connection is re-established
+  reconnected_rx: broadcast::Receiver<()>,
+  control_rx: UnboundedReceiver<ControllerCommand>,
+  detector: DetectorChannels,
+  /// Where the state is saved each time it changes
+  state_path: PathBuf,
+  /// Where the durations measured by calibrating the door are saved
+  calibration_path: PathBuf,
+  /// The state saved before we restarted, kept until the detector reports the door's state so it can be restored
+  saved_state: Option<SavedDoorState>,
+  monitor: ServiceMonitor,
+  heartbeat: Heartbeat,
 }
 
 impl fmt::Display for DoorController {
@@ -50,59 +131,205 @@ impl DoorController {
   pub async fn new(
     identifier: Identifier,
     config: DoorControllerConfig,
-    mqtt_tx: mpsc::UnboundedSender<MqttPublish>,
-    mqtt_rx: UnboundedReceiver<MqttPublish>,
-    remote_mutex: Arc<RemoteMutex>,
-    initial_state: State,
+    context: DoorContext,
+    channels: ControllerChannels,
+    mut detector: DetectorChannels,
+    initial_state: Option<DetectedState>,
   ) -> GarageResult<DoorController> {
-    let remote = DoorRemote::new(config.remote, remote_mutex)?;
+    let timings = config.timings();
+    let remote = DoorRemote::new(config.remote, context.remote_mutex)?;
+    let sensor_health = detector
+      .health_rx
+      .as_mut()
+      .map(|health_rx| health_rx.borrow_and_update().clone())
+      .unwrap_or_default();
+
+    let state_path = SavedState::path(&context.state_directory, &identifier);
+    let calibration_path = Calibration::path(&context.state_directory, &identifier);
+    let saved_state = SavedState::load(&state_path);
+    let auto_close = config.auto_close.map(|auto_close| {
+      AutoClose::new(
+        auto_close,
+        context.clock.clone(),
+        saved_state.as_ref().is_some_and(|saved| saved.hold_open),
+        saved_state.as_ref().and_then(|saved| saved.opened_at),
+      )
+    });
+    let schedule = config.schedule.map(|schedule| {
+      let (skip_next, holidays) = saved_state
+        .as_ref()
+        .map(|saved| (saved.skip_scheduled, saved.holidays.clone()))
+        .unwrap_or_default();
+      Schedule::new(schedule, context.clock.clone(), skip_next, holidays)
+    });
+    let (current_state, saved_state, next_target_state, next_position, position) = match saved_state {
+      // the door has run before, so only a command that never got to run is carried out, not the initial target
+      Some(saved) => match initial_state.map(|initial_state| saved.state.restore(initial_state)) {
+        Some(State::Unknown) | None => (
+          State::Unknown,
+          Some(saved.state),
+          saved.next_target_state,
+          saved.next_position,
+          saved.position,
+        ),
+        Some(state) => (
+          state,
+          None,
+          saved.next_target_state,
+          saved.next_position,
+          saved.position,
+        ),
+      },
+      None => (initial_state.into(), None, config.initial_target_state, None, None),
+    };
+    // the position is corrected once the state is set, if it's at a limit
+    let position = PositionEstimate::new(
+      position.unwrap_or(OPEN_POSITION),
+      timings.open_duration,
+      timings.close_duration,
+    );
 
+    let identifier_name = identifier.0.clone();
     let mut controller = DoorController {
       identifier,
-      current_state: initial_state,
+      current_state,
       command_topic: config.command_topic,
       state_topic: config.state_topic,
       stuck_topic: config.stuck_topic,
-      travel_duration: config.travel_duration,
-      initial_target_state: config.initial_target_state,
+      availability_topic: config.availability_topic,
+      availability: context.availability,
+      published_availability: None,
+      diagnostics_topic: config.diagnostics_topic,
+      position_topic: config.position_topic,
+      published_position: None,
+      sensor_health,
+      // the initial target is only acted on once the door's state is known
+      next_target_state,
+      next_position,
+      position,
+      target_position: None,
+      stop_at: None,
+      auto_close,
+      schedule,
       max_remote_latency_duration: config.max_remote_latency_duration,
-      mqtt_tx,
+      open_limit: timings.open_limit,
+      close_limit: timings.close_limit,
+      mqtt_tx: channels.mqtt_tx,
       remote,
-      mqtt_rx,
+      mqtt_rx: channels.mqtt_rx,
+      position_rx: channels.position_rx,
+      schedule_rx: channels.schedule_rx,
+      reconnected_rx: context.reconnected_tx.subscribe(),
+      control_rx: channels.control_rx,
+      detector,
+      state_path,
+      calibration_path,
+      saved_state,
+      heartbeat: context.monitor.heartbeat(format!("door {}", identifier_name)),
+      monitor: context.monitor,
     };
 
-    controller.publish_current_state()?;
-
-    if let Some(target_state) = controller.initial_target_state {
-      controller.goto_target_state(target_state).await?;
-    }
+    controller.update_position();
+    controller.update_auto_close();
+    controller.log_next_scheduled();
+    // the remote has been acquired, so the door is available once its state is known
+    controller.publish_all()?;
+    controller.save_state();
 
     Ok(controller)
   }
 
-  pub async fn listen(mut self, mut detector_rx: mpsc::UnboundedReceiver<DetectedState>) -> GarageResult<()> {
-    let mut next_target_state: Option<TargetState> = None;
-
+  pub async fn listen(mut self) -> GarageResult<()> {
     log::info!("{} listening with initial state: {:?}", &self, self.current_state);
+    // wakes the loop when nothing else does, so the heartbeat keeps beating
+    let mut heartbeat_interval = time::interval(self.heartbeat.interval());
+    let mut position_interval = time::interval(POSITION_INTERVAL);
+    // the channels the listener can't carry on without, once both have ended
+    let mut detector_ended = false;
+    let mut commands_ended = false;
     loop {
+      if detector_ended && commands_ended {
+        // stop beating so the watchdog notices, rather than carrying on without a detector or commands
+        log::error!(
+          "{} listener ended (channels closed, MQTT connection likely lost)",
+          &self
+        );
+        break Err(GarageError::MqttClosed);
+      }
+
+      self.heartbeat.beat();
       let result: GarageResult<()> = select! {
-        Some(detected_state) = detector_rx.recv() => {
+        // checked first so a stop takes priority over any command or retry that's also ready
+        biased;
+
+        Some(command) = self.control_rx.recv() => {
+          match self.control(command) {
+            Ok(ControlFlow::Break(())) => break Ok(()),
+            result => result.map(|_| ()),
+          }
+        }
+
+        Some(detected_state) = async {
+          let detected_state = self.detector.state_rx.recv().await;
+          detector_ended = detected_state.is_none();
+          detected_state
+        }, if !detector_ended => {
           // detected state changed
           log::debug!("{} detected state: {:?}, current state: {:?}", &self, &detected_state, &self.current_state);
 
           match (&self.current_state, detected_state) {
+            (_, DetectedState::SensorFault) => {
+              // we can't trust the reading, so stay as we are
+              log::error!("{} sensor fault detected, ignoring", &self);
+              Ok(())
+            }
+            (State::Unknown, detected_state) => {
+              // the detector has finally reported the door's state
+              log::info!("{} initial state detected: {:?}", &self, detected_state);
+              let state = match &self.saved_state {
+                Some(saved_state) => saved_state.restore(detected_state),
+                None => detected_state.into(),
+              };
+              if let State::Unknown = state {
+                // e.g. stuck, which doesn't say where the door is
+                log::warn!("{} state is still unknown", &self);
+                Ok(())
+              }
+              else {
+                self.saved_state = None;
+                self.set_current_state(state)
+              }
+            }
             (State::Closed | State::AttemptingOpen(_), DetectedState::Stuck) => {
               self.set_current_state(State::StuckClosed)
             }
-            (State::Open | State::Opening(_) | State::Closing(_), DetectedState::Stuck) => {
+            (State::Open | State::Opening(_) | State::ConfirmedOpening(_) | State::Closing(_) | State::Stopped, DetectedState::Stuck) => {
               self.set_current_state(State::StuckOpen)
             }
             (State::Closed | State::AttemptingOpen(_)| State::StuckClosed | State::StuckOpen, DetectedState::Open) => {
               // door was stuck/closed but it's now open
               log::debug!("{} was opened", &self);
-              self.set_current_state(State::Opening(AssumedTravel::new(self.travel_duration)),)
+              self.set_current_state(State::Opening(AssumedTravel::new(self.position.open_duration)))
+            }
+            (State::Closed | State::AttemptingOpen(_) | State::StuckClosed, DetectedState::MidTravel) => {
+              // door has left the closed limit, we'll know when it's fully open
+              log::debug!("{} was opened", &self);
+              self.set_current_state(State::ConfirmedOpening(ConfirmedTravel::new(self.travel_limit(TargetState::Open))))
             }
-            (State::Open | State::Closing(_) | State::StuckClosed | State::StuckOpen | State::Opening(_), DetectedState::Closed) => {
+            (State::Open | State::StuckOpen, DetectedState::MidTravel) => {
+              // door has left the open limit without being commanded to
+              log::debug!("{} started closing", &self);
+              self.set_current_state(State::Closing(ConfirmedTravel::new(self.travel_limit(TargetState::Closed))))
+            }
+            (
+              State::Closed | State::AttemptingOpen(_) | State::Opening(_) | State::ConfirmedOpening(_) | State::Closing(_) | State::StuckClosed | State::StuckOpen | State::Stopped,
+              DetectedState::FullyOpen
+            ) => {
+              // door has reached the open limit
+              log::debug!("{} is fully open", &self);
+              self.set_current_state(State::Open)
+            }
+            (State::Open | State::Closing(_) | State::StuckClosed | State::StuckOpen | State::Opening(_) | State::ConfirmedOpening(_) | State::Stopped, DetectedState::Closed) => {
               // door was open/stuck/closing and it's now closed
               log::debug!("{} was closed", &self);
               self.set_current_state(State::Closed)
@@ -119,16 +346,23 @@ impl DoorController {
             None
           }
         } => {
+          // a directional remote can't have moved the door the wrong way, so there's nothing to gain by trying again
+          let max_reattempts = if self.remote.is_directional() { 0 } else { MAX_STUCK_REATTEMPTS };
           match &mut self.current_state {
             State::AttemptingOpen(confirmed_travel) | State::Closing(confirmed_travel) => {
               // the door didn't open/close as it was requested to
-              if confirmed_travel.reattempt(MAX_STUCK_REATTEMPTS).is_ok() {
+              if confirmed_travel.reattempt(max_reattempts) {
                 // the travel expired, i.e. the door didn't move in to place before it should have
                 // travel is still the current state at this point, so we can safely assume it hasn't completed
 
                 // we're going to try again
                 log::debug!("{} door failed to move, triggering remote again", &self);
-                self.remote.trigger().await;
+                let target_state = match self.current_state {
+                  State::AttemptingOpen(_) => TargetState::Open,
+                  _ => TargetState::Closed,
+                };
+                self.save_state();
+                self.trigger_remote(target_state).await;
               } else {
                 // we've tried too many times
                 log::debug!("{} door failed to move after maximum attemps, marking as stuck", &self);
@@ -139,34 +373,142 @@ impl DoorController {
                 }
               }
             },
+            State::ConfirmedOpening(_) => {
+              // the door left the closed limit but never reached the open limit, pressing the remote again could
+              // reverse it, so we leave it as is
+              log::debug!("{} door failed to fully open, marking as stuck", &self);
+              self.set_current_state(State::StuckOpen)?;
+            },
             State::Opening(_) => {
               // the assumed travel time has expired, mark it as being in the end state
               log::debug!("{} open travel assumed complete", &self);
               self.set_current_state(State::Open)?;
             },
-            State::Open | State::StuckOpen | State::Closed | State::StuckClosed => unreachable!("state should not have an expiry"),
+            State::Unknown | State::Open | State::StuckOpen | State::Closed | State::StuckClosed | State::Stopped => unreachable!("state should not have an expiry"),
           }
 
           Ok(())
         }
 
-        Some(target_state) = async { next_target_state }, if !self.current_state.is_travelling() => { // only act on commands while not travelling
-          next_target_state = None;
+        Some(()) = async {
+          self.stop_at.as_mut()?.await;
+          Some(())
+        } => {
+          // the door has travelled as far as it was asked to
+          log::debug!("{} reached position {:?}, stopping", &self, self.target_position);
+          self.stop_at = None;
+          self.halt().await
+        }
+
+        Some(event) = async { self.auto_close.as_mut()?.next_event().await } => {
+          match event {
+            AutoCloseEvent::Warning(closing_at) => self.publish_auto_close_warning(closing_at),
+            AutoCloseEvent::Close => {
+              log::info!("{} has been left open, closing it", &self);
+              self.set_next_target_state(TargetState::Closed);
+              Ok(())
+            }
+          }
+        }
+
+        Some(event) = async { self.schedule.as_mut()?.next_event().await } => {
+          self.scheduled(event);
+          Ok(())
+        }
+
+        Some(publish) = async { self.schedule_rx.as_mut()?.recv().await } => {
+          match (publish.payload.trim().parse::<ScheduleOverride>(), &mut self.schedule) {
+            (Ok(schedule_override), Some(schedule)) => {
+              schedule.apply(schedule_override);
+              log::info!("{} schedule overridden: {:?}", &self, schedule_override);
+              self.log_next_scheduled();
+              self.save_state();
+            }
+            _ => log::warn!("{} ignoring invalid schedule override: {}", &self, publish.payload),
+          }
+          Ok(())
+        }
+
+        // only act on commands while not travelling and once the door's state is known
+        Some(target_state) = async { self.next_target_state }, if !self.current_state.is_travelling() && !self.current_state.is_unknown() => {
+          self.next_target_state = None;
+          self.save_state();
           // commanded to move to `target_state`
           log::debug!("{} was commanded to moved to state: {:?}, current state: {:?}", &self, &target_state, &self.current_state);
           self.goto_target_state(target_state).await
         }
 
-        Some(publish) = self.mqtt_rx.recv() => {
-          if &self.command_topic == &publish.topic {
-            if let Ok(target_state) = TargetState::from_str(&publish.payload) {
-              next_target_state = Some(target_state);
+        Some(position) = async { self.next_position }, if !self.current_state.is_travelling() && !self.current_state.is_unknown() => {
+          self.next_position = None;
+          self.save_state();
+          log::debug!("{} was commanded to move to position: {}, current position: {}", &self, position, self.position.position());
+          self.goto_position(position).await
+        }
+
+        Some(health) = async {
+          let health_rx = self.detector.health_rx.as_mut()?;
+          health_rx.changed().await.ok()?;
+          let health = health_rx.borrow_and_update().clone();
+          Some(health)
+        } => {
+          if health.is_degraded() {
+            log::warn!("{} sensor is degraded: {:?}", &self, &health.problems);
+          }
+          self.sensor_health = health;
+          self.publish_health()?;
+          self.publish_availability()
+        }
+
+        Ok(()) = self.reconnected_rx.recv() => {
+          // the broker may have lost our retained messages (or replaced our availability with the last will)
+          log::debug!("{} republishing after reconnection", &self);
+          self.publish_all()
+        }
+
+        Some(publish) = async {
+          let publish = self.mqtt_rx.recv().await;
+          commands_ended = publish.is_none();
+          publish
+        }, if !commands_ended => {
+          match DoorCommand::from_str(&publish.payload) {
+            Ok(DoorCommand::Move(target_state)) if topic_matches(&self.command_topic, &publish.topic) => {
+              self.set_next_target_state(target_state);
+              Ok(())
+            }
+            // stopping can't wait for the door to finish travelling
+            Ok(DoorCommand::Stop) if topic_matches(&self.command_topic, &publish.topic) => self.stop_travel().await,
+            Ok(DoorCommand::HoldOpen) if topic_matches(&self.command_topic, &publish.topic) => {
+              self.hold_open();
+              Ok(())
             }
+            // calibrating stops the door if it's told to, other commands wait until it's finished
+            Ok(DoorCommand::Calibrate) if topic_matches(&self.command_topic, &publish.topic) => {
+              match self.calibrate().await {
+                Ok(ControlFlow::Break(())) => break Ok(()),
+                result => result.map(|_| ()),
+              }
+            }
+            _ => Ok(()),
           }
+        }
 
-          Ok(())
+        Some(publish) = async { self.position_rx.as_mut()?.recv().await } => {
+          match publish.payload.trim().parse::<u8>() {
+            Ok(position) if position <= OPEN_POSITION => {
+              self.set_next_position(position);
+              Ok(())
+            }
+            _ => {
+              log::warn!("{} ignoring invalid position: {}", &self, publish.payload);
+              Ok(())
+            }
+          }
         }
 
+        _ = position_interval.tick(), if self.position.direction().is_some() => self.publish_position(),
+
+        _ = heartbeat_interval.tick() => Ok(()),
+
         else => {
           log::error!("{} listener ended (channels closed, MQTT connection likely lost)", &self);
           break Err(GarageError::MqttClosed);
@@ -174,18 +516,276 @@ impl DoorController {
       };
 
       if let Err(err) = result {
-        return Err::<(), _>(err);
+        // the door is no longer being controlled
+        self.send_availability(false).ok();
+        return Err(err);
+      }
+    }
+  }
+
+  /// Act on a change made to the controller, breaking once it's told to stop
+  fn control(&mut self, command: ControllerCommand) -> GarageResult<ControlFlow<()>> {
+    match command {
+      ControllerCommand::UpdateTimings(timings) => {
+        log::info!("{} updating timings: {:?}", &self, timings);
+        self.set_timings(timings);
+        Ok(ControlFlow::Continue(()))
+      }
+      ControllerCommand::Stop => {
+        log::info!("{} stopping with state: {:?}", &self, self.current_state);
+        self.save_state();
+        self.send_availability(false)?;
+        self.monitor.remove_door(&self.identifier.0);
+        Ok(ControlFlow::Break(()))
       }
     }
   }
 
+  /// Act on a scheduled command coming due
+  fn scheduled(&mut self, event: ScheduleEvent) {
+    match event {
+      ScheduleEvent::Due(rule) if rule.applies_in(&self.current_state) => {
+        log::info!("{} scheduled to move to {}", &self, rule.target_state);
+        self.next_target_state = Some(rule.target_state);
+        self.next_position = None;
+      }
+      ScheduleEvent::Due(rule) => log::info!(
+        "{} not moving to scheduled {} as it's {}",
+        &self,
+        rule.target_state,
+        self.current_state
+      ),
+      ScheduleEvent::Skipped(target_state) => log::info!("{} skipped scheduled {}", &self, target_state),
+      ScheduleEvent::Missed(target_state) => {
+        log::warn!(
+          "{} missed scheduled {}, the clock must have changed",
+          &self,
+          target_state
+        )
+      }
+    }
+    self.log_next_scheduled();
+    self.save_state();
+  }
+
   fn set_current_state(&mut self, current_state: State) -> GarageResult<()> {
     log::debug!("{} setting new state: {:?}", &self, current_state);
     self.current_state = current_state;
-    self.publish_current_state()
+    self.update_position();
+    self.update_auto_close();
+    self.save_state();
+    self.publish_current_state()?;
+    self.publish_position()?;
+    self.publish_availability()
+  }
+
+  /// Keep the estimated position in line with the state, and when to stop if the door is travelling part way
+  fn update_position(&mut self) {
+    match self.current_state {
+      State::Open => self.position.set(OPEN_POSITION),
+      State::Closed | State::StuckClosed => self.position.set(CLOSED_POSITION),
+      State::Opening(_) | State::ConfirmedOpening(_) => self.position.start(TargetState::Open),
+      State::Closing(_) => self.position.start(TargetState::Closed),
+      State::StuckOpen | State::Stopped => self.position.stop(),
+      // the door hasn't been seen to move yet
+      State::AttemptingOpen(_) | State::Unknown => {}
+    }
+
+    if !self.current_state.is_travelling() {
+      self.target_position = None;
+    }
+    self.stop_at = self
+      .target_position
+      .and_then(|target_position| self.position.reaches(target_position))
+      .map(|reaches| Box::pin(time::sleep_until(reaches)));
+  }
+
+  /// Start timing how long the door has been left open once it's open, and stop once it isn't
+  fn update_auto_close(&mut self) {
+    if let Some(auto_close) = &mut self.auto_close {
+      match self.current_state {
+        State::Open => auto_close.opened(),
+        State::Closed => auto_close.closed(),
+        // keep when the door was opened until its saved state has been restored
+        State::Unknown => {}
+        _ => auto_close.cancel(),
+      }
+      if let Some(due) = auto_close.due() {
+        log::debug!("{} will be closed automatically at {}", &self, due);
+      }
+    }
+  }
+
+  fn log_next_scheduled(&self) {
+    if let Some((due, target_state)) = self.schedule.as_ref().and_then(Schedule::next) {
+      let skipped = if self.schedule.as_ref().is_some_and(Schedule::skip_next) {
+        ", which will be skipped"
+      }
+      else {
+        ""
+      };
+      log::info!(
+        "{} next scheduled to move to {} at {}{}",
+        &self,
+        target_state,
+        due,
+        skipped
+      );
+    }
+  }
+
+  /// Keep the door open until it's next closed, rather than closing it automatically
+  fn hold_open(&mut self) {
+    let Some(auto_close) = &mut self.auto_close
+    else {
+      log::warn!("{} isn't closed automatically, so there's nothing to hold open", &self);
+      return;
+    };
+
+    auto_close.hold();
+    log::info!("{} holding open until it's next closed", &self);
+    self.save_state();
+  }
+
+  /// Warn that the door is about to be closed automatically
+  fn publish_auto_close_warning(&self, closing_at: DateTime<Tz>) -> GarageResult<()> {
+    let warning_topic = self
+      .auto_close
+      .as_ref()
+      .and_then(|auto_close| auto_close.warning_topic());
+    if let Some(warning_topic) = warning_topic {
+      log::info!("{} will be closed automatically at {}", &self, closing_at);
+      self
+        .mqtt_tx
+        .send(MqttPublish {
+          topic: warning_topic.to_owned(),
+          qos: QoS::AtLeastOnce,
+          retain: false,
+          payload: closing_at.to_rfc3339_opts(SecondsFormat::Secs, false),
+        })
+        .map_err(|_| GarageError::MqttClosed)?;
+    }
+
+    Ok(())
+  }
+
+  fn set_timings(&mut self, timings: DoorTimings) {
+    self.position.open_duration = timings.open_duration;
+    self.position.close_duration = timings.close_duration;
+    self.max_remote_latency_duration = timings.max_remote_latency_duration;
+    self.open_limit = timings.open_limit;
+    self.close_limit = timings.close_limit;
+    self.remote.config.pressed_time = timings.pressed_time;
+    self.remote.config.wait_time = timings.wait_time;
+  }
+
+  /// How long the door can take to travel towards `target_state` once it has started, before it has failed
+  fn travel_limit(&self, target_state: TargetState) -> Duration {
+    match target_state {
+      TargetState::Open => self.open_limit,
+      TargetState::Closed => self.close_limit,
+    }
+  }
+
+  /// Save the state so it can be restored if we restart
+  fn save_state(&self) {
+    let (state, position) = match (&self.current_state, &self.saved_state) {
+      // don't lose the saved state before it has been restored
+      (State::Unknown, Some(saved_state)) => (saved_state.clone(), Some(self.position.position())),
+      // the position is only a guess until the state is known
+      (State::Unknown, None) => (SavedDoorState::Unknown, None),
+      (current_state, _) => (current_state.into(), Some(self.position.position())),
+    };
+    SavedState {
+      state,
+      next_target_state: self.next_target_state,
+      next_position: self.next_position,
+      position,
+      hold_open: self.auto_close.as_ref().is_some_and(AutoClose::is_held),
+      opened_at: self.auto_close.as_ref().and_then(AutoClose::opened_at),
+      skip_scheduled: self.schedule.as_ref().is_some_and(Schedule::skip_next),
+      holidays: self
+        .schedule
+        .as_ref()
+        .map(|schedule| schedule.holidays().clone())
+        .unwrap_or_default(),
+    }
+    .save(&self.state_path);
+  }
+
+  /// Publish the door's state, health and availability
+  fn publish_all(&mut self) -> GarageResult<()> {
+    self.publish_current_state()?;
+    if self.detector.health_rx.is_some() {
+      self.publish_health()?;
+    }
+    self.published_position = None;
+    self.publish_position()?;
+    self.published_availability = None;
+    self.publish_availability()
+  }
+
+  /// Publish the door's estimated position, if it has changed
+  fn publish_position(&mut self) -> GarageResult<()> {
+    // the position is only a guess until the state is known
+    if self.current_state.is_unknown() {
+      return Ok(());
+    }
+    let position = self.position.position();
+    if let Some(position_topic) = &self.position_topic {
+      if self.published_position != Some(position) {
+        self
+          .mqtt_tx
+          .send(MqttPublish {
+            topic: position_topic.clone(),
+            qos: QoS::AtLeastOnce,
+            retain: true,
+            payload: position.to_string(),
+          })
+          .map_err(|_| GarageError::MqttClosed)?;
+        self.published_position = Some(position);
+      }
+    }
+
+    Ok(())
+  }
+
+  /// The door is available once its state is known, and while its sensor isn't stale
+  fn is_available(&self) -> bool {
+    !self.current_state.is_unknown() && !self.sensor_health.problems.contains(&HealthProblem::Stale)
+  }
+
+  /// Publish the door's availability, if it has changed
+  fn publish_availability(&mut self) -> GarageResult<()> {
+    let available = self.is_available();
+    if self.published_availability != Some(available) {
+      self.send_availability(available)?;
+      self.published_availability = Some(available);
+    }
+
+    Ok(())
+  }
+
+  fn send_availability(&self, available: bool) -> GarageResult<()> {
+    if let Some(availability_topic) = &self.availability_topic {
+      self
+        .mqtt_tx
+        .send(MqttPublish {
+          topic: availability_topic.clone(),
+          qos: QoS::AtLeastOnce,
+          retain: true,
+          payload: self.availability.payload(available).to_owned(),
+        })
+        .map_err(|_| GarageError::MqttClosed)?;
+    }
+
+    Ok(())
   }
 
   fn publish_current_state(&self) -> GarageResult<()> {
+    self
+      .monitor
+      .set_door_state(&self.identifier.0, self.current_state.to_string());
     self
       .mqtt_tx
       .send(MqttPublish {
@@ -211,16 +811,44 @@ impl DoorController {
     Ok(())
   }
 
+  fn publish_health(&self) -> GarageResult<()> {
+    if let Some(diagnostics_topic) = &self.diagnostics_topic {
+      self
+        .mqtt_tx
+        .send(MqttPublish {
+          topic: diagnostics_topic.clone(),
+          qos: QoS::AtLeastOnce,
+          retain: true,
+          payload: serde_json::to_string(&self.sensor_health).expect("health is always serializable"),
+        })
+        .map_err(|_| GarageError::MqttClosed)?;
+    }
+
+    Ok(())
+  }
+
   async fn goto_target_state(&mut self, target_state: TargetState) -> GarageResult<()> {
     if self.current_state.is_travelling() {
-      panic!("Door is currently travelling, cannot move to another target state");
+      // the remote would reverse or stop the door, so wait for it to finish travelling
+      log::debug!(
+        "{} is travelling, moving to {} once it has finished",
+        &self,
+        target_state
+      );
+      self.set_next_target_state(target_state);
     }
     else if self.current_state != target_state {
       // we're not in our target state, transition to travelling and trigger the door
       match target_state {
         TargetState::Closed => {
           // because we can't be for sure if the door actually moves from the open state, we assume it's closing
-          self.set_current_state(State::Closing(ConfirmedTravel::new(self.travel_duration)))?;
+          self.set_current_state(State::Closing(ConfirmedTravel::new(
+            self.max_remote_latency_duration + self.remote.config.pressed_time + self.travel_limit(TargetState::Closed),
+          )))?;
+        }
+        TargetState::Open if matches!(self.current_state, State::Stopped) => {
+          // the door is already open as far as the detector is concerned, so we can't detect it starting to open
+          self.set_current_state(State::Opening(AssumedTravel::new(self.position.open_duration)))?;
         }
         TargetState::Open => {
           // we can detect if the door starts to open, so ensure it does
@@ -231,9 +859,307 @@ impl DoorController {
       }
       // trigger the door
       log::debug!("{} is now targeting state {}, triggering remote", &self, target_state);
-      self.remote.trigger().await;
+      self.trigger_remote(target_state).await;
     }
 
     Ok(())
   }
+
+  /// Trigger the remote to move the door towards `target_state`
+  async fn trigger_remote(&mut self, target_state: TargetState) {
+    if let Some(travel_tx) = &self.detector.travel_tx {
+      travel_tx.send(DoorCommand::Move(target_state)).ok();
+    }
+    self
+      .heartbeat
+      .beat_after(self.remote.config.pressed_time + self.remote.config.wait_time);
+    self.remote.trigger(target_state).await;
+  }
+
+  /// Queue a command, replacing any position command
+  fn set_next_target_state(&mut self, target_state: TargetState) {
+    self.next_target_state = Some(target_state);
+    self.next_position = None;
+    self.save_state();
+  }
+
+  /// Queue a position command, the ends are the same as open and close commands
+  fn set_next_position(&mut self, position: u8) {
+    (self.next_target_state, self.next_position) = match position {
+      OPEN_POSITION => (Some(TargetState::Open), None),
+      CLOSED_POSITION => (Some(TargetState::Closed), None),
+      position => (None, Some(position)),
+    };
+    self.save_state();
+  }
+
+  /// Move the door part way, stopping it once it's estimated to have reached `position`
+  async fn goto_position(&mut self, position: u8) -> GarageResult<()> {
+    if !self.remote.can_stop() {
+      log::warn!(
+        "{} can't move to position {}, its remote can't stop the door",
+        &self,
+        position
+      );
+      return Ok(());
+    }
+    if self.current_state.is_travelling() {
+      log::debug!(
+        "{} is travelling, moving to position {} once it has finished",
+        &self,
+        position
+      );
+      self.set_next_position(position);
+      return Ok(());
+    }
+
+    let direction = match position.cmp(&self.position.position()) {
+      Ordering::Greater => TargetState::Open,
+      Ordering::Less => TargetState::Closed,
+      Ordering::Equal => return Ok(()),
+    };
+    self.target_position = Some(position);
+    self.goto_target_state(direction).await
+  }
+
+  /// Halt the door if it's travelling, cancelling any command waiting for the travel to finish
+  async fn stop_travel(&mut self) -> GarageResult<()> {
+    self.next_target_state = None;
+    self.next_position = None;
+    self.halt().await
+  }
+
+  /// Halt the door if it's travelling
+  async fn halt(&mut self) -> GarageResult<()> {
+    match self.current_state {
+      State::AttemptingOpen(_) if !self.remote.has_stop_button() => {
+        // the door may not have started moving, in which case pressing the remote would start it rather than stop it.
+        // if it has started, the detector will report it as open
+        log::debug!("{} abandoning opening", &self);
+        self.set_current_state(State::Closed)
+      }
+      State::AttemptingOpen(_) => {
+        log::debug!("{} stopping, pressing stop", &self);
+        self.set_current_state(State::Closed)?;
+        self.trigger_stop().await;
+        Ok(())
+      }
+      State::Opening(_) | State::ConfirmedOpening(_) | State::Closing(_) if !self.remote.can_stop() => {
+        log::warn!("{} can't be stopped, its remote has no stop button", &self);
+        self.save_state();
+        Ok(())
+      }
+      State::Opening(_) | State::ConfirmedOpening(_) | State::Closing(_) => {
+        log::debug!("{} stopping, pressing stop", &self);
+        self.set_current_state(State::Stopped)?;
+        self.trigger_stop().await;
+        Ok(())
+      }
+      _ => {
+        log::debug!("{} isn't travelling, so there's nothing to stop", &self);
+        self.save_state();
+        Ok(())
+      }
+    }
+  }
+
+  async fn trigger_stop(&mut self) {
+    if let Some(travel_tx) = &self.detector.travel_tx {
+      travel_tx.send(DoorCommand::Stop).ok();
+    }
+    self
+      .heartbeat
+      .beat_after(self.remote.config.pressed_time + self.remote.config.wait_time);
+    self.remote.trigger_stop().await;
+  }
+}
+
+#[cfg(all(test, not(feature = "arm")))]
+mod tests {
+  use std::{fs, sync::Arc, time::Duration};
+
+  use chrono::Utc;
+  use tokio::{
+    sync::{broadcast, mpsc},
+    time,
+  };
+
+  use super::{
+    saved_state::{SavedDoorState, SavedState},
+    ControllerChannels, DoorController,
+  };
+  use crate::{
+    clock::SystemClock,
+    door::{
+      detector::DetectorChannels,
+      state::{DetectedState, TargetState},
+      DoorContext,
+    },
+    mock_gpio::test_presses,
+    mqtt_client::{Availability, MqttPublish},
+    systemd::{Notifier, ServiceMonitor},
+  };
+
+  /// What a door did while it was running
+  struct Run {
+    /// How many times the remote was pressed
+    presses: usize,
+    published: Vec<MqttPublish>,
+  }
+
+  impl Run {
+    /// The payloads published to `topic`
+    fn payloads(&self, topic: &str) -> Vec<&str> {
+      self
+        .published
+        .iter()
+        .filter(|publish| publish.topic == topic)
+        .map(|publish| publish.payload.as_str())
+        .collect()
+    }
+  }
+
+  /// Run a door, which starts out wanting to be closed and is closed once left open for an hour, with a toggle remote on
+  /// `pin` for a while
+  async fn run(pin: u8, saved_state: Option<SavedState>, initial_state: Option<DetectedState>) -> Run {
+    let config = toml::from_str(&format!(
+      r#"
+        command_topic = "garage/door/set"
+        state_topic = "garage/door/state"
+        position_topic = "garage/door/position"
+        initial_target_state = "CLOSED"
+        travel_duration = 10
+        max_remote_latency_duration = 2
+
+        [remote]
+        pin = "Gpio{}"
+        pressed_time = 0.5
+        wait_time = 0.5
+
+        [auto_close]
+        close_after = 3600
+      "#,
+      pin
+    ))
+    .unwrap();
+    let state_directory = std::env::temp_dir().join(format!("mqtt-garage-controller-{}-{}", pin, std::process::id()));
+    let identifier = format!("door-{}", pin).into();
+    if let Some(saved_state) = saved_state {
+      saved_state.save(&SavedState::path(&state_directory, &identifier));
+    }
+    let (reconnected_tx, _) = broadcast::channel(1);
+    let context = DoorContext {
+      remote_mutex: Default::default(),
+      availability: Availability {
+        online: "online".to_string(),
+        offline: "offline".to_string(),
+      },
+      initialisation_timeout: Duration::from_secs(10),
+      reconnected_tx,
+      state_directory: state_directory.clone(),
+      monitor: ServiceMonitor::new(Notifier::from_env()),
+      clock: Arc::new(SystemClock),
+    };
+    let (mqtt_tx, mut mqtt_rx) = mpsc::unbounded_channel();
+    let (_command_tx, command_rx) = mpsc::unbounded_channel();
+    let (_control_tx, control_rx) = mpsc::unbounded_channel();
+    let (_state_tx, state_rx) = mpsc::unbounded_channel();
+    let channels = ControllerChannels {
+      mqtt_tx,
+      mqtt_rx: command_rx,
+      position_rx: None,
+      schedule_rx: None,
+      control_rx,
+    };
+    let detector = DetectorChannels {
+      state_rx,
+      health_rx: None,
+      travel_tx: None,
+    };
+
+    let controller = DoorController::new(identifier, config, context, channels, detector, initial_state)
+      .await
+      .unwrap();
+    time::timeout(Duration::from_secs(60), controller.listen()).await.ok();
+    fs::remove_dir_all(state_directory).ok();
+    let mut published = Vec::new();
+    while let Ok(publish) = mqtt_rx.try_recv() {
+      published.push(publish);
+    }
+    Run {
+      presses: test_presses(pin),
+      published,
+    }
+  }
+
+  #[tokio::test(start_paused = true)]
+  async fn moves_to_the_initial_target_state() {
+    // it's never detected as closed, so it keeps on trying
+    assert!(run(20, None, Some(DetectedState::Open)).await.presses > 0);
+    assert_eq!(run(21, None, Some(DetectedState::Closed)).await.presses, 0);
+  }
+
+  #[tokio::test(start_paused = true)]
+  async fn faulty_sensor_doesnt_drive_the_remote() {
+    assert_eq!(run(22, None, Some(DetectedState::SensorFault)).await.presses, 0);
+    assert_eq!(run(23, None, Some(DetectedState::Stuck)).await.presses, 0);
+    assert_eq!(run(24, None, None).await.presses, 0);
+  }
+
+  #[tokio::test(start_paused = true)]
+  async fn restarted_door_doesnt_move_to_the_initial_target_state() {
+    let saved_state = |next_target_state| SavedState {
+      state: SavedDoorState::Open,
+      next_target_state,
+      next_position: None,
+      position: None,
+      hold_open: false,
+      opened_at: None,
+      skip_scheduled: false,
+      holidays: Default::default(),
+    };
+    assert_eq!(
+      run(25, Some(saved_state(None)), Some(DetectedState::Open))
+        .await
+        .presses,
+      0
+    );
+    // but a command which never got to run still is
+    let run = run(
+      26,
+      Some(saved_state(Some(TargetState::Closed))),
+      Some(DetectedState::Open),
+    )
+    .await;
+    assert!(run.presses > 0);
+  }
+
+  #[tokio::test(start_paused = true)]
+  async fn position_is_published_once_the_state_is_known() {
+    assert_eq!(
+      run(18, None, None).await.payloads("garage/door/position"),
+      Vec::<&str>::new()
+    );
+    assert_eq!(
+      run(19, None, Some(DetectedState::Closed))
+        .await
+        .payloads("garage/door/position"),
+      vec!["0"]
+    );
+  }
+
+  #[tokio::test(start_paused = true)]
+  async fn restarted_door_is_closed_once_left_open_too_long() {
+    let saved_state = SavedState {
+      state: SavedDoorState::Open,
+      next_target_state: None,
+      next_position: None,
+      position: None,
+      hold_open: false,
+      opened_at: Some(Utc::now() - chrono::Duration::hours(2)),
+      skip_scheduled: false,
+      holidays: Default::default(),
+    };
+    assert!(run(17, Some(saved_state), Some(DetectedState::Open)).await.presses > 0);
+  }
 }
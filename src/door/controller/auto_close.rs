@@ -0,0 +1,362 @@
+use std::{pin::Pin, sync::Arc, time::Duration};
+
+use chrono::{DateTime, NaiveTime, Utc};
+use chrono_tz::Tz;
+use serde::Deserialize;
+use serde_with::{serde_as, DisplayFromStr, DurationSeconds};
+use tokio::time::Sleep;
+
+use crate::clock::{self, default_time_zone, resolve_local, Clock};
+
+fn default_warning_duration() -> Duration {
+  Duration::from_secs(60)
+}
+
+/// The time of day the door can be closed automatically, which can span midnight
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct TimeWindow {
+  /// When the window starts, e.g. "22:00"
+  pub start: NaiveTime,
+  /// When the window ends, e.g. "06:30"
+  pub end: NaiveTime,
+}
+
+impl TimeWindow {
+  pub fn contains(&self, time: NaiveTime) -> bool {
+    if self.start <= self.end {
+      self.start <= time && time < self.end
+    }
+    else {
+      time >= self.start || time < self.end
+    }
+  }
+
+  /// The first moment at or after `from` which is within the window
+  fn next(&self, from: DateTime<Utc>, time_zone: Tz) -> DateTime<Utc> {
+    let local = from.with_timezone(&time_zone);
+    if self.contains(local.time()) {
+      return from;
+    }
+
+    // the window next starts later today, or tomorrow
+    let today = local.date_naive();
+    [Some(today), today.succ_opt()]
+      .into_iter()
+      .flatten()
+      .filter_map(|date| resolve_local(time_zone, date.and_time(self.start)))
+      .find(|start| *start > from)
+      .unwrap_or(from)
+  }
+}
+
+/// Closes the door once it has been left open for too long
+#[serde_as]
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct AutoCloseConfig {
+  #[serde_as(as = "DurationSeconds<u64>")]
+  /// How long the door can be left open before it's closed
+  pub close_after: Duration,
+
+  /// Only close the door within this time of day, if set.
+  ///
+  /// If the door has been open long enough outside the window, it's closed once the window starts.
+  pub window: Option<TimeWindow>,
+
+  #[serde_as(as = "DisplayFromStr")]
+  #[serde(default = "default_time_zone")]
+  /// The time zone the window is in, e.g. "Europe/London", UTC by default
+  pub time_zone: Tz,
+
+  /// The name of the MQTT topic a warning is sent on before the door is closed, if desired.
+  ///
+  /// The warning is the time the door will be closed, which gives a chance to hold it open.
+  pub warning_topic: Option<String>,
+
+  #[serde_as(as = "DurationSeconds<u64>")]
+  #[serde(default = "default_warning_duration")]
+  /// How long before closing the door the warning is sent, a minute by default
+  pub warning_duration: Duration,
+}
+
+impl AutoCloseConfig {
+  /// When a door left open at `opened` is due to be closed
+  pub fn due(&self, opened: DateTime<Utc>) -> DateTime<Utc> {
+    let due = chrono::Duration::from_std(self.close_after)
+      .ok()
+      .and_then(|close_after| opened.checked_add_signed(close_after))
+      .unwrap_or(DateTime::<Utc>::MAX_UTC);
+    match &self.window {
+      Some(window) => window.next(due, self.time_zone),
+      None => due,
+    }
+  }
+}
+
+/// What's due next for a door which has been left open
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AutoCloseEvent {
+  /// The door will be closed at this time
+  Warning(DateTime<Tz>),
+  /// The door has been open for too long, and should be closed
+  Close,
+}
+
+/// Tracks how long the door has been left open, to close it once it has been open too long
+#[derive(Debug)]
+pub struct AutoClose {
+  config: AutoCloseConfig,
+  clock: Arc<dyn Clock>,
+  /// Whether closing has been suspended until the door next closes
+  held: bool,
+  /// When the door was left open, if it's open
+  opened_at: Option<DateTime<Utc>>,
+  /// When the door will be closed, and whether the warning has been sent
+  due: Option<(DateTime<Utc>, bool)>,
+  /// Wakes the door for the next event
+  timer: Option<Pin<Box<Sleep>>>,
+}
+
+impl AutoClose {
+  /// `opened_at` is when the door was left open before a restart, which is used if it's still open
+  pub fn new(config: AutoCloseConfig, clock: Arc<dyn Clock>, held: bool, opened_at: Option<DateTime<Utc>>) -> Self {
+    AutoClose {
+      config,
+      clock,
+      held,
+      opened_at,
+      due: None,
+      timer: None,
+    }
+  }
+
+  /// Whether closing has been suspended until the door next closes
+  pub fn is_held(&self) -> bool {
+    self.held
+  }
+
+  pub fn warning_topic(&self) -> Option<&str> {
+    self.config.warning_topic.as_deref()
+  }
+
+  /// The door has been opened, unless it was already open
+  pub fn opened(&mut self) {
+    if self.due.is_none() && !self.held {
+      let opened_at = *self.opened_at.get_or_insert_with(|| self.clock.now());
+      self.schedule(self.config.due(opened_at), false);
+    }
+  }
+
+  /// When the door was left open, if it's open
+  pub fn opened_at(&self) -> Option<DateTime<Utc>> {
+    self.opened_at
+  }
+
+  /// When the door will be closed, if it's due to be
+  pub fn due(&self) -> Option<DateTime<Tz>> {
+    self.due.map(|(due, _)| due.with_timezone(&self.config.time_zone))
+  }
+
+  /// The door is no longer open, e.g. it's moving or stuck
+  pub fn cancel(&mut self) {
+    self.opened_at = None;
+    self.due = None;
+    self.timer = None;
+  }
+
+  /// The door has closed, so it's closed automatically again once it's next left open
+  pub fn closed(&mut self) {
+    self.cancel();
+    self.held = false;
+  }
+
+  /// Keep the door open until it's next closed
+  pub fn hold(&mut self) {
+    self.cancel();
+    self.held = true;
+  }
+
+  fn schedule(&mut self, due: DateTime<Utc>, warned: bool) {
+    let wake = match (warned, &self.config.warning_topic) {
+      (false, Some(_)) => chrono::Duration::from_std(self.config.warning_duration)
+        .ok()
+        .and_then(|warning_duration| due.checked_sub_signed(warning_duration))
+        .unwrap_or(due),
+      _ => due,
+    };
+    self.due = Some((due, warned || self.config.warning_topic.is_none()));
+    self.timer = Some(clock::sleep_until(self.clock.as_ref(), wake));
+  }
+
+  /// Wait until the warning needs sending or the door needs closing.
+  ///
+  /// Returns `None` straight away if the door isn't due to be closed, or once the close has been put back (e.g. the
+  /// clock changed and it's no longer within the window).
+  pub async fn next_event(&mut self) -> Option<AutoCloseEvent> {
+    self.timer.as_mut()?.await;
+    let (due, warned) = self.due?;
+    if !warned {
+      self.schedule(due, true);
+      return Some(AutoCloseEvent::Warning(due.with_timezone(&self.config.time_zone)));
+    }
+
+    // the clock may have changed since the door was opened, so make sure it's still time
+    let now = self.clock.now();
+    let due = match &self.config.window {
+      Some(window) => window.next(now, self.config.time_zone),
+      None => now,
+    };
+    if due > now {
+      self.schedule(due, false);
+      return None;
+    }
+
+    self.cancel();
+    Some(AutoCloseEvent::Close)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use chrono::{DateTime, NaiveTime, Utc};
+
+  use super::{AutoClose, AutoCloseConfig, AutoCloseEvent, TimeWindow};
+  use crate::clock::{Clock, FakeClock};
+
+  fn utc(time: &str) -> DateTime<Utc> {
+    time.parse().unwrap()
+  }
+
+  fn time(time: &str) -> NaiveTime {
+    time.parse().unwrap()
+  }
+
+  fn window(start: &str, end: &str) -> TimeWindow {
+    TimeWindow {
+      start: time(start),
+      end: time(end),
+    }
+  }
+
+  fn config(toml: &str) -> AutoCloseConfig {
+    toml::from_str(toml).unwrap()
+  }
+
+  #[test]
+  fn window_within_a_day() {
+    let window = window("09:00", "17:00");
+    assert!(window.contains(time("09:00")));
+    assert!(window.contains(time("12:00")));
+    assert!(!window.contains(time("17:00")));
+    assert!(!window.contains(time("03:00")));
+  }
+
+  #[test]
+  fn window_spanning_midnight() {
+    let window = window("22:00", "06:30");
+    assert!(window.contains(time("22:00")));
+    assert!(window.contains(time("23:59")));
+    assert!(window.contains(time("03:00")));
+    assert!(!window.contains(time("06:30")));
+    assert!(!window.contains(time("12:00")));
+  }
+
+  #[test]
+  fn next_is_the_window_start() {
+    let window = window("22:00", "06:30");
+    // already within the window
+    assert_eq!(
+      window.next(utc("2026-07-01T23:00:00Z"), chrono_tz::UTC),
+      utc("2026-07-01T23:00:00Z")
+    );
+    // later today
+    assert_eq!(
+      window.next(utc("2026-07-01T12:00:00Z"), chrono_tz::UTC),
+      utc("2026-07-01T22:00:00Z")
+    );
+    // 22:00 BST
+    assert_eq!(
+      window.next(utc("2026-07-01T12:00:00Z"), chrono_tz::Europe::London),
+      utc("2026-07-01T21:00:00Z")
+    );
+  }
+
+  #[test]
+  fn next_is_tomorrow_once_the_window_has_started_today() {
+    // today's window has already ended
+    let window = window("01:00", "02:00");
+    assert_eq!(
+      window.next(utc("2026-07-01T03:00:00Z"), chrono_tz::UTC),
+      utc("2026-07-02T01:00:00Z")
+    );
+  }
+
+  #[test]
+  fn due_after_being_open_for_long_enough() {
+    assert_eq!(
+      config("close_after = 600").due(utc("2026-07-01T12:00:00Z")),
+      utc("2026-07-01T12:10:00Z")
+    );
+    // within the window
+    let config = config(
+      r#"
+      close_after = 600
+      window = { start = "22:00", end = "06:30" }
+      "#,
+    );
+    assert_eq!(config.due(utc("2026-07-01T12:00:00Z")), utc("2026-07-01T22:00:00Z"));
+    assert_eq!(config.due(utc("2026-07-01T23:00:00Z")), utc("2026-07-01T23:10:00Z"));
+  }
+
+  #[tokio::test(start_paused = true)]
+  async fn warned_before_closing() {
+    let clock = FakeClock::new(utc("2026-07-01T12:00:00Z"));
+    let config = config(
+      r#"
+      close_after = 600
+      warning_topic = "garage/door/closing"
+      "#,
+    );
+    let mut auto_close = AutoClose::new(config, clock.clone(), false, None);
+    auto_close.opened();
+
+    let event = auto_close.next_event().await;
+    assert_eq!(clock.now(), utc("2026-07-01T12:09:00Z"));
+    assert!(matches!(event, Some(AutoCloseEvent::Warning(due)) if due == utc("2026-07-01T12:10:00Z")));
+    assert_eq!(auto_close.next_event().await, Some(AutoCloseEvent::Close));
+    assert_eq!(clock.now(), utc("2026-07-01T12:10:00Z"));
+    // the door isn't closed again until it's next opened
+    assert_eq!(auto_close.next_event().await, None);
+  }
+
+  #[tokio::test(start_paused = true)]
+  async fn held_open_until_closed() {
+    let clock = FakeClock::new(utc("2026-07-01T12:00:00Z"));
+    let mut auto_close = AutoClose::new(config("close_after = 600"), clock.clone(), true, None);
+    auto_close.opened();
+    assert_eq!(auto_close.due(), None);
+
+    auto_close.closed();
+    auto_close.opened();
+    assert_eq!(auto_close.next_event().await, Some(AutoCloseEvent::Close));
+  }
+
+  #[tokio::test(start_paused = true)]
+  async fn opened_before_a_restart() {
+    let clock = FakeClock::new(utc("2026-07-01T12:00:00Z"));
+    let mut auto_close = AutoClose::new(
+      config("close_after = 600"),
+      clock.clone(),
+      false,
+      Some(utc("2026-07-01T11:55:00Z")),
+    );
+    auto_close.opened();
+    assert_eq!(auto_close.opened_at(), Some(utc("2026-07-01T11:55:00Z")));
+    assert_eq!(auto_close.next_event().await, Some(AutoCloseEvent::Close));
+    assert_eq!(clock.now(), utc("2026-07-01T12:05:00Z"));
+
+    // once it has moved, the next time it's opened is used
+    auto_close.cancel();
+    auto_close.opened();
+    assert_eq!(auto_close.opened_at(), Some(utc("2026-07-01T12:05:00Z")));
+  }
+}
@@ -0,0 +1,339 @@
+use std::{
+  fs, io,
+  ops::ControlFlow,
+  path::{Path, PathBuf},
+  str::FromStr,
+  time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, DurationSecondsWithFrac};
+use tokio::{
+  pin, select,
+  sync::mpsc::UnboundedReceiver,
+  time::{self, Instant},
+};
+
+use super::{ControllerCommand, DoorController};
+use crate::{
+  door::{
+    identifier::Identifier,
+    state::{AssumedTravel, ConfirmedTravel, DetectedState, DoorCommand, State, TargetState},
+  },
+  error::{GarageError, GarageResult},
+  mqtt_client::receiver::topic_matches,
+};
+
+/// How long calibrating waits for the door to reach each limit before giving up
+const CALIBRATION_TIMEOUT: Duration = Duration::from_secs(120);
+/// How much slower than measured the remote can be to start the door, as it varies from press to press
+const LATENCY_MARGIN: f64 = 1.5;
+
+/// Round a measured duration up to the next tenth of a second, so the calibration file is readable
+fn round_up(duration: Duration) -> Duration {
+  Duration::from_secs_f64((duration.as_secs_f64() * 10.0).ceil() / 10.0)
+}
+
+/// The durations measured by calibrating a door, which replace the configured ones
+#[serde_as]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Calibration {
+  /// When the door was calibrated
+  pub calibrated: DateTime<Utc>,
+  #[serde_as(as = "Option<DurationSecondsWithFrac<f64>>")]
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  /// How long the door took to open, only measured if it has a sensor at the open limit
+  pub open_duration: Option<Duration>,
+  #[serde_as(as = "Option<DurationSecondsWithFrac<f64>>")]
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  /// How long the door took to close
+  pub close_duration: Option<Duration>,
+  #[serde_as(as = "DurationSecondsWithFrac<f64>")]
+  /// The longest the door took to start once the remote was released, with a margin
+  pub max_remote_latency_duration: Duration,
+}
+
+impl Calibration {
+  /// The path the door's calibration is saved to
+  pub fn path(state_directory: &Path, identifier: &Identifier) -> PathBuf {
+    state_directory.join(format!("{}.calibration.toml", identifier.0))
+  }
+
+  /// Load the door's calibration, if it has been calibrated
+  pub fn load(path: &Path) -> Option<Calibration> {
+    let calibration = fs::read_to_string(path).ok()?;
+    match toml::from_str(&calibration) {
+      Ok(calibration) => Some(calibration),
+      Err(err) => {
+        log::warn!("ignoring invalid calibration {}: {}", path.display(), err);
+        None
+      }
+    }
+  }
+
+  pub fn save(&self, path: &Path) -> io::Result<()> {
+    let calibration = toml::to_string(self).expect("calibration is always serializable");
+    path.parent().map_or(Ok(()), fs::create_dir_all)?;
+    fs::write(path, calibration)
+  }
+}
+
+/// Why calibrating was abandoned before the door was closed again
+enum Interruption {
+  /// STOP was received on the command topic
+  Stop,
+  /// The controller was told to stop, e.g. as the service is shutting down
+  Shutdown,
+  Error(GarageError),
+}
+
+impl From<GarageError> for Interruption {
+  fn from(err: GarageError) -> Self {
+    Interruption::Error(err)
+  }
+}
+
+/// The detector's next report of one of `expected`, and when it was reported, or `None` if the door got stuck
+async fn report(
+  state_rx: &mut UnboundedReceiver<DetectedState>,
+  expected: &[DetectedState],
+) -> Option<(DetectedState, Instant)> {
+  loop {
+    match state_rx.recv().await {
+      Some(state) if expected.contains(&state) => return Some((state, Instant::now())),
+      Some(DetectedState::Stuck) | None => return None,
+      // e.g. a sensor fault
+      Some(_) => {}
+    }
+  }
+}
+
+impl DoorController {
+  /// Open and close the door, timing it from the detector's reports, and save the results to be used from the next
+  /// start.
+  ///
+  /// The door has to be closed to start with. The open duration can only be measured if the door has a sensor at the
+  /// open limit, otherwise it's left to the configuration. The door is stopped if it's told to stop while calibrating,
+  /// and breaks once the controller has been told to stop.
+  pub(super) async fn calibrate(&mut self) -> GarageResult<ControlFlow<()>> {
+    if self.detector.travel_tx.is_some() {
+      log::warn!("{} can't be calibrated, its detector can't see the door move", &self);
+      return Ok(ControlFlow::Continue(()));
+    }
+    if self.current_state != TargetState::Closed {
+      log::warn!(
+        "{} can only be calibrated from closed, it's {}",
+        &self,
+        self.current_state
+      );
+      return Ok(ControlFlow::Continue(()));
+    }
+    log::info!("{} calibrating, the door will open and then close", &self);
+
+    match self.measure().await {
+      Ok(Some(calibration)) => {
+        self.save_calibration(&calibration);
+        Ok(ControlFlow::Continue(()))
+      }
+      Ok(None) => Ok(ControlFlow::Continue(())),
+      Err(Interruption::Stop) => {
+        log::warn!("{} calibration abandoned, stopping the door", &self);
+        self.stop_travel().await?;
+        Ok(ControlFlow::Continue(()))
+      }
+      Err(Interruption::Shutdown) => {
+        log::warn!("{} calibration abandoned, stopping the door", &self);
+        self.halt().await?;
+        self.control(ControllerCommand::Stop)
+      }
+      Err(Interruption::Error(err)) => Err(err),
+    }
+  }
+
+  /// Open and close the door, returning the durations measured or `None` if it failed to move as expected
+  async fn measure(&mut self) -> Result<Option<Calibration>, Interruption> {
+    // open the door, timing how long it takes to leave the closed limit and then reach the open limit
+    self.set_current_state(State::AttemptingOpen(ConfirmedTravel::new(CALIBRATION_TIMEOUT)))?;
+    let pressed = Instant::now();
+    let left_closed = self
+      .press_and_wait(
+        TargetState::Open,
+        &[DetectedState::Open, DetectedState::MidTravel, DetectedState::FullyOpen],
+      )
+      .await?;
+    let Some((detected_state, left_closed)) = left_closed
+    else {
+      log::error!("{} calibration failed, the door didn't start opening", &self);
+      self.set_current_state(State::StuckClosed)?;
+      return Ok(None);
+    };
+    let open_latency = left_closed - pressed;
+
+    let open_duration = if detected_state == DetectedState::MidTravel {
+      self.set_current_state(State::ConfirmedOpening(ConfirmedTravel::new(CALIBRATION_TIMEOUT)))?;
+      self.heartbeat.beat_after(CALIBRATION_TIMEOUT);
+      let opened = self
+        .wait_for(&[DetectedState::FullyOpen], Instant::now() + CALIBRATION_TIMEOUT)
+        .await?;
+      let Some((_, opened)) = opened
+      else {
+        log::error!("{} calibration failed, the door didn't fully open", &self);
+        self.set_current_state(State::StuckOpen)?;
+        return Ok(None);
+      };
+      Some(opened - left_closed)
+    }
+    else {
+      // there's no sensor at the open limit, so wait as long as the door's configured to take
+      let open_duration = self.travel_limit(TargetState::Open);
+      self.set_current_state(State::Opening(AssumedTravel::new(open_duration)))?;
+      self.heartbeat.beat_after(open_duration);
+      let reported = self
+        .wait_for(
+          &[DetectedState::Closed, DetectedState::Stuck],
+          left_closed + open_duration,
+        )
+        .await?;
+      if let Some((detected_state, _)) = reported {
+        log::error!("{} calibration failed, the door didn't stay open", &self);
+        self.set_current_state(match detected_state {
+          DetectedState::Closed => State::Closed,
+          _ => State::StuckOpen,
+        })?;
+        return Ok(None);
+      }
+      None
+    };
+    self.set_current_state(State::Open)?;
+
+    // close the door, timing how long it takes to leave the open limit (if there's a sensor there) and then close
+    self.set_current_state(State::Closing(ConfirmedTravel::new(CALIBRATION_TIMEOUT)))?;
+    let pressed = Instant::now();
+    let left_open = self
+      .press_and_wait(TargetState::Closed, &[DetectedState::MidTravel, DetectedState::Closed])
+      .await?;
+    let (close_latency, close_duration) = match left_open {
+      Some((DetectedState::MidTravel, left_open)) => {
+        self.heartbeat.beat_after(CALIBRATION_TIMEOUT);
+        let closed = self
+          .wait_for(&[DetectedState::Closed], Instant::now() + CALIBRATION_TIMEOUT)
+          .await?;
+        let Some((_, closed)) = closed
+        else {
+          log::error!("{} calibration failed, the door didn't close", &self);
+          self.set_current_state(State::StuckOpen)?;
+          return Ok(None);
+        };
+        (left_open - pressed, closed - left_open)
+      }
+      // without a sensor at the open limit, the remote is assumed to take as long as it did to open the door
+      Some((_, closed)) => (open_latency, closed.saturating_duration_since(pressed + open_latency)),
+      None => {
+        log::error!("{} calibration failed, the door didn't close", &self);
+        self.set_current_state(State::StuckOpen)?;
+        return Ok(None);
+      }
+    };
+    self.set_current_state(State::Closed)?;
+
+    // the latencies were timed from the start of the press, which is already allowed for when waiting for the door
+    let remote_latency = open_latency
+      .max(close_latency)
+      .saturating_sub(self.remote.config.pressed_time);
+    Ok(Some(Calibration {
+      calibrated: Utc::now(),
+      open_duration: open_duration.map(round_up),
+      close_duration: Some(round_up(close_duration)),
+      max_remote_latency_duration: round_up(remote_latency.mul_f64(LATENCY_MARGIN)),
+    }))
+  }
+
+  fn save_calibration(&self, calibration: &Calibration) {
+    log::info!("{} calibrated: {:?}", &self, calibration);
+    match calibration.save(&self.calibration_path) {
+      Ok(()) => log::info!(
+        "{} calibration saved to {}, it will be used once the service is restarted or reloaded",
+        &self,
+        self.calibration_path.display()
+      ),
+      Err(err) => log::error!(
+        "{} failed to save calibration to {}: {}",
+        &self,
+        self.calibration_path.display(),
+        err
+      ),
+    }
+  }
+
+  /// Press the remote to move the door towards `target_state`, then wait for the detector to report one of
+  /// `expected`.
+  ///
+  /// The detector's reports are timed while the remote is pressed, so they aren't delayed by the press.
+  async fn press_and_wait(
+    &mut self,
+    target_state: TargetState,
+    expected: &[DetectedState],
+  ) -> Result<Option<(DetectedState, Instant)>, Interruption> {
+    let deadline = Instant::now() + CALIBRATION_TIMEOUT;
+    self.heartbeat.beat_after(CALIBRATION_TIMEOUT);
+    {
+      let press = self.remote.trigger(target_state);
+      pin!(press);
+      select! {
+        _ = &mut press => {}
+        reported = report(&mut self.detector.state_rx, expected) => {
+          press.await;
+          return Ok(reported);
+        }
+      }
+    }
+    self.wait_for(expected, deadline).await
+  }
+
+  /// Wait for the detector to report one of `expected`, returning the state and when it was reported, or `None` if
+  /// the door got stuck or didn't get there by `deadline`.
+  ///
+  /// Meanwhile, the door can still be told to stop and scheduled commands are queued, as are any other commands.
+  async fn wait_for(
+    &mut self,
+    expected: &[DetectedState],
+    deadline: Instant,
+  ) -> Result<Option<(DetectedState, Instant)>, Interruption> {
+    loop {
+      select! {
+        // checked first so a stop takes priority over the door reaching where it was expected to
+        biased;
+
+        Some(command) = self.control_rx.recv() => match command {
+          ControllerCommand::UpdateTimings(timings) => {
+            log::info!("{} updating timings: {:?}", &self, timings);
+            self.set_timings(timings);
+          }
+          ControllerCommand::Stop => return Err(Interruption::Shutdown),
+        },
+
+        Some(publish) = self.mqtt_rx.recv() => match DoorCommand::from_str(&publish.payload) {
+          Ok(DoorCommand::Stop) if topic_matches(&self.command_topic, &publish.topic) => {
+            return Err(Interruption::Stop)
+          }
+          Ok(DoorCommand::Move(target_state)) if topic_matches(&self.command_topic, &publish.topic) => {
+            self.set_next_target_state(target_state)
+          }
+          Ok(DoorCommand::HoldOpen) if topic_matches(&self.command_topic, &publish.topic) => self.hold_open(),
+          Ok(DoorCommand::Calibrate) if topic_matches(&self.command_topic, &publish.topic) => {
+            log::warn!("{} is already calibrating", &self)
+          }
+          _ => {}
+        },
+
+        reported = report(&mut self.detector.state_rx, expected) => return Ok(reported),
+
+        // a scheduled command can't wait until the door has been calibrated, as it would be missed
+        Some(event) = async { self.schedule.as_mut()?.next_event().await } => self.scheduled(event),
+
+        _ = time::sleep_until(deadline) => return Ok(None),
+      }
+    }
+  }
+}
@@ -1,16 +1,15 @@
 use std::time::Duration;
 
 use serde::Deserialize;
-use serde_with::{serde_as, DurationSeconds};
+use serde_with::{serde_as, DurationSeconds, DurationSecondsWithFrac};
 
-use super::remote::RemoteConfig;
+use super::{auto_close::AutoCloseConfig, calibration::Calibration, remote::RemoteConfig, schedule::ScheduleConfig};
 use crate::door::state::TargetState;
 
-
 #[serde_as]
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Clone, PartialEq)]
 pub struct DoorControllerConfig {
-  /// The name of the MQTT topic open/close commands are received on
+  /// The name of the MQTT topic open/close/stop commands are received on
   pub command_topic: String,
 
   /// The name of the MQTT topic state change commands are sent on
@@ -19,21 +18,128 @@ pub struct DoorControllerConfig {
   /// The name of the MQTT topic stuck state change commands are sent on, if desired
   pub stuck_topic: Option<String>,
 
-  /// If set, when first turned on the door will attempt to move to this state
+  /// The name of the MQTT topic the door's own availability is sent on, if desired.
+  ///
+  /// The door is only available once its state is known and while its sensor isn't stale. This is in addition to the
+  /// service's availability topic, which is what's marked offline if the service stops unexpectedly.
+  pub availability_topic: Option<String>,
+
+  /// The name of the MQTT topic the health of the door's sensor(s) is sent on, if desired
+  pub diagnostics_topic: Option<String>,
+
+  /// The name of the MQTT topic the door's estimated position (0-100% open) is sent on, if desired
+  pub position_topic: Option<String>,
+
+  /// The name of the MQTT topic position commands (0-100% open) are received on, if desired.
+  ///
+  /// The door is moved part way by triggering the remote and then stopping it once it's estimated to have reached the
+  /// position, so the remote must be able to stop the door.
+  pub set_position_topic: Option<String>,
+
+  /// If set, when first turned on (i.e. without a saved state) the door will attempt to move to this state
   pub initial_target_state: Option<TargetState>,
 
   /// The remote used to open and close the door
   pub remote: RemoteConfig,
 
-  #[serde_as(as = "DurationSeconds<u64>")]
-  /// How long the door is expected to take to go to/from open/close.
+  /// Close the door automatically once it has been left open for a while, if desired.
+  ///
+  /// A `HOLD_OPEN` command on the command topic keeps the door open until it's next closed.
+  pub auto_close: Option<AutoCloseConfig>,
+
+  /// Move the door at times of day, if desired
+  pub schedule: Option<ScheduleConfig>,
+
+  #[serde_as(as = "Option<DurationSecondsWithFrac<f64>>")]
+  #[serde(default)]
+  /// How long the door takes to open fully from closed.
   ///
-  /// If it exceeds this it tries again.
-  pub travel_duration: Duration,
+  /// This is used to estimate the door's position, and if it takes much longer than this it's considered stuck. The
+  /// `calibrate` command measures it, and the measured value replaces this one once it has.
+  pub open_duration: Option<Duration>,
+
+  #[serde_as(as = "Option<DurationSecondsWithFrac<f64>>")]
+  #[serde(default)]
+  /// How long the door takes to close fully from open, see `open_duration`
+  pub close_duration: Option<Duration>,
 
-  #[serde_as(as = "DurationSeconds<u64>")]
+  #[serde_as(as = "Option<DurationSeconds<u64>>")]
+  #[serde(default)]
+  /// How long the door takes to go to/from open/close, used for `open_duration` and `close_duration` if they aren't set.
+  ///
+  /// If the door takes longer than this it tries again, whereas it's given a little longer than `open_duration` and
+  /// `close_duration`.
+  pub travel_duration: Option<Duration>,
+
+  #[serde_as(as = "DurationSecondsWithFrac<f64>")]
   /// The maximum time the remote's signal can take to start moving the door.
   ///
-  /// If the door doesn't open after this time it'll try again.
+  /// If the door doesn't open after this time it'll try again. This is also replaced by the calibrated value, if the
+  /// door has been calibrated.
+  pub max_remote_latency_duration: Duration,
+}
+
+/// The durations of a door which can be changed while it's running
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DoorTimings {
   pub max_remote_latency_duration: Duration,
+  pub pressed_time: Duration,
+  pub wait_time: Duration,
+  pub open_duration: Duration,
+  pub close_duration: Duration,
+  /// How long the door can take to open once it has started, before it has failed
+  pub open_limit: Duration,
+  /// How long the door can take to close once it has started, before it has failed
+  pub close_limit: Duration,
+}
+
+/// How much longer than its open/close duration the door can take to travel before it's considered to have failed
+const TRAVEL_TOLERANCE: f64 = 1.25;
+
+/// How long the door can take to travel before it has failed.
+///
+/// `travel_duration` has always been the limit itself, so only an open or close duration (which is how long the door
+/// actually takes) is given some tolerance.
+fn travel_limit(duration: Option<Duration>, travel_duration: Option<Duration>) -> Duration {
+  match duration {
+    Some(duration) => duration.mul_f64(TRAVEL_TOLERANCE),
+    None => travel_duration.unwrap_or_default(),
+  }
+}
+
+impl DoorControllerConfig {
+  pub fn timings(&self) -> DoorTimings {
+    DoorTimings {
+      max_remote_latency_duration: self.max_remote_latency_duration,
+      pressed_time: self.remote.pressed_time,
+      wait_time: self.remote.wait_time,
+      open_duration: self.open_duration.or(self.travel_duration).unwrap_or_default(),
+      close_duration: self.close_duration.or(self.travel_duration).unwrap_or_default(),
+      open_limit: travel_limit(self.open_duration, self.travel_duration),
+      close_limit: travel_limit(self.close_duration, self.travel_duration),
+    }
+  }
+
+  /// Use the durations measured by calibrating the door in place of the configured ones
+  pub fn apply_calibration(&mut self, calibration: &Calibration) {
+    if let Some(open_duration) = calibration.open_duration {
+      self.open_duration = Some(open_duration);
+    }
+    if let Some(close_duration) = calibration.close_duration {
+      self.close_duration = Some(close_duration);
+    }
+    self.max_remote_latency_duration = calibration.max_remote_latency_duration;
+  }
+
+  /// Whether this is the same as `other`, ignoring the timings
+  pub fn eq_ignoring_timings(&self, other: &DoorControllerConfig) -> bool {
+    let mut other = other.clone();
+    other.travel_duration = self.travel_duration;
+    other.max_remote_latency_duration = self.max_remote_latency_duration;
+    other.remote.pressed_time = self.remote.pressed_time;
+    other.remote.wait_time = self.remote.wait_time;
+    other.open_duration = self.open_duration;
+    other.close_duration = self.close_duration;
+    *self == other
+  }
 }
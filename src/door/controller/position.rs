@@ -0,0 +1,88 @@
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+use crate::door::state::TargetState;
+
+/// The position of a fully open door, as a percentage open
+pub const OPEN_POSITION: u8 = 100;
+/// The position of a closed door
+pub const CLOSED_POSITION: u8 = 0;
+
+/// Estimates how far open the door is from how long it has been travelling
+#[derive(Debug)]
+pub struct PositionEstimate {
+  /// How long the door takes to open from closed
+  pub open_duration: Duration,
+  /// How long the door takes to close from fully open
+  pub close_duration: Duration,
+  /// The position when the door last started or stopped moving, as a percentage open
+  position: f64,
+  /// The direction the door is moving in, and when it started
+  movement: Option<(TargetState, Instant)>,
+}
+
+impl PositionEstimate {
+  pub fn new(position: u8, open_duration: Duration, close_duration: Duration) -> Self {
+    PositionEstimate {
+      open_duration,
+      close_duration,
+      position: position.into(),
+      movement: None,
+    }
+  }
+
+  fn estimate(&self) -> f64 {
+    match self.movement {
+      Some((direction, started)) => {
+        let (duration, sign) = match direction {
+          TargetState::Open => (self.open_duration, 1.0),
+          TargetState::Closed => (self.close_duration, -1.0),
+        };
+        let travelled = started.elapsed().as_secs_f64() / duration.as_secs_f64().max(f64::EPSILON) * 100.0;
+        (self.position + sign * travelled).clamp(CLOSED_POSITION.into(), OPEN_POSITION.into())
+      }
+      None => self.position,
+    }
+  }
+
+  /// The estimated position, as a percentage open
+  pub fn position(&self) -> u8 {
+    self.estimate().round() as u8
+  }
+
+  /// The direction the door is moving in, if it is
+  pub fn direction(&self) -> Option<TargetState> {
+    self.movement.map(|(direction, _)| direction)
+  }
+
+  /// The door has started moving in `direction`, unless it already was
+  pub fn start(&mut self, direction: TargetState) {
+    if self.direction() != Some(direction) {
+      self.position = self.estimate();
+      self.movement = Some((direction, Instant::now()));
+    }
+  }
+
+  /// The door has stopped, wherever it's estimated to be
+  pub fn stop(&mut self) {
+    self.position = self.estimate();
+    self.movement = None;
+  }
+
+  /// The door is known to have stopped at `position`, e.g. it's at a limit
+  pub fn set(&mut self, position: u8) {
+    self.position = position.into();
+    self.movement = None;
+  }
+
+  /// When the door will reach `position` if it keeps moving as it is, if it's moving towards it
+  pub fn reaches(&self, position: u8) -> Option<Instant> {
+    let (direction, started) = self.movement?;
+    let (duration, distance) = match direction {
+      TargetState::Open => (self.open_duration, f64::from(position) - self.position),
+      TargetState::Closed => (self.close_duration, self.position - f64::from(position)),
+    };
+    (distance >= 0.0).then(|| started + duration.mul_f64(distance / 100.0))
+  }
+}
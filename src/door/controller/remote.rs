@@ -1,21 +1,28 @@
 use std::sync::Arc;
 
-pub use config::RemoteConfig;
+pub use config::{RemoteButtons, RemoteConfig};
 use log::debug;
 use mutex::RemoteMutex;
 #[cfg(feature = "arm")]
 use rppal::gpio::{Gpio, OutputPin};
 
-use crate::error::GarageResult;
 #[cfg(not(feature = "arm"))]
 use crate::mock_gpio::{Gpio, OutputPin};
+use crate::{config::gpio::GpioPin, door::state::TargetState, error::GarageResult};
 
 mod config;
 pub mod mutex;
 
+#[derive(Debug)]
+enum ButtonPins {
+  Directional { open: OutputPin, close: OutputPin },
+  Toggle(OutputPin),
+}
+
 #[derive(Debug)]
 pub struct DoorRemote {
-  pin: OutputPin,
+  buttons: ButtonPins,
+  stop_pin: Option<OutputPin>,
   pub config: RemoteConfig,
   mutex: Arc<RemoteMutex>,
 }
@@ -23,20 +30,91 @@ pub struct DoorRemote {
 impl DoorRemote {
   pub fn new(config: RemoteConfig, mutex: Arc<RemoteMutex>) -> GarageResult<Self> {
     let gpio = Gpio::new()?;
-    let pin = gpio.get(config.pin.bcm_number())?.into_output();
+    let output = |pin: GpioPin| -> GarageResult<OutputPin> { Ok(gpio.get(pin.bcm_number())?.into_output()) };
+    let buttons = match config.buttons {
+      RemoteButtons::Directional { open_pin, close_pin } => ButtonPins::Directional {
+        open: output(open_pin)?,
+        close: output(close_pin)?,
+      },
+      RemoteButtons::Toggle { pin } => ButtonPins::Toggle(output(pin)?),
+    };
+    let stop_pin = match config.stop_pin {
+      Some(stop_pin) => Some(output(stop_pin)?),
+      None => None,
+    };
+
+    Ok(DoorRemote {
+      buttons,
+      stop_pin,
+      config,
+      mutex,
+    })
+  }
+
+  /// Whether the remote has a separate stop button
+  pub fn has_stop_button(&self) -> bool {
+    self.stop_pin.is_some()
+  }
+
+  /// Whether the remote can stop the door mid-travel, see [`RemoteConfig::can_stop`]
+  pub fn can_stop(&self) -> bool {
+    self.config.can_stop()
+  }
 
-    Ok(DoorRemote { pin, config, mutex })
+  /// Whether the remote always moves the door in the direction asked, rather than toggling it
+  pub fn is_directional(&self) -> bool {
+    matches!(self.buttons, ButtonPins::Directional { .. })
   }
 
-  /// Trigger the remote to send the open/close signal
-  pub async fn trigger(&mut self) {
-    let guard = self.mutex.lock().await;
+  async fn press(pin: &mut OutputPin, config: &RemoteConfig, mutex: &RemoteMutex) {
+    let guard = mutex.lock().await;
     debug!("Locked remote mutex");
-    self.pin.set_high();
-    tokio::time::sleep(self.config.pressed_time).await;
-    self.pin.set_low();
-    tokio::time::sleep(self.config.wait_time).await;
+    pin.set_high();
+    tokio::time::sleep(config.pressed_time).await;
+    pin.set_low();
+    tokio::time::sleep(config.wait_time).await;
     debug!("Unlocked remote mutex");
     drop(guard);
   }
+
+  /// Trigger the remote to move the door towards `target_state`.
+  ///
+  /// A toggle remote sends the same signal either way, so it's down to the opener which way the door moves.
+  pub async fn trigger(&mut self, target_state: TargetState) {
+    let pin = match (&mut self.buttons, target_state) {
+      (ButtonPins::Directional { open, .. }, TargetState::Open) => open,
+      (ButtonPins::Directional { close, .. }, TargetState::Closed) => close,
+      (ButtonPins::Toggle(pin), _) => pin,
+    };
+    Self::press(pin, &self.config, &self.mutex).await;
+  }
+
+  /// Trigger the remote's stop button, or toggle the remote if it doesn't have one (which stops most doors
+  /// mid-travel).
+  ///
+  /// Does nothing if the remote can't stop the door, see [`DoorRemote::can_stop`].
+  pub async fn trigger_stop(&mut self) {
+    let pin = match (&mut self.stop_pin, &mut self.buttons) {
+      (Some(stop_pin), _) => stop_pin,
+      (None, ButtonPins::Toggle(pin)) => pin,
+      (None, ButtonPins::Directional { .. }) => return,
+    };
+    Self::press(pin, &self.config, &self.mutex).await;
+  }
+}
+
+impl Drop for DoorRemote {
+  fn drop(&mut self) {
+    // the remote must never be left pressed, even if a press was interrupted
+    match &mut self.buttons {
+      ButtonPins::Directional { open, close } => {
+        open.set_low();
+        close.set_low();
+      }
+      ButtonPins::Toggle(pin) => pin.set_low(),
+    }
+    if let Some(stop_pin) = &mut self.stop_pin {
+      stop_pin.set_low();
+    }
+  }
 }
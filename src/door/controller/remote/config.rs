@@ -5,11 +5,27 @@ use serde_with::{serde_as, DurationSecondsWithFrac};
 
 use crate::config::gpio::GpioPin;
 
+/// The buttons which move the door
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum RemoteButtons {
+  /// Separate open and close buttons, so the door is guaranteed to move in the right direction
+  Directional { open_pin: GpioPin, close_pin: GpioPin },
+  /// A single button which toggles the door, so the direction it moves depends on the opener
+  Toggle { pin: GpioPin },
+}
+
 #[serde_as]
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Clone, PartialEq)]
 pub struct RemoteConfig {
-  /// The pin of the door remote
-  pub pin: GpioPin,
+  /// The pin(s) of the door remote, either `pin` or `open_pin` and `close_pin`
+  #[serde(flatten)]
+  pub buttons: RemoteButtons,
+
+  /// The pin of the remote's stop button, if it has one.
+  ///
+  /// Without one, a toggle remote stops the door by being pressed while it's travelling.
+  pub stop_pin: Option<GpioPin>,
 
   #[serde_as(as = "DurationSecondsWithFrac<f64>")]
   /// How long the remote pin is high for (i.e. how long the remote signal is sent)
@@ -19,3 +35,24 @@ pub struct RemoteConfig {
   /// How long to wait after pressing the remote before pressing another remote
   pub wait_time: Duration,
 }
+
+impl RemoteConfig {
+  /// Whether the remote can stop the door mid-travel, either with its stop button or by toggling it
+  pub fn can_stop(&self) -> bool {
+    self.stop_pin.is_some() || matches!(self.buttons, RemoteButtons::Toggle { .. })
+  }
+
+  /// Every pin the remote uses, along with what uses them
+  pub fn pins(&self) -> Vec<(GpioPin, &'static str)> {
+    let buttons = match self.buttons {
+      RemoteButtons::Directional { open_pin, close_pin } => {
+        vec![(open_pin, "remote open button"), (close_pin, "remote close button")]
+      }
+      RemoteButtons::Toggle { pin } => vec![(pin, "remote")],
+    };
+    buttons
+      .into_iter()
+      .chain(self.stop_pin.map(|pin| (pin, "remote stop button")))
+      .collect()
+  }
+}
@@ -6,6 +6,12 @@ use tokio::sync::Mutex;
 #[derive(Debug)]
 pub struct RemoteMutex(Mutex<()>);
 
+impl Default for RemoteMutex {
+  fn default() -> Self {
+    RemoteMutex::new()
+  }
+}
+
 impl RemoteMutex {
   pub fn new() -> Self {
     RemoteMutex(Mutex::new(()))
@@ -0,0 +1,189 @@
+use std::{
+  collections::BTreeSet,
+  fs,
+  path::{Path, PathBuf},
+  time::Duration,
+};
+
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, DurationSecondsWithFrac};
+
+use crate::door::{
+  identifier::Identifier,
+  state::{AssumedTravel, ConfirmedTravel, DetectedState, State, TargetState},
+};
+
+/// A travel that was in progress when the state was saved
+#[serde_as]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SavedTravel {
+  /// When the travel (or its latest attempt) started
+  pub started: DateTime<Utc>,
+  #[serde_as(as = "DurationSecondsWithFrac<f64>")]
+  pub duration: Duration,
+  /// The number of times the travel had been reattempted
+  #[serde(default)]
+  pub attempt: u8,
+}
+
+impl From<&ConfirmedTravel> for SavedTravel {
+  fn from(travel: &ConfirmedTravel) -> Self {
+    SavedTravel {
+      started: travel.started(),
+      duration: travel.duration(),
+      attempt: travel.attempt(),
+    }
+  }
+}
+
+impl From<&AssumedTravel> for SavedTravel {
+  fn from(travel: &AssumedTravel) -> Self {
+    SavedTravel {
+      started: travel.started(),
+      duration: travel.duration(),
+      attempt: 0,
+    }
+  }
+}
+
+impl SavedTravel {
+  fn resume_confirmed(&self) -> ConfirmedTravel {
+    ConfirmedTravel::resume(self.duration, self.started, self.attempt)
+  }
+
+  fn resume_assumed(&self) -> AssumedTravel {
+    AssumedTravel::resume(self.duration, self.started)
+  }
+}
+
+/// A serializable copy of the controller's [`State`]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum SavedDoorState {
+  Unknown,
+  AttemptingOpen(SavedTravel),
+  Opening(SavedTravel),
+  ConfirmedOpening(SavedTravel),
+  Open,
+  StuckOpen,
+  Closing(SavedTravel),
+  Closed,
+  StuckClosed,
+  Stopped,
+}
+
+impl From<&State> for SavedDoorState {
+  fn from(state: &State) -> Self {
+    match state {
+      State::Unknown => SavedDoorState::Unknown,
+      State::AttemptingOpen(travel) => SavedDoorState::AttemptingOpen(travel.into()),
+      State::Opening(travel) => SavedDoorState::Opening(travel.into()),
+      State::ConfirmedOpening(travel) => SavedDoorState::ConfirmedOpening(travel.into()),
+      State::Open => SavedDoorState::Open,
+      State::StuckOpen => SavedDoorState::StuckOpen,
+      State::Closing(travel) => SavedDoorState::Closing(travel.into()),
+      State::Closed => SavedDoorState::Closed,
+      State::StuckClosed => SavedDoorState::StuckClosed,
+      State::Stopped => SavedDoorState::Stopped,
+    }
+  }
+}
+
+impl SavedDoorState {
+  /// Restore the saved state if it agrees with what the detector reports, otherwise the detected state is used, which
+  /// is [`State::Unknown`] if it doesn't say where the door is.
+  ///
+  /// Travels resume with whatever time they had left (expiring straight away if that has passed), so a travel which
+  /// was interrupted is retried or marked as stuck as it would have been had we not restarted.
+  pub fn restore(&self, detected_state: DetectedState) -> State {
+    match (self, detected_state) {
+      // the remote was pressed but the door hadn't started moving yet
+      (SavedDoorState::AttemptingOpen(travel), DetectedState::Closed) => {
+        State::AttemptingOpen(travel.resume_confirmed())
+      }
+      (SavedDoorState::Opening(travel), DetectedState::Open) => State::Opening(travel.resume_assumed()),
+      (SavedDoorState::ConfirmedOpening(travel), DetectedState::MidTravel) => {
+        State::ConfirmedOpening(travel.resume_confirmed())
+      }
+      (SavedDoorState::Closing(travel), DetectedState::Open | DetectedState::FullyOpen | DetectedState::MidTravel) => {
+        State::Closing(travel.resume_confirmed())
+      }
+      // the door is still where it got stuck
+      (SavedDoorState::StuckClosed, DetectedState::Closed | DetectedState::Stuck) => State::StuckClosed,
+      (
+        SavedDoorState::StuckOpen,
+        DetectedState::Open | DetectedState::FullyOpen | DetectedState::MidTravel | DetectedState::Stuck,
+      ) => State::StuckOpen,
+      // the door is still where it was stopped
+      (SavedDoorState::Stopped, DetectedState::Open | DetectedState::MidTravel) => State::Stopped,
+      (SavedDoorState::Closed, DetectedState::Stuck) => State::StuckClosed,
+      (SavedDoorState::Open | SavedDoorState::Stopped, DetectedState::Stuck) => State::StuckOpen,
+      // a faulty sensor can't confirm the saved state, so it stays unknown until the sensor recovers
+      (_, detected_state) => detected_state.into(),
+    }
+  }
+}
+
+/// The controller's state as it is saved between restarts
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SavedState {
+  pub state: SavedDoorState,
+  /// The command that was waiting for the door to stop travelling
+  pub next_target_state: Option<TargetState>,
+  /// The position command that was waiting for the door to stop travelling
+  #[serde(default)]
+  pub next_position: Option<u8>,
+  /// The door's estimated position, as a percentage open
+  #[serde(default)]
+  pub position: Option<u8>,
+  /// Whether the door is being held open, rather than closed automatically
+  #[serde(default)]
+  pub hold_open: bool,
+  /// When the door was left open, so it's still closed automatically on time
+  #[serde(default)]
+  pub opened_at: Option<DateTime<Utc>>,
+  /// Whether the next scheduled command is skipped
+  #[serde(default)]
+  pub skip_scheduled: bool,
+  /// The dates no scheduled commands are sent on
+  #[serde(default)]
+  pub holidays: BTreeSet<NaiveDate>,
+}
+
+impl SavedState {
+  /// The path the door's state is saved to
+  pub fn path(state_directory: &Path, identifier: &Identifier) -> PathBuf {
+    state_directory.join(format!("{}.controller.json", identifier.0))
+  }
+
+  /// Load the saved state, if there is one
+  pub fn load(path: &Path) -> Option<SavedState> {
+    let saved = fs::read_to_string(path).ok()?;
+    match serde_json::from_str(&saved) {
+      Ok(saved) => Some(saved),
+      Err(err) => {
+        log::warn!("ignoring invalid saved state {}: {}", path.display(), err);
+        None
+      }
+    }
+  }
+
+  pub fn save(&self, path: &Path) {
+    // write to a temporary file first so a power cut mid-write doesn't leave a corrupt state behind
+    let temporary_path = path.with_extension("json.tmp");
+    let result = path
+      .parent()
+      .map_or(Ok(()), fs::create_dir_all)
+      .and_then(|_| {
+        fs::write(
+          &temporary_path,
+          serde_json::to_string(self).expect("state is always serializable"),
+        )
+      })
+      .and_then(|_| fs::rename(&temporary_path, path));
+    if let Err(err) = result {
+      log::warn!("failed to save state to {}: {}", path.display(), err);
+    }
+  }
+}
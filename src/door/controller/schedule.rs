@@ -0,0 +1,572 @@
+use std::{collections::BTreeSet, pin::Pin, str::FromStr, sync::Arc};
+
+use chrono::{DateTime, Datelike, NaiveDate, NaiveTime, Utc, Weekday};
+use chrono_tz::Tz;
+use serde::Deserialize;
+use serde_with::{serde_as, DisplayFromStr};
+use tokio::time::Sleep;
+
+use self::sun::{Location, SunEvent};
+use crate::{
+  clock::{self, default_time_zone, resolve_local, Clock},
+  door::state::{State, TargetState},
+};
+
+pub mod sun;
+
+/// How far ahead the next command is looked for, enough to get past any number of holidays
+const MAX_DAYS_AHEAD: usize = 366;
+/// How late a command can be and still be sent, e.g. if the service was busy, beyond which the clock must have jumped
+const MAX_LATENESS: chrono::Duration = chrono::Duration::minutes(1);
+
+/// When in the day a rule moves the door
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(untagged)]
+pub enum RuleTime {
+  /// At a time of day
+  Time {
+    /// The time of day in the schedule's time zone, e.g. "22:00"
+    time: NaiveTime,
+  },
+  /// Relative to sunrise or sunset, which needs the schedule's location
+  Sun {
+    /// "sunrise" or "sunset"
+    sun: SunEvent,
+    /// How many seconds after the event, negative for before, e.g. 1800 for half an hour after
+    #[serde(default)]
+    offset: i64,
+  },
+}
+
+/// The states a rule can be limited to, named as the door's state is published
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum StateCondition {
+  Opening,
+  Open,
+  Closing,
+  Closed,
+  Stopped,
+}
+
+impl StateCondition {
+  pub fn matches(self, state: &State) -> bool {
+    matches!(
+      (self, state),
+      (
+        StateCondition::Opening,
+        State::AttemptingOpen(_) | State::Opening(_) | State::ConfirmedOpening(_)
+      ) | (StateCondition::Open, State::Open | State::StuckOpen)
+        | (StateCondition::Closing, State::Closing(_))
+        | (StateCondition::Closed, State::Closed | State::StuckClosed)
+        | (StateCondition::Stopped, State::Stopped)
+    )
+  }
+}
+
+/// Moves the door to a state each day, at a time of day or relative to sunrise or sunset
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct ScheduleRule {
+  /// The state to move the door to, if it isn't already in it
+  pub target_state: TargetState,
+  #[serde(flatten)]
+  pub at: RuleTime,
+  /// The days of the week the rule applies on, e.g. ["Sat", "Sun"], every day if empty
+  #[serde(default)]
+  pub days: Vec<Weekday>,
+  /// Only move the door if it's in one of these states when the rule is due, e.g. ["open"], whatever state it's in if
+  /// empty
+  #[serde(default)]
+  pub if_state: Vec<StateCondition>,
+}
+
+impl ScheduleRule {
+  fn applies_on(&self, date: NaiveDate) -> bool {
+    self.days.is_empty() || self.days.contains(&date.weekday())
+  }
+
+  /// Whether the rule moves the door when it's in `state`
+  pub fn applies_in(&self, state: &State) -> bool {
+    self.if_state.is_empty() || self.if_state.iter().any(|condition| condition.matches(state))
+  }
+}
+
+/// Commands sent to the door at times of day
+#[serde_as]
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct ScheduleConfig {
+  #[serde_as(as = "DisplayFromStr")]
+  #[serde(default = "default_time_zone")]
+  /// The time zone the rules' times are in, e.g. "Europe/London", UTC by default
+  pub time_zone: Tz,
+
+  /// The name of the MQTT topic overrides are received on, if desired.
+  ///
+  /// `SKIP` skips the next command, a date (e.g. `2026-12-25`) skips every command on that day, and `CLEAR` cancels
+  /// them.
+  pub override_topic: Option<String>,
+
+  /// Where the door is, which is needed for rules relative to sunrise or sunset
+  pub location: Option<Location>,
+
+  /// The commands to send, each whenever it's due
+  pub rules: Vec<ScheduleRule>,
+}
+
+impl ScheduleConfig {
+  /// When `rule` is due on the local `date`, if it is.
+  ///
+  /// Times of day are found for the local date, so they stay at the same time of day as the clocks change. The sun's
+  /// times are calculated in UTC, so they aren't affected by the clocks at all.
+  fn due_on(&self, rule: &ScheduleRule, date: NaiveDate) -> Option<DateTime<Utc>> {
+    if !rule.applies_on(date) {
+      return None;
+    }
+
+    match rule.at {
+      RuleTime::Time { time } => resolve_local(self.time_zone, date.and_time(time)),
+      RuleTime::Sun { sun, offset } => {
+        let time = self.location?.sun_time(date, sun)?;
+        time.checked_add_signed(chrono::Duration::seconds(offset))
+      }
+    }
+  }
+
+  /// The index of the first rule due after `after`, and when it's due, except on `holidays`
+  pub fn next_after(&self, after: DateTime<Utc>, holidays: &BTreeSet<NaiveDate>) -> Option<(DateTime<Utc>, usize)> {
+    let today = after.with_timezone(&self.time_zone).date_naive();
+    today
+      .iter_days()
+      .take(MAX_DAYS_AHEAD)
+      .filter(|date| !holidays.contains(date))
+      .find_map(|date| {
+        self
+          .rules
+          .iter()
+          .enumerate()
+          .filter_map(|(index, rule)| Some((self.due_on(rule, date)?, index)))
+          .filter(|(time, _)| *time > after)
+          .min_by_key(|(time, _)| *time)
+      })
+  }
+}
+
+/// A change to the schedule, received on its override topic
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScheduleOverride {
+  /// Skip the next command
+  SkipNext,
+  /// Skip every command on a date
+  Holiday(NaiveDate),
+  /// Cancel any skips and holidays
+  Clear,
+}
+
+impl FromStr for ScheduleOverride {
+  type Err = ();
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "SKIP" => Ok(ScheduleOverride::SkipNext),
+      "CLEAR" => Ok(ScheduleOverride::Clear),
+      s => NaiveDate::from_str(s).map(ScheduleOverride::Holiday).map_err(|_| ()),
+    }
+  }
+}
+
+/// What happened to a command when it came due
+#[derive(Debug, Clone, PartialEq)]
+pub enum ScheduleEvent {
+  /// The rule is due, so the door should move if it's in a state the rule applies in
+  Due(ScheduleRule),
+  /// The command was skipped by an override
+  Skipped(TargetState),
+  /// The command wasn't sent as the clock jumped past it
+  Missed(TargetState),
+}
+
+/// Sends the door's scheduled commands as they come due
+#[derive(Debug)]
+pub struct Schedule {
+  config: ScheduleConfig,
+  clock: Arc<dyn Clock>,
+  /// Whether the next command is skipped
+  skip_next: bool,
+  /// The dates in the schedule's time zone no commands are sent on
+  holidays: BTreeSet<NaiveDate>,
+  /// When the next command is due, and the index of its rule
+  next: Option<(DateTime<Utc>, usize)>,
+  /// Wakes the door when the next command is due
+  timer: Option<Pin<Box<Sleep>>>,
+}
+
+impl Schedule {
+  pub fn new(config: ScheduleConfig, clock: Arc<dyn Clock>, skip_next: bool, holidays: BTreeSet<NaiveDate>) -> Self {
+    let mut schedule = Schedule {
+      config,
+      clock,
+      skip_next,
+      holidays,
+      next: None,
+      timer: None,
+    };
+    let now = schedule.clock.now();
+    schedule.schedule_after(now);
+    schedule
+  }
+
+  /// Whether the next command is skipped
+  pub fn skip_next(&self) -> bool {
+    self.skip_next
+  }
+
+  /// The dates no commands are sent on
+  pub fn holidays(&self) -> &BTreeSet<NaiveDate> {
+    &self.holidays
+  }
+
+  /// The next command and when it's due, if there is one
+  pub fn next(&self) -> Option<(DateTime<Tz>, TargetState)> {
+    self.next.map(|(due, index)| {
+      (
+        due.with_timezone(&self.config.time_zone),
+        self.config.rules[index].target_state,
+      )
+    })
+  }
+
+  /// Find the first command due after `after`
+  fn schedule_after(&mut self, after: DateTime<Utc>) {
+    // holidays which have passed are no longer needed
+    let today = self.clock.now().with_timezone(&self.config.time_zone).date_naive();
+    self.holidays.retain(|holiday| *holiday >= today);
+
+    self.next = self.config.next_after(after, &self.holidays);
+    self.timer = self.next.map(|(due, _)| clock::sleep_until(self.clock.as_ref(), due));
+  }
+
+  /// Change the schedule as an override says to
+  pub fn apply(&mut self, schedule_override: ScheduleOverride) {
+    match schedule_override {
+      ScheduleOverride::SkipNext => self.skip_next = true,
+      ScheduleOverride::Holiday(date) => {
+        self.holidays.insert(date);
+      }
+      ScheduleOverride::Clear => {
+        self.skip_next = false;
+        self.holidays.clear();
+      }
+    }
+
+    let now = self.clock.now();
+    self.schedule_after(now);
+  }
+
+  /// Wait until the next command is due.
+  ///
+  /// Returns `None` straight away if there are no commands, or if it turns out the command isn't due yet (e.g. the
+  /// clock went back).
+  pub async fn next_event(&mut self) -> Option<ScheduleEvent> {
+    self.timer.as_mut()?.await;
+    let (due, index) = self.next?;
+    let rule = self.config.rules[index].clone();
+    let now = self.clock.now();
+    if due > now {
+      self.timer = Some(clock::sleep_until(self.clock.as_ref(), due));
+      return None;
+    }
+
+    // only the commands from now on are sent, rather than catching up on every one that was missed
+    self.schedule_after(now);
+    if now - due > MAX_LATENESS {
+      Some(ScheduleEvent::Missed(rule.target_state))
+    }
+    else if self.skip_next {
+      self.skip_next = false;
+      Some(ScheduleEvent::Skipped(rule.target_state))
+    }
+    else {
+      Some(ScheduleEvent::Due(rule))
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{collections::BTreeSet, time::Duration};
+
+  use chrono::{DateTime, NaiveDate, Utc};
+
+  use super::{Schedule, ScheduleConfig, ScheduleEvent, ScheduleOverride, ScheduleRule};
+  use crate::{
+    clock::{Clock, FakeClock},
+    door::state::{AssumedTravel, ConfirmedTravel, State, TargetState},
+  };
+
+  fn utc(time: &str) -> DateTime<Utc> {
+    time.parse().unwrap()
+  }
+
+  fn date(date: &str) -> NaiveDate {
+    date.parse().unwrap()
+  }
+
+  fn config(toml: &str) -> ScheduleConfig {
+    toml::from_str(toml).unwrap()
+  }
+
+  fn close_at(time: &str) -> ScheduleConfig {
+    config(&format!(
+      r#"
+      time_zone = "Europe/London"
+      rules = [{{ target_state = "CLOSED", time = "{}" }}]
+      "#,
+      time
+    ))
+  }
+
+  #[test]
+  fn time_skipped_as_the_clocks_go_forward_is_an_hour_later() {
+    // 01:00 to 02:00 doesn't happen in London on 2026-03-29
+    let (due, _) = close_at("01:30")
+      .next_after(utc("2026-03-28T12:00:00Z"), &BTreeSet::new())
+      .unwrap();
+    assert_eq!(due, utc("2026-03-29T01:30:00Z"));
+  }
+
+  #[test]
+  fn time_repeated_as_the_clocks_go_back_is_the_first() {
+    // 01:00 to 02:00 happens twice in London on 2026-10-25, first in BST then GMT
+    let schedule = close_at("01:30");
+    let (due, _) = schedule
+      .next_after(utc("2026-10-24T12:00:00Z"), &BTreeSet::new())
+      .unwrap();
+    assert_eq!(due, utc("2026-10-25T00:30:00Z"));
+
+    // and only once
+    let (due, _) = schedule.next_after(due, &BTreeSet::new()).unwrap();
+    assert_eq!(due, utc("2026-10-26T01:30:00Z"));
+  }
+
+  #[test]
+  fn rules_are_at_local_time() {
+    let (due, _) = close_at("22:00")
+      .next_after(utc("2026-07-01T12:00:00Z"), &BTreeSet::new())
+      .unwrap();
+    assert_eq!(due, utc("2026-07-01T21:00:00Z"));
+  }
+
+  #[test]
+  fn earliest_rule_is_next() {
+    let schedule = config(
+      r#"
+      rules = [
+        { target_state = "CLOSED", time = "22:00" },
+        { target_state = "OPEN", time = "07:00", days = ["Mon", "Tue", "Wed", "Thu", "Fri"] },
+      ]
+      "#,
+    );
+    // a Friday
+    let (due, index) = schedule
+      .next_after(utc("2026-07-03T12:00:00Z"), &BTreeSet::new())
+      .unwrap();
+    assert_eq!((due, index), (utc("2026-07-03T22:00:00Z"), 0));
+    // the weekend is skipped
+    let (due, index) = schedule
+      .next_after(utc("2026-07-04T23:00:00Z"), &BTreeSet::new())
+      .unwrap();
+    assert_eq!((due, index), (utc("2026-07-05T22:00:00Z"), 0));
+    let (due, index) = schedule.next_after(due, &BTreeSet::new()).unwrap();
+    assert_eq!((due, index), (utc("2026-07-06T07:00:00Z"), 1));
+  }
+
+  #[test]
+  fn holidays_are_skipped() {
+    let holidays = BTreeSet::from([date("2026-12-25"), date("2026-12-26")]);
+    let (due, _) = close_at("22:00")
+      .next_after(utc("2026-12-24T23:00:00Z"), &holidays)
+      .unwrap();
+    assert_eq!(due, utc("2026-12-27T22:00:00Z"));
+  }
+
+  #[test]
+  fn no_rules_are_never_due() {
+    assert_eq!(
+      config("rules = []").next_after(utc("2026-07-01T12:00:00Z"), &BTreeSet::new()),
+      None
+    );
+  }
+
+  #[test]
+  fn overrides_are_parsed() {
+    assert_eq!("SKIP".parse(), Ok(ScheduleOverride::SkipNext));
+    assert_eq!("CLEAR".parse(), Ok(ScheduleOverride::Clear));
+    assert_eq!("2026-12-25".parse(), Ok(ScheduleOverride::Holiday(date("2026-12-25"))));
+    assert_eq!("tomorrow".parse::<ScheduleOverride>(), Err(()));
+  }
+
+  #[tokio::test(start_paused = true)]
+  async fn due_command_is_sent() {
+    let clock = FakeClock::new(utc("2026-07-01T20:59:00Z"));
+    let mut schedule = Schedule::new(close_at("22:00"), clock.clone(), false, BTreeSet::new());
+
+    let event = schedule.next_event().await;
+    assert!(matches!(event, Some(ScheduleEvent::Due(rule)) if rule.target_state == TargetState::Closed));
+    assert_eq!(clock.now(), utc("2026-07-01T21:00:00Z"));
+    assert_eq!(
+      schedule.next().map(|(due, _)| due.with_timezone(&Utc)),
+      Some(utc("2026-07-02T21:00:00Z"))
+    );
+  }
+
+  #[tokio::test(start_paused = true)]
+  async fn skip_skips_only_the_next_command() {
+    let clock = FakeClock::new(utc("2026-07-01T20:59:00Z"));
+    let mut schedule = Schedule::new(close_at("22:00"), clock.clone(), false, BTreeSet::new());
+    schedule.apply(ScheduleOverride::SkipNext);
+    assert!(schedule.skip_next());
+
+    assert_eq!(
+      schedule.next_event().await,
+      Some(ScheduleEvent::Skipped(TargetState::Closed))
+    );
+    assert!(!schedule.skip_next());
+    assert!(matches!(schedule.next_event().await, Some(ScheduleEvent::Due(_))));
+    assert_eq!(clock.now(), utc("2026-07-02T21:00:00Z"));
+  }
+
+  #[tokio::test(start_paused = true)]
+  async fn holiday_override_moves_the_next_command() {
+    let clock = FakeClock::new(utc("2026-07-01T12:00:00Z"));
+    let mut schedule = Schedule::new(close_at("22:00"), clock.clone(), false, BTreeSet::new());
+    schedule.apply(ScheduleOverride::Holiday(date("2026-07-01")));
+    assert_eq!(
+      schedule.next().map(|(due, _)| due.with_timezone(&Utc)),
+      Some(utc("2026-07-02T21:00:00Z"))
+    );
+
+    // clearing it (and any skip) puts it back
+    schedule.apply(ScheduleOverride::SkipNext);
+    schedule.apply(ScheduleOverride::Clear);
+    assert!(!schedule.skip_next());
+    assert!(schedule.holidays().is_empty());
+    assert_eq!(
+      schedule.next().map(|(due, _)| due.with_timezone(&Utc)),
+      Some(utc("2026-07-01T21:00:00Z"))
+    );
+  }
+
+  #[tokio::test(start_paused = true)]
+  async fn past_holidays_are_forgotten() {
+    let clock = FakeClock::new(utc("2026-07-01T12:00:00Z"));
+    let holidays = BTreeSet::from([date("2026-06-30"), date("2026-07-01")]);
+    let schedule = Schedule::new(close_at("22:00"), clock, false, holidays);
+    assert_eq!(schedule.holidays(), &BTreeSet::from([date("2026-07-01")]));
+  }
+
+  #[tokio::test(start_paused = true)]
+  async fn command_is_missed_if_the_clock_jumps_past_it() {
+    let clock = FakeClock::new(utc("2026-07-01T20:59:00Z"));
+    let mut schedule = Schedule::new(close_at("22:00"), clock.clone(), false, BTreeSet::new());
+    clock.set(utc("2026-07-01T21:30:00Z"));
+
+    assert_eq!(
+      schedule.next_event().await,
+      Some(ScheduleEvent::Missed(TargetState::Closed))
+    );
+    // it isn't caught up on, the next one is tomorrow's
+    assert_eq!(
+      schedule.next().map(|(due, _)| due.with_timezone(&Utc)),
+      Some(utc("2026-07-02T21:00:00Z"))
+    );
+  }
+
+  #[tokio::test(start_paused = true)]
+  async fn command_waits_if_the_clock_goes_back() {
+    let clock = FakeClock::new(utc("2026-07-01T20:59:00Z"));
+    let mut schedule = Schedule::new(close_at("22:00"), clock.clone(), false, BTreeSet::new());
+    clock.set(utc("2026-07-01T20:00:00Z"));
+
+    assert_eq!(schedule.next_event().await, None);
+    assert!(matches!(schedule.next_event().await, Some(ScheduleEvent::Due(_))));
+    assert_eq!(clock.now(), utc("2026-07-01T21:00:00Z"));
+  }
+
+  fn rule(toml: &str) -> ScheduleRule {
+    toml::from_str(toml).unwrap()
+  }
+
+  #[tokio::test]
+  async fn rules_only_apply_in_their_states() {
+    let close_if_open = rule(
+      r#"
+      target_state = "CLOSED"
+      time = "22:00"
+      if_state = ["open", "stopped"]
+      "#,
+    );
+    assert!(close_if_open.applies_in(&State::Open));
+    assert!(close_if_open.applies_in(&State::StuckOpen));
+    assert!(close_if_open.applies_in(&State::Stopped));
+    assert!(!close_if_open.applies_in(&State::Closed));
+    assert!(!close_if_open.applies_in(&State::Opening(AssumedTravel::new(Duration::from_secs(10)))));
+    assert!(!close_if_open.applies_in(&State::Unknown));
+
+    let open_if_closing = rule(
+      r#"
+      target_state = "OPEN"
+      time = "07:00"
+      if_state = ["closing", "closed"]
+      "#,
+    );
+    assert!(open_if_closing.applies_in(&State::Closing(ConfirmedTravel::new(Duration::from_secs(10)))));
+    assert!(open_if_closing.applies_in(&State::StuckClosed));
+    assert!(!open_if_closing.applies_in(&State::AttemptingOpen(ConfirmedTravel::new(Duration::from_secs(10)))));
+
+    // without any states, a rule applies whatever the state
+    let close = rule(
+      r#"
+      target_state = "CLOSED"
+      time = "22:00"
+      "#,
+    );
+    assert!(close.applies_in(&State::Open));
+    assert!(close.applies_in(&State::Unknown));
+  }
+
+  #[test]
+  fn sun_rules_are_relative_to_the_sun() {
+    let schedule = config(
+      r#"
+      location = { latitude = 51.5072, longitude = -0.1276 }
+      rules = [
+        { target_state = "CLOSED", sun = "sunset", offset = 1800 },
+        { target_state = "OPEN", sun = "sunrise", offset = -600 },
+      ]
+      "#,
+    );
+    // sunset is 20:21 on midsummer's day, sunrise 03:43 the next day
+    let (due, index) = schedule
+      .next_after(utc("2026-06-21T12:00:00Z"), &BTreeSet::new())
+      .unwrap();
+    assert_eq!(index, 0);
+    assert!(
+      (due - utc("2026-06-21T20:51:00Z")).num_seconds().abs() <= 120,
+      "{}",
+      due
+    );
+    let (due, index) = schedule.next_after(due, &BTreeSet::new()).unwrap();
+    assert_eq!(index, 1);
+    assert!(
+      (due - utc("2026-06-22T03:33:00Z")).num_seconds().abs() <= 120,
+      "{}",
+      due
+    );
+  }
+
+  #[test]
+  fn sun_rules_without_a_location_are_never_due() {
+    let schedule = config(r#"rules = [{ target_state = "CLOSED", sun = "sunset" }]"#);
+    assert_eq!(schedule.next_after(utc("2026-06-21T12:00:00Z"), &BTreeSet::new()), None);
+  }
+}
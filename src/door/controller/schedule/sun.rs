@@ -0,0 +1,129 @@
+use chrono::{DateTime, Datelike, NaiveDate, Utc};
+use serde::Deserialize;
+
+/// The sun's zenith at sunrise and sunset, allowing for refraction and the size of its disc
+const SUNRISE_ZENITH: f64 = 90.833;
+
+/// Where the door is, which the sun's times are calculated for
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+pub struct Location {
+  /// Degrees north of the equator, negative for south
+  pub latitude: f64,
+  /// Degrees east of the prime meridian, negative for west
+  pub longitude: f64,
+}
+
+/// A point in the sun's day the door can be moved relative to
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SunEvent {
+  Sunrise,
+  Sunset,
+}
+
+impl Location {
+  /// When `event` happens on `date`, or `None` if the sun doesn't rise or set that day (i.e. near the poles).
+  ///
+  /// This uses NOAA's general solar position calculations, which are accurate to within a minute or two. The date is
+  /// treated as a UTC date, which makes no noticeable difference to the sun's position.
+  pub fn sun_time(&self, date: NaiveDate, event: SunEvent) -> Option<DateTime<Utc>> {
+    let days_in_year = if date.leap_year() { 366.0 } else { 365.0 };
+    // the fraction of the way through the year at noon, in radians
+    let year = 2.0 * std::f64::consts::PI / days_in_year * f64::from(date.ordinal0());
+
+    // how far the sun is ahead of or behind the clock, in minutes
+    let equation_of_time = 229.18
+      * (0.000075 + 0.001868 * year.cos()
+        - 0.032077 * year.sin()
+        - 0.014615 * (2.0 * year).cos()
+        - 0.040849 * (2.0 * year).sin());
+    let declination = 0.006918 - 0.399912 * year.cos() + 0.070257 * year.sin() - 0.006758 * (2.0 * year).cos()
+      + 0.000907 * (2.0 * year).sin()
+      - 0.002697 * (3.0 * year).cos()
+      + 0.00148 * (3.0 * year).sin();
+
+    let latitude = self.latitude.to_radians();
+    let cos_hour_angle =
+      SUNRISE_ZENITH.to_radians().cos() / (latitude.cos() * declination.cos()) - latitude.tan() * declination.tan();
+    if !(-1.0..=1.0).contains(&cos_hour_angle) {
+      // the sun is up (or down) all day
+      return None;
+    }
+    let hour_angle = cos_hour_angle.acos().to_degrees();
+
+    let minutes = match event {
+      SunEvent::Sunrise => 720.0 - 4.0 * (self.longitude + hour_angle) - equation_of_time,
+      SunEvent::Sunset => 720.0 - 4.0 * (self.longitude - hour_angle) - equation_of_time,
+    };
+    let midnight = date.and_hms_opt(0, 0, 0)?.and_utc();
+    Some(midnight + chrono::Duration::seconds((minutes * 60.0).round() as i64))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use chrono::{DateTime, NaiveDate, Utc};
+
+  use super::{Location, SunEvent};
+
+  const LONDON: Location = Location {
+    latitude: 51.5072,
+    longitude: -0.1276,
+  };
+  const AUCKLAND: Location = Location {
+    latitude: -36.8485,
+    longitude: 174.7633,
+  };
+  const TROMSO: Location = Location {
+    latitude: 69.6492,
+    longitude: 18.9553,
+  };
+
+  fn date(date: &str) -> NaiveDate {
+    date.parse().unwrap()
+  }
+
+  /// Check the sun's time is within a couple of minutes of a published time, which is as accurate as it gets
+  fn assert_near(location: Location, on: &str, event: SunEvent, expected: &str) {
+    let expected: DateTime<Utc> = expected.parse().unwrap();
+    let time = location.sun_time(date(on), event).unwrap();
+    assert!(
+      (time - expected).num_seconds().abs() <= 120,
+      "{:?} on {} was {}, expected {}",
+      event,
+      on,
+      time,
+      expected
+    );
+  }
+
+  #[test]
+  fn london_midsummer() {
+    assert_near(LONDON, "2026-06-21", SunEvent::Sunrise, "2026-06-21T03:43:00Z");
+    assert_near(LONDON, "2026-06-21", SunEvent::Sunset, "2026-06-21T20:21:00Z");
+  }
+
+  #[test]
+  fn london_midwinter() {
+    assert_near(LONDON, "2026-12-21", SunEvent::Sunrise, "2026-12-21T08:04:00Z");
+    assert_near(LONDON, "2026-12-21", SunEvent::Sunset, "2026-12-21T15:54:00Z");
+  }
+
+  #[test]
+  fn sunrise_far_east_is_on_the_previous_utc_day() {
+    // 07:33 on the 21st in Auckland
+    assert_near(AUCKLAND, "2026-06-21", SunEvent::Sunrise, "2026-06-20T19:33:00Z");
+    assert_near(AUCKLAND, "2026-06-21", SunEvent::Sunset, "2026-06-21T05:11:00Z");
+  }
+
+  #[test]
+  fn no_sunrise_or_sunset_near_the_poles() {
+    // the midnight sun
+    assert_eq!(TROMSO.sun_time(date("2026-06-21"), SunEvent::Sunrise), None);
+    assert_eq!(TROMSO.sun_time(date("2026-06-21"), SunEvent::Sunset), None);
+    // the polar night
+    assert_eq!(TROMSO.sun_time(date("2026-12-21"), SunEvent::Sunrise), None);
+    // but otherwise it does
+    assert!(TROMSO.sun_time(date("2026-03-21"), SunEvent::Sunrise).is_some());
+  }
+}
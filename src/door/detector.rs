@@ -1,71 +1,165 @@
-use std::{fmt::Debug, future::Future};
+use std::{fmt::Debug, future::Future, path::Path};
 
 use serde::Deserialize;
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, watch};
 
-use self::zigbee2mqtt::{Zigbee2MqttDoorDetector, Zigbee2MqttDoorDetectorConfig};
-use super::{identifier::Identifier, state::DetectedState};
-use crate::{error::GarageResult, mqtt_client::receiver::MqttReceiver};
+use self::{
+  assumed::{AssumedDoorDetector, AssumedDoorDetectorConfig},
+  dual::{DualDoorDetector, DualDoorDetectorConfig},
+  gpio::{GpioDoorDetector, GpioDoorDetectorConfig},
+  health::SensorHealth,
+  zigbee2mqtt::{Zigbee2MqttDoorDetector, Zigbee2MqttDoorDetectorConfig},
+};
+use super::{
+  identifier::Identifier,
+  state::{DetectedState, DoorCommand},
+};
+use crate::{error::GarageResult, mqtt_client::receiver::MqttSubscriber};
 
-// pub mod assumed;
-// pub mod gpio;
+pub mod assumed;
+pub mod dual;
+pub mod gpio;
+pub mod health;
 pub mod zigbee2mqtt;
 
 pub trait DoorDetector: Debug {
   type Config;
 
+  /// Create the detector, `state_directory` being where it can save anything it needs to keep across restarts
   fn new(
     identifier: Identifier,
     config: Self::Config,
-    mqtt_receiver: &mut MqttReceiver,
+    mqtt_subscriber: &MqttSubscriber,
+    state_directory: &Path,
   ) -> impl Future<Output = GarageResult<Self>> + Send
   where
     Self: Sized;
 
+  /// A channel the controller sends its command along each time it triggers the remote.
+  ///
+  /// Only detectors which can't sense the door themselves need this.
+  fn travel_sender(&self) -> Option<mpsc::UnboundedSender<DoorCommand>> {
+    None
+  }
+
+  /// A channel the health of the detector's sensor(s) is sent along, if the detector can tell.
+  fn health_receiver(&self) -> Option<watch::Receiver<SensorHealth>> {
+    None
+  }
+
   /// Listen to state changes, sending any changes along the returned channel.
   ///
-  /// Must also return an initial state.
-  fn listen(self)
-    -> impl Future<Output = GarageResult<(DetectedState, mpsc::UnboundedReceiver<DetectedState>)>> + Send;
+  /// Must also return the initial state if it's known straight away, otherwise the first state is sent along the
+  /// channel once it's known. Anything spawned which uses the detector's pins or topics must hold on to a clone of
+  /// `release_guard` until it has stopped using them.
+  fn listen(
+    self,
+    release_guard: ReleaseGuard,
+  ) -> impl Future<Output = GarageResult<(Option<DetectedState>, mpsc::UnboundedReceiver<DetectedState>)>> + Send;
+}
+
+/// Held by a detector's tasks while they use its pins and topics, see [`Released`]
+#[derive(Debug, Clone)]
+pub struct ReleaseGuard {
+  _tx: mpsc::Sender<()>,
 }
 
-#[derive(Debug, Deserialize)]
+/// Completes once every [`ReleaseGuard`] has been dropped, i.e. the detector has released its pins and topics
+#[derive(Debug)]
+pub struct Released(mpsc::Receiver<()>);
+
+impl Released {
+  pub async fn wait(&mut self) {
+    // nothing is ever sent, so this only returns once the guards have all been dropped
+    self.0.recv().await;
+  }
+}
+
+pub fn release_guard() -> (ReleaseGuard, Released) {
+  let (tx, rx) = mpsc::channel(1);
+  (ReleaseGuard { _tx: tx }, Released(rx))
+}
+
+/// The channels connecting a detector to its door's controller
+#[derive(Debug)]
+pub struct DetectorChannels {
+  /// Changes to the detected state
+  pub state_rx: mpsc::UnboundedReceiver<DetectedState>,
+  /// Changes to the health of the detector's sensor(s), see [`DoorDetector::health_receiver`]
+  pub health_rx: Option<watch::Receiver<SensorHealth>>,
+  /// Travels are sent to the detector along this, see [`DoorDetector::travel_sender`]
+  pub travel_tx: Option<mpsc::UnboundedSender<DoorCommand>>,
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
 #[serde(untagged)]
 pub enum DoorDetectorConfig {
-  // Gpio(GpioDoorDetectorConfig),
-  // Assumed(AssumedDoorDetectorConfig),
+  Dual(DualDoorDetectorConfig),
+  Gpio(GpioDoorDetectorConfig),
+  Assumed(AssumedDoorDetectorConfig),
   Zigbee2Mqtt(Zigbee2MqttDoorDetectorConfig),
 }
 
 #[derive(Debug)]
 pub enum AnyDoorDetector {
-  // Gpio(GpioDoorDetector),
-  // Assumed(AssumedDoorDetector),
+  Dual(DualDoorDetector),
+  Gpio(GpioDoorDetector),
+  Assumed(AssumedDoorDetector),
   Zigbee2Mqtt(Zigbee2MqttDoorDetector),
 }
 
 impl DoorDetector for AnyDoorDetector {
   type Config = DoorDetectorConfig;
 
-  async fn new(identifier: Identifier, config: Self::Config, mqtt_receiver: &mut MqttReceiver) -> GarageResult<Self> {
+  async fn new(
+    identifier: Identifier,
+    config: Self::Config,
+    mqtt_subscriber: &MqttSubscriber,
+    state_directory: &Path,
+  ) -> GarageResult<Self> {
     match config {
-      // DoorDetectorConfig::Gpio(config) => Ok(AnyDoorDetector::Gpio(GpioDoorDetector::new(identifier, config)?)),
-      // DoorDetectorConfig::Assumed(config) => {
-      //   Ok(AnyDoorDetector::Assumed(AssumedDoorDetector::new(identifier, config)?))
-      // }
+      DoorDetectorConfig::Dual(config) => Ok(AnyDoorDetector::Dual(
+        DualDoorDetector::new(identifier, config, mqtt_subscriber, state_directory).await?,
+      )),
+      DoorDetectorConfig::Gpio(config) => Ok(AnyDoorDetector::Gpio(
+        GpioDoorDetector::new(identifier, config, mqtt_subscriber, state_directory).await?,
+      )),
+      DoorDetectorConfig::Assumed(config) => Ok(AnyDoorDetector::Assumed(
+        AssumedDoorDetector::new(identifier, config, mqtt_subscriber, state_directory).await?,
+      )),
       DoorDetectorConfig::Zigbee2Mqtt(config) => Ok(AnyDoorDetector::Zigbee2Mqtt(
-        Zigbee2MqttDoorDetector::new(identifier, config, mqtt_receiver).await?,
+        Zigbee2MqttDoorDetector::new(identifier, config, mqtt_subscriber, state_directory).await?,
       )),
     }
   }
 
-  fn listen(
+  fn travel_sender(&self) -> Option<mpsc::UnboundedSender<DoorCommand>> {
+    match self {
+      AnyDoorDetector::Dual(detector) => detector.travel_sender(),
+      AnyDoorDetector::Gpio(detector) => detector.travel_sender(),
+      AnyDoorDetector::Assumed(detector) => detector.travel_sender(),
+      AnyDoorDetector::Zigbee2Mqtt(detector) => detector.travel_sender(),
+    }
+  }
+
+  fn health_receiver(&self) -> Option<watch::Receiver<SensorHealth>> {
+    match self {
+      AnyDoorDetector::Dual(detector) => detector.health_receiver(),
+      AnyDoorDetector::Gpio(detector) => detector.health_receiver(),
+      AnyDoorDetector::Assumed(detector) => detector.health_receiver(),
+      AnyDoorDetector::Zigbee2Mqtt(detector) => detector.health_receiver(),
+    }
+  }
+
+  async fn listen(
     self,
-  ) -> impl Future<Output = GarageResult<(DetectedState, mpsc::UnboundedReceiver<DetectedState>)>> + Send {
+    release_guard: ReleaseGuard,
+  ) -> GarageResult<(Option<DetectedState>, mpsc::UnboundedReceiver<DetectedState>)> {
     match self {
-      // AnyDoorDetector::Gpio(detector) => detector.listen(),
-      // AnyDoorDetector::Assumed(detector) => detector.listen(),
-      AnyDoorDetector::Zigbee2Mqtt(detector) => detector.listen(),
+      AnyDoorDetector::Dual(detector) => detector.listen(release_guard).await,
+      AnyDoorDetector::Gpio(detector) => detector.listen(release_guard).await,
+      AnyDoorDetector::Assumed(detector) => detector.listen(release_guard).await,
+      AnyDoorDetector::Zigbee2Mqtt(detector) => detector.listen(release_guard).await,
     }
   }
 }
@@ -1,47 +1,66 @@
-use std::{fs, str::FromStr, time::Duration};
+use std::{
+  fs,
+  path::{Path, PathBuf},
+  str::FromStr,
+  time::Duration,
+};
 
 use log::warn;
 use serde::Deserialize;
 use serde_with::{serde_as, DurationSeconds};
+use tokio::{
+  select,
+  sync::mpsc::{self, UnboundedReceiver, UnboundedSender},
+  time,
+};
 
-use super::{DetectedState, DoorDetector, Travel};
+use super::{DetectedState, DoorDetector, ReleaseGuard};
 use crate::{
   door::{
-    state_controller::{State, TargetState},
-    Identifier,
+    identifier::Identifier,
+    state::{DoorCommand, TargetState},
   },
   error::GarageResult,
   mqtt_client::{
-    receiver::{MqttReceiver, PublishReceiver},
+    receiver::{topic_matches, MqttSubscriber},
     MqttPublish,
   },
 };
 
 #[serde_as]
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Clone, PartialEq)]
 pub struct AssumedDoorDetectorConfig {
   #[serde_as(as = "DurationSeconds<u64>")]
   /// How long the door is assumed to take to go to/from open/close.
+  ///
+  /// This should be a little shorter than the controller's `open_duration` and `close_duration`, otherwise the
+  /// controller will think the door failed to open/close.
   pub travel_time: Duration,
   /// Top topic state overrides can be sent to to correct an incorrect state.
   pub override_topic: String,
 }
 
 
+/// A detector for doors without any sensor.
+///
+/// The state is derived from the commands sent to the remote and how long the door takes to travel.
 #[derive(Debug)]
 pub struct AssumedDoorDetector {
-  identifier: Identifier, // TODO: can we borrow this?
+  identifier: Identifier,
+  /// Where the assumed state is saved, so it's kept across restarts
+  state_path: PathBuf,
   travel_time: Duration,
   override_topic: String,
-  current_travel: Option<Travel>,
   assumed_state: TargetState,
+  mqtt_rx: UnboundedReceiver<MqttPublish>,
+  travel_tx: UnboundedSender<DoorCommand>,
+  travel_rx: UnboundedReceiver<DoorCommand>,
 }
 
 impl AssumedDoorDetector {
   fn set_assumed_state(&mut self, assumed_state: TargetState) {
     self.assumed_state = assumed_state;
-    self.current_travel = None;
-    if let Err(err) = fs::write(format!("{}.state", &self.identifier.0), assumed_state.to_string()) {
+    if let Err(err) = fs::write(&self.state_path, assumed_state.to_string()) {
       warn!("failed to write assumed state: {}", err);
     }
   }
@@ -50,77 +69,116 @@ impl AssumedDoorDetector {
 impl DoorDetector for AssumedDoorDetector {
   type Config = AssumedDoorDetectorConfig;
 
-  fn new(identifier: Identifier, config: Self::Config) -> GarageResult<Self> {
-    let assumed_state = fs::read_to_string(format!("{}.state", &identifier.0))
+  async fn new(
+    identifier: Identifier,
+    config: Self::Config,
+    mqtt_subscriber: &MqttSubscriber,
+    state_directory: &Path,
+  ) -> GarageResult<Self> {
+    let state_path = state_directory.join(format!("{}.state", identifier.0));
+    let assumed_state = fs::read_to_string(&state_path)
       .ok()
       .and_then(|value| TargetState::from_str(&value).ok())
       .unwrap_or(TargetState::Closed);
 
+    let (travel_tx, travel_rx) = mpsc::unbounded_channel();
+
     Ok(AssumedDoorDetector {
       identifier,
+      state_path,
       travel_time: config.travel_time,
+      mqtt_rx: mqtt_subscriber
+        .subscribe(config.override_topic.clone(), rumqttc::QoS::AtLeastOnce)
+        .await?,
       override_topic: config.override_topic,
-      current_travel: None,
       assumed_state,
+      travel_tx,
+      travel_rx,
     })
   }
 
-  async fn travel(&mut self, target_state: TargetState) -> DetectedState {
-    if self.current_travel.is_some() {
-      panic!("AssumedDoorDetector attempted to travel while it was already travelling");
-    }
-    self.current_travel = Some(Travel::new(target_state));
-    tokio::time::sleep(self.travel_time).await;
-    self.detect_state()
-  }
-
-
-  fn detect_state(&mut self) -> DetectedState {
-    if let Some(current_travel) = &self.current_travel {
-      if current_travel.expired_invalid(self.assumed_state.into(), self.travel_time) {
-        let target_state = current_travel.target_state;
-        // door was moving and should've finished by now, we assume it's finished. move to the target state
-        self.set_assumed_state(target_state);
-        // TODO: write to file
-        self.assumed_state.into()
-      }
-      else {
-        // door is moving, use the previous state (which is the inverse of the target state)
-        self.assumed_state.into()
-      }
-    }
-    else {
-      // the door isn't moving, so we use the previously assumed state
-      self.assumed_state.into()
-    }
-  }
-
-  fn should_check_periodically(&self) -> bool {
-    true
-  }
-
-  async fn subscribe(&mut self, mqtt_receiver: &mut MqttReceiver) -> GarageResult<Option<PublishReceiver>> {
-    Ok(Some(
-      mqtt_receiver
-        .subscribe(self.override_topic.clone(), rumqttc::QoS::AtLeastOnce)
-        .await?,
-    ))
+  fn travel_sender(&self) -> Option<UnboundedSender<DoorCommand>> {
+    Some(self.travel_tx.clone())
   }
 
-  fn receive_message(&mut self, publish: MqttPublish) {
-    if &self.override_topic == &publish.topic {
-      if let Ok(override_state) = TargetState::from_str(&publish.payload) {
-        log::info!("{:?} overriding state to {:?}", &self.identifier, override_state);
-        self.set_assumed_state(override_state);
+  async fn listen(
+    mut self,
+    release_guard: ReleaseGuard,
+  ) -> GarageResult<(Option<DetectedState>, UnboundedReceiver<DetectedState>)> {
+    let (detector_tx, detector_rx) = mpsc::unbounded_channel();
+    let initial_state: DetectedState = self.assumed_state.into();
+
+    tokio::spawn(async move {
+      let _release_guard = release_guard;
+      let mut detected_state = initial_state;
+      // the state the door is currently travelling to, and when it is assumed to get there
+      let mut current_travel = None;
+
+      loop {
+        let next_state = select! {
+          Some(command) = self.travel_rx.recv() => {
+            match command {
+              DoorCommand::Move(target_state) => {
+                current_travel = Some((target_state, Box::pin(time::sleep(self.travel_time))));
+                match target_state {
+                  // the door is no longer closed as soon as it starts opening
+                  TargetState::Open => DetectedState::Open,
+                  // but it isn't closed until it has finished travelling
+                  TargetState::Closed => detected_state,
+                }
+              }
+              // the door was stopped part way, so it's left open
+              DoorCommand::Stop if current_travel.take().is_some() => {
+                self.set_assumed_state(TargetState::Open);
+                DetectedState::Open
+              }
+              // the controller only sends travels and stops
+              DoorCommand::Stop | DoorCommand::HoldOpen | DoorCommand::Calibrate => detected_state,
+            }
+          }
+
+          Some(target_state) = async {
+            if let Some((target_state, expiry)) = &mut current_travel {
+              expiry.await;
+              Some(*target_state)
+            } else {
+              None
+            }
+          } => {
+            // the door should've finished moving by now, we assume it's in the target state
+            log::debug!("{:?} assumed travel to {:?} complete", &self.identifier, target_state);
+            current_travel = None;
+            self.set_assumed_state(target_state);
+            target_state.into()
+          }
+
+          Some(publish) = self.mqtt_rx.recv() => {
+            match TargetState::from_str(&publish.payload) {
+              Ok(override_state) if topic_matches(&self.override_topic, &publish.topic) => {
+                log::info!("{:?} overriding state to {:?}", &self.identifier, override_state);
+                current_travel = None;
+                self.set_assumed_state(override_state);
+                override_state.into()
+              }
+              _ => detected_state,
+            }
+          }
+
+          _ = detector_tx.closed() => break,
+
+          else => break,
+        };
+
+        if next_state != detected_state {
+          detected_state = next_state;
+          if detector_tx.send(detected_state).is_err() {
+            // channel ended
+            break;
+          }
+        }
       }
-    }
-  }
+    });
 
-  fn manual_travel_state(&self, target_state: TargetState) -> State {
-    // manual changes only happen on override, we don't want
-    match target_state {
-      TargetState::Open => State::Open,
-      TargetState::Closed => State::Closed,
-    }
+    Ok((Some(initial_state), detector_rx))
   }
 }
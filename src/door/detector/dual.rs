@@ -0,0 +1,231 @@
+use std::path::Path;
+
+use serde::Deserialize;
+use tokio::{
+  select,
+  sync::{
+    mpsc::{self, UnboundedReceiver},
+    watch,
+  },
+};
+
+use super::{
+  gpio::{GpioDoorDetector, GpioDoorDetectorConfig},
+  health::SensorHealth,
+  zigbee2mqtt::{Zigbee2MqttDoorDetector, Zigbee2MqttDoorDetectorConfig},
+  DetectedState, DoorDetector, ReleaseGuard,
+};
+use crate::{door::identifier::Identifier, error::GarageResult, mqtt_client::receiver::MqttSubscriber};
+
+/// A sensor at one end of the door's travel.
+///
+/// These are the same sensors used as single detectors, with their "closed" state meaning the limit is reached.
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum LimitSensorConfig {
+  Gpio(GpioDoorDetectorConfig),
+  Zigbee2Mqtt(Zigbee2MqttDoorDetectorConfig),
+}
+
+#[derive(Debug)]
+pub enum LimitSensor {
+  Gpio(GpioDoorDetector),
+  Zigbee2Mqtt(Zigbee2MqttDoorDetector),
+}
+
+impl DoorDetector for LimitSensor {
+  type Config = LimitSensorConfig;
+
+  async fn new(
+    identifier: Identifier,
+    config: Self::Config,
+    mqtt_subscriber: &MqttSubscriber,
+    state_directory: &Path,
+  ) -> GarageResult<Self> {
+    match config {
+      LimitSensorConfig::Gpio(config) => Ok(LimitSensor::Gpio(
+        GpioDoorDetector::new(identifier, config, mqtt_subscriber, state_directory).await?,
+      )),
+      LimitSensorConfig::Zigbee2Mqtt(config) => Ok(LimitSensor::Zigbee2Mqtt(
+        Zigbee2MqttDoorDetector::new(identifier, config, mqtt_subscriber, state_directory).await?,
+      )),
+    }
+  }
+
+  fn health_receiver(&self) -> Option<watch::Receiver<SensorHealth>> {
+    match self {
+      LimitSensor::Gpio(detector) => detector.health_receiver(),
+      LimitSensor::Zigbee2Mqtt(detector) => detector.health_receiver(),
+    }
+  }
+
+  async fn listen(
+    self,
+    release_guard: ReleaseGuard,
+  ) -> GarageResult<(Option<DetectedState>, UnboundedReceiver<DetectedState>)> {
+    match self {
+      LimitSensor::Gpio(detector) => detector.listen(release_guard).await,
+      LimitSensor::Zigbee2Mqtt(detector) => detector.listen(release_guard).await,
+    }
+  }
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct DualDoorDetectorConfig {
+  /// The sensor which is active when the door is fully closed
+  pub closed_sensor: LimitSensorConfig,
+  /// The sensor which is active when the door is fully open
+  pub open_sensor: LimitSensorConfig,
+}
+
+/// A detector using sensors at both the closed and open limits, so both can be confirmed.
+#[derive(Debug)]
+pub struct DualDoorDetector {
+  identifier: Identifier,
+  closed_sensor: LimitSensor,
+  open_sensor: LimitSensor,
+}
+
+/// Combine the states of both limit sensors in to the door's state, if both are known
+fn combined_state(closed_sensor: Option<DetectedState>, open_sensor: Option<DetectedState>) -> Option<DetectedState> {
+  Some(match (closed_sensor?, open_sensor?) {
+    (DetectedState::SensorFault, _) | (_, DetectedState::SensorFault) => DetectedState::SensorFault,
+    (DetectedState::Stuck, _) | (_, DetectedState::Stuck) => DetectedState::Stuck,
+    // the door can't be at both ends at once
+    (DetectedState::Closed, DetectedState::Closed) => DetectedState::SensorFault,
+    (DetectedState::Closed, _) => DetectedState::Closed,
+    (_, DetectedState::Closed) => DetectedState::FullyOpen,
+    _ => DetectedState::MidTravel,
+  })
+}
+
+impl DoorDetector for DualDoorDetector {
+  type Config = DualDoorDetectorConfig;
+
+  async fn new(
+    identifier: Identifier,
+    config: Self::Config,
+    mqtt_subscriber: &MqttSubscriber,
+    state_directory: &Path,
+  ) -> GarageResult<Self> {
+    Ok(DualDoorDetector {
+      closed_sensor: LimitSensor::new(
+        identifier.clone(),
+        config.closed_sensor,
+        mqtt_subscriber,
+        state_directory,
+      )
+      .await?,
+      open_sensor: LimitSensor::new(identifier.clone(), config.open_sensor, mqtt_subscriber, state_directory).await?,
+      identifier,
+    })
+  }
+
+  fn health_receiver(&self) -> Option<watch::Receiver<SensorHealth>> {
+    match (self.closed_sensor.health_receiver(), self.open_sensor.health_receiver()) {
+      (Some(mut closed_rx), Some(mut open_rx)) => {
+        // report the worst of both sensors
+        let merged_tx = watch::Sender::new(closed_rx.borrow().merge(&open_rx.borrow()));
+        let merged_rx = merged_tx.subscribe();
+        tokio::spawn(async move {
+          loop {
+            select! {
+              Ok(()) = closed_rx.changed() => {},
+              Ok(()) = open_rx.changed() => {},
+              _ = merged_tx.closed() => break,
+              else => break,
+            }
+            let merged = closed_rx.borrow_and_update().merge(&open_rx.borrow_and_update());
+            if merged_tx.send(merged).is_err() {
+              // channel ended
+              break;
+            }
+          }
+        });
+        Some(merged_rx)
+      }
+      (closed_rx, open_rx) => closed_rx.or(open_rx),
+    }
+  }
+
+  async fn listen(
+    self,
+    release_guard: ReleaseGuard,
+  ) -> GarageResult<(Option<DetectedState>, UnboundedReceiver<DetectedState>)> {
+    let (detector_tx, detector_rx) = mpsc::unbounded_channel();
+
+    let (mut closed_state, mut closed_rx) = self.closed_sensor.listen(release_guard.clone()).await?;
+    let (mut open_state, mut open_rx) = self.open_sensor.listen(release_guard).await?;
+    let initial_state = combined_state(closed_state, open_state);
+    log::debug!(
+      "{:?} dual sensor initial state: {:?} (closed sensor: {:?}, open sensor: {:?})",
+      &self.identifier,
+      initial_state,
+      closed_state,
+      open_state
+    );
+
+    tokio::spawn(async move {
+      let mut previous_state = initial_state;
+      loop {
+        select! {
+          Some(state) = closed_rx.recv() => closed_state = Some(state),
+          Some(state) = open_rx.recv() => open_state = Some(state),
+          // dropping the sensors' channels stops them too
+          _ = detector_tx.closed() => break,
+          else => break,
+        }
+
+        let Some(detected_state) = combined_state(closed_state, open_state)
+        else {
+          // still waiting for one of the sensors
+          continue;
+        };
+        if previous_state != Some(detected_state) {
+          previous_state = Some(detected_state);
+          if detector_tx.send(detected_state).is_err() {
+            // channel ended
+            break;
+          }
+        }
+      }
+    });
+
+    Ok((initial_state, detector_rx))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::combined_state;
+  use crate::door::state::DetectedState::{self, *};
+
+  #[test]
+  fn combines_the_limit_sensors() {
+    let cases: [(DetectedState, DetectedState, DetectedState); 6] = [
+      (Closed, Open, Closed),
+      (Open, Closed, FullyOpen),
+      (Open, Open, MidTravel),
+      // the door can't be at both ends at once
+      (Closed, Closed, SensorFault),
+      (SensorFault, Closed, SensorFault),
+      (Open, Stuck, Stuck),
+    ];
+    for (closed_sensor, open_sensor, expected) in cases {
+      assert_eq!(
+        combined_state(Some(closed_sensor), Some(open_sensor)),
+        Some(expected),
+        "closed sensor {:?}, open sensor {:?}",
+        closed_sensor,
+        open_sensor
+      );
+    }
+  }
+
+  #[test]
+  fn unknown_until_both_sensors_report() {
+    assert_eq!(combined_state(None, Some(Open)), None);
+    assert_eq!(combined_state(Some(Closed), None), None);
+    assert_eq!(combined_state(None, None), None);
+  }
+}
@@ -1,132 +1,96 @@
-use std::{thread::sleep, time::Duration};
+use std::path::Path;
 
 #[cfg(feature = "arm")]
-use rppal::gpio::{Gpio, InputPin};
+use rppal::gpio::Gpio;
 use serde::Deserialize;
-use serde_with::{serde_as, DurationSeconds};
+use tokio::{
+  select,
+  sync::mpsc::{self, UnboundedReceiver},
+};
 
-use super::{DetectedState, DoorDetector, Travel};
+use super::{DetectedState, DoorDetector, ReleaseGuard};
 #[cfg(not(feature = "arm"))]
-use crate::mock_gpio::{Gpio, InputPin};
+use crate::mock_gpio::Gpio;
 use crate::{
-  config::gpio::GpioPin,
-  door::{
-    state_controller::{State, TargetState},
-    Identifier,
+  config::gpio::{
+    debounce::{DebounceConfig, DebouncedInput, InputState},
+    GpioPin,
   },
+  door::identifier::Identifier,
   error::GarageResult,
+  mqtt_client::receiver::MqttSubscriber,
 };
 
-#[serde_as]
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Clone, PartialEq)]
 pub struct GpioDoorDetectorConfig {
-  /// The pin of the door detector sensor (if available)
+  /// The pin of the door's reed switch, low when the door is closed
   pub pin: GpioPin,
 
-  #[serde_as(as = "DurationSeconds<u64>")]
-  /// How long the door is expected to take to go to/from open/close.
-  ///
-  /// If it exceeds this it tries again.
-  pub travel_time: Duration,
+  /// How the pin's readings are debounced
+  #[serde(default)]
+  pub debounce: DebounceConfig,
 }
 
 
 #[derive(Debug)]
 pub struct GpioDoorDetector {
-  pin: InputPin,
-  travel_time: Duration,
-  current_travel: Option<Travel>,
+  identifier: Identifier,
+  input: DebouncedInput,
 }
 
-impl GpioDoorDetector {
-  /// Take a single reading of the pin
-  fn pin_state(&self) -> DetectedState {
-    if self.pin.is_high() {
-      DetectedState::Open
-    }
-    else {
-      DetectedState::Closed
-    }
-  }
-
-  /// Take multiple readings until we get stable state
-  fn stable_state(&self) -> DetectedState {
-    const MAX_READS: usize = 50;
-    const MIN_CONSECUTIVE: usize = 10;
-
-    let mut previous_state = None;
-    let mut consecutive = 0;
-    for _ in 0..MAX_READS {
-      if let Some(prev_state) = previous_state {
-        let state = self.pin_state();
-        if state == prev_state {
-          consecutive += 1;
-          if consecutive >= MIN_CONSECUTIVE {
-            return state;
-          }
-        }
-        else {
-          consecutive = 0;
-          previous_state = Some(state);
-        }
-      }
-      else {
-        previous_state = Some(self.pin_state())
-      }
-
-      sleep(Duration::from_millis(20))
+impl From<InputState> for DetectedState {
+  fn from(input_state: InputState) -> Self {
+    match input_state {
+      InputState::High => DetectedState::Open,
+      InputState::Low => DetectedState::Closed,
+      // the reed switch flickering indicates the door is possibly stuck right at the switch
+      InputState::Flapping => DetectedState::Stuck,
     }
-
-    // we didn't get enough consecutive readings, we're possibly stuck
-    DetectedState::Stuck
   }
 }
 
 impl DoorDetector for GpioDoorDetector {
   type Config = GpioDoorDetectorConfig;
 
-  fn new(_: Identifier, config: Self::Config) -> GarageResult<Self> {
+  async fn new(identifier: Identifier, config: Self::Config, _: &MqttSubscriber, _: &Path) -> GarageResult<Self> {
     let gpio = Gpio::new()?;
     let pin = gpio.get(config.pin.bcm_number())?.into_input_pullup();
 
     Ok(GpioDoorDetector {
-      pin,
-      travel_time: config.travel_time,
-      current_travel: None,
+      identifier,
+      input: DebouncedInput::new(pin, config.debounce)?,
     })
   }
 
-  async fn travel(&mut self, target_state: TargetState) -> DetectedState {
-    if self.current_travel.is_some() {
-      panic!("GpioDoorDetector attempted to travel while it was already travelling");
-    }
-    self.current_travel = Some(Travel::new(target_state));
-    tokio::time::sleep(self.travel_time).await;
-    self.detect_state()
-  }
-
-  fn detect_state(&mut self) -> DetectedState {
-    let detected_state: DetectedState = self.stable_state();
-
-    // check if this state indicates the door might be stuck
-    if let Some(current_travel) = self.current_travel.take() {
-      if current_travel.expired_invalid(detected_state, self.travel_time) {
-        return DetectedState::Stuck;
+  async fn listen(
+    self,
+    release_guard: ReleaseGuard,
+  ) -> GarageResult<(Option<DetectedState>, UnboundedReceiver<DetectedState>)> {
+    let (detector_tx, detector_rx) = mpsc::unbounded_channel();
+
+    let (initial_state, mut input_rx, input_handle) = self.input.listen().await;
+    log::debug!("{:?} GPIO sensor initial state: {:?}", &self.identifier, initial_state);
+
+    tokio::spawn(async move {
+      loop {
+        select! {
+          Some(input_state) = input_rx.recv() => {
+            if detector_tx.send(input_state.into()).is_err() {
+              // channel ended
+              break;
+            }
+          }
+          _ = detector_tx.closed() => break,
+          else => break,
+        }
       }
-    }
-
-    detected_state
-  }
 
-  fn should_check_periodically(&self) -> bool {
-    true
-  }
+      // the input releases the pin once nothing is listening to it
+      drop(input_rx);
+      input_handle.await.ok();
+      drop(release_guard);
+    });
 
-  fn manual_travel_state(&self, target_state: TargetState) -> State {
-    match target_state {
-      // the sensor indicates if it's closed, so once no longer closed we assume opening
-      TargetState::Open => State::Opening,
-      TargetState::Closed => State::Closed,
-    }
+    Ok((Some(initial_state.into()), detector_rx))
   }
 }
@@ -0,0 +1,87 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+/// Something wrong with a sensor, which means its readings may not be trustworthy
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum HealthProblem {
+  /// No update has been received from the sensor for too long
+  Stale,
+  /// The sensor's battery is below the configured threshold
+  LowBattery,
+  /// The sensor's last payload couldn't be understood
+  InvalidPayload,
+}
+
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum HealthStatus {
+  Ok,
+  Degraded,
+}
+
+/// The health of a door's sensor(s), published on the door's diagnostics topic
+#[derive(Debug, Serialize, Clone, PartialEq)]
+pub struct SensorHealth {
+  pub status: HealthStatus,
+  pub problems: Vec<HealthProblem>,
+  /// The battery percentage, if reported
+  pub battery: Option<f64>,
+  /// The link quality, if reported
+  pub linkquality: Option<u64>,
+  /// When the sensor was last seen, if reported
+  pub last_seen: Option<DateTime<Utc>>,
+}
+
+impl Default for SensorHealth {
+  fn default() -> Self {
+    SensorHealth {
+      status: HealthStatus::Ok,
+      problems: Vec::new(),
+      battery: None,
+      linkquality: None,
+      last_seen: None,
+    }
+  }
+}
+
+impl SensorHealth {
+  pub fn is_degraded(&self) -> bool {
+    self.status == HealthStatus::Degraded
+  }
+
+  /// Set whether `problem` is occurring, updating the status to match
+  pub fn set_problem(&mut self, problem: HealthProblem, occurring: bool) {
+    self.problems.retain(|existing| *existing != problem);
+    if occurring {
+      self.problems.push(problem);
+    }
+    self.status = if self.problems.is_empty() {
+      HealthStatus::Ok
+    }
+    else {
+      HealthStatus::Degraded
+    };
+  }
+
+  /// Combine the health of two sensors, taking the worst of each
+  pub fn merge(&self, other: &SensorHealth) -> SensorHealth {
+    fn min<T: PartialOrd>(a: Option<T>, b: Option<T>) -> Option<T> {
+      match (a, b) {
+        (Some(a), Some(b)) => Some(if b < a { b } else { a }),
+        (a, b) => a.or(b),
+      }
+    }
+
+    let mut merged = SensorHealth {
+      battery: min(self.battery, other.battery),
+      linkquality: min(self.linkquality, other.linkquality),
+      last_seen: min(self.last_seen, other.last_seen),
+      ..SensorHealth::default()
+    };
+    for problem in self.problems.iter().chain(other.problems.iter()) {
+      merged.set_problem(*problem, true);
+    }
+    merged
+  }
+}
@@ -1,98 +1,364 @@
+use std::{future, path::Path, time::Duration};
+
+use chrono::{DateTime, Utc};
 use serde::Deserialize;
-use tokio::sync::mpsc::{self, UnboundedReceiver};
+use serde_json::Value;
+use serde_with::{serde_as, DurationSeconds};
+use tokio::{
+  select,
+  sync::{
+    mpsc::{self, UnboundedReceiver},
+    watch,
+  },
+  time,
+};
 
-use super::{DetectedState, DoorDetector};
+use super::{
+  health::{HealthProblem, SensorHealth},
+  DetectedState, DoorDetector, ReleaseGuard,
+};
 use crate::{
   door::identifier::Identifier,
-  error::{GarageError, GarageResult},
-  mqtt_client::{receiver::MqttReceiver, MqttPublish},
+  error::GarageResult,
+  mqtt_client::{
+    receiver::{topic_matches, MqttSubscriber},
+    MqttPublish,
+  },
 };
 
+fn default_field() -> String {
+  "contact".to_owned()
+}
+
+fn default_closed_values() -> Vec<Value> {
+  vec![Value::Bool(true)]
+}
+
+fn default_open_values() -> Vec<Value> {
+  vec![Value::Bool(false)]
+}
+
+/// How a sensor's payload maps to the door's state.
+///
+/// The defaults suit a contact sensor, which reports `"contact": true` when closed.
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct PayloadMapping {
+  /// The payload field the state is read from, either a top level key (e.g. `tilt`) or a JSON pointer (e.g.
+  /// `/state/tilt`). `contact` by default
+  #[serde(default = "default_field")]
+  pub field: String,
+
+  /// The values of the field which mean the door is closed, `[true]` by default
+  #[serde(default = "default_closed_values")]
+  pub closed_values: Vec<Value>,
+
+  /// The values of the field which mean the door is open, `[false]` by default.
+  ///
+  /// If empty, any value not in `closed_values` means the door is open.
+  #[serde(default = "default_open_values")]
+  pub open_values: Vec<Value>,
+
+  /// Swap the meaning of open and closed, for sensors with inverted polarity
+  #[serde(default)]
+  pub invert: bool,
+}
+
+impl PayloadMapping {
+  fn field_value<'a>(&self, payload: &'a Value) -> Option<&'a Value> {
+    if self.field.starts_with('/') {
+      payload.pointer(&self.field)
+    }
+    else {
+      payload.get(&self.field)
+    }
+  }
+
+  /// Get the state of the door from a payload, returning `None` if it couldn't be determined
+  fn detected_state(&self, payload: &Value) -> Option<DetectedState> {
+    let Some(value) = self.field_value(payload)
+    else {
+      log::error!("Sensor payload has no field '{}'", &self.field);
+      return None;
+    };
+
+    let any_match = |values: &[Value]| values.iter().any(|expected| values_match(expected, value));
+    let closed = if any_match(&self.closed_values) {
+      true
+    }
+    else if self.open_values.is_empty() || any_match(&self.open_values) {
+      false
+    }
+    else {
+      log::error!("Sensor field '{}' has unexpected value: {}", &self.field, value);
+      return None;
+    };
+
+    if closed != self.invert {
+      Some(DetectedState::Closed)
+    }
+    else {
+      Some(DetectedState::Open)
+    }
+  }
+}
+
+/// Compare values, treating numbers as equal regardless of whether they're integers or floats
+fn values_match(expected: &Value, value: &Value) -> bool {
+  match (expected.as_f64(), value.as_f64()) {
+    (Some(expected), Some(value)) => expected == value,
+    _ => expected == value,
+  }
+}
+
+/// The device fields zigbee2mqtt includes alongside the sensor's readings
 #[derive(Debug, Deserialize)]
+struct DevicePayload {
+  battery: Option<f64>,
+  linkquality: Option<u64>,
+  /// Either milliseconds since the epoch or an ISO 8601 string, depending on zigbee2mqtt's configuration
+  last_seen: Option<Value>,
+}
+
+impl DevicePayload {
+  fn last_seen(&self) -> Option<DateTime<Utc>> {
+    match self.last_seen.as_ref()? {
+      Value::Number(millis) => DateTime::from_timestamp_millis(millis.as_i64()?),
+      Value::String(iso) => DateTime::parse_from_rfc3339(iso)
+        .ok()
+        .map(|last_seen| last_seen.with_timezone(&Utc)),
+      _ => None,
+    }
+  }
+}
+
+#[serde_as]
+#[derive(Debug, Deserialize, Clone, PartialEq)]
 pub struct Zigbee2MqttDoorDetectorConfig {
   pub sensor_topic: String,
+
+  /// How the sensor's payload is mapped to the door's state
+  #[serde(flatten)]
+  pub payload: PayloadMapping,
+
+  #[serde_as(as = "Option<DurationSeconds<u64>>")]
+  #[serde(default)]
+  /// If no update is received from the sensor for this long it's considered stale.
+  ///
+  /// Most sensors report at least every hour or so, even if nothing changes.
+  pub stale_timeout: Option<Duration>,
+
+  #[serde(default)]
+  /// The battery percentage below which the sensor is considered degraded
+  pub low_battery: Option<f64>,
 }
 
 #[derive(Debug)]
 pub struct Zigbee2MqttDoorDetector {
   sensor_topic: String,
+  payload_mapping: PayloadMapping,
+  stale_timeout: Option<Duration>,
+  low_battery: Option<f64>,
   mqtt_rx: UnboundedReceiver<MqttPublish>,
+  health_tx: watch::Sender<SensorHealth>,
 }
 
-impl DetectedState {
-  fn from_publish(sensor_topic: &str, publish: MqttPublish) -> Option<DetectedState> {
-    if sensor_topic == &publish.topic {
-      Some(
-        serde_json::from_str::<ContactSensorPayload>(&publish.payload)
-          .map(|payload| {
-            log::debug!("Received sensor payload: {payload:?}");
-            if payload.contact {
-              DetectedState::Closed
-            }
-            else {
-              DetectedState::Open
-            }
-          })
-          .unwrap_or_else(|e| {
-            log::error!("Failed to parse sensor payload: {}", e);
-            DetectedState::Stuck
-          }),
-      )
+impl Zigbee2MqttDoorDetector {
+  /// Read the door's state from a publish, updating the sensor's health.
+  ///
+  /// Returns `None` if the publish isn't from the sensor.
+  fn read_publish(&self, publish: MqttPublish) -> Option<DetectedState> {
+    if !topic_matches(&self.sensor_topic, &publish.topic) {
+      return None;
     }
-    else {
-      None
+
+    let payload = serde_json::from_str::<Value>(&publish.payload)
+      .map_err(|err| log::error!("Failed to parse sensor payload: {}", err))
+      .ok();
+    if let Some(payload) = &payload {
+      log::debug!("Received sensor payload: {payload}");
     }
+
+    let detected_state = payload
+      .as_ref()
+      .and_then(|payload| self.payload_mapping.detected_state(payload));
+    let device = payload.and_then(|payload| DevicePayload::deserialize(payload).ok());
+
+    self.health_tx.send_if_modified(|health| {
+      let previous_health = health.clone();
+      health.set_problem(HealthProblem::InvalidPayload, detected_state.is_none());
+      // a fresh message means the sensor is no longer stale, unless it's reporting an old reading
+      health.set_problem(HealthProblem::Stale, false);
+
+      if let Some(device) = device {
+        health.battery = device.battery.or(health.battery);
+        health.linkquality = device.linkquality.or(health.linkquality);
+        health.last_seen = device.last_seen().or(health.last_seen);
+      }
+      if let (Some(low_battery), Some(battery)) = (self.low_battery, health.battery) {
+        health.set_problem(HealthProblem::LowBattery, battery < low_battery);
+      }
+      if let (Some(stale_timeout), Some(last_seen)) = (self.stale_timeout, health.last_seen) {
+        let since_seen = (Utc::now() - last_seen).to_std().unwrap_or_default();
+        health.set_problem(HealthProblem::Stale, since_seen > stale_timeout);
+      }
+
+      *health != previous_health
+    });
+
+    // an unreadable payload says nothing about whether the door is stuck, just that the sensor is faulty
+    Some(detected_state.unwrap_or(DetectedState::SensorFault))
+  }
+
+  fn set_stale(&self) {
+    self.health_tx.send_if_modified(|health| {
+      let was_stale = health.problems.contains(&HealthProblem::Stale);
+      health.set_problem(HealthProblem::Stale, true);
+      !was_stale
+    });
   }
 }
 
 impl DoorDetector for Zigbee2MqttDoorDetector {
   type Config = Zigbee2MqttDoorDetectorConfig;
 
-  async fn new(_: Identifier, config: Self::Config, mqtt_receiver: &mut MqttReceiver) -> GarageResult<Self> {
+  async fn new(_: Identifier, config: Self::Config, mqtt_subscriber: &MqttSubscriber, _: &Path) -> GarageResult<Self> {
     Ok(Zigbee2MqttDoorDetector {
-      mqtt_rx: mqtt_receiver
+      mqtt_rx: mqtt_subscriber
         .subscribe(config.sensor_topic.clone(), rumqttc::QoS::AtLeastOnce)
         .await?,
       sensor_topic: config.sensor_topic,
+      payload_mapping: config.payload,
+      stale_timeout: config.stale_timeout,
+      low_battery: config.low_battery,
+      health_tx: watch::Sender::new(SensorHealth::default()),
     })
   }
 
-  async fn listen(mut self) -> GarageResult<(DetectedState, mpsc::UnboundedReceiver<DetectedState>)> {
+  fn health_receiver(&self) -> Option<watch::Receiver<SensorHealth>> {
+    Some(self.health_tx.subscribe())
+  }
+
+  async fn listen(
+    mut self,
+    release_guard: ReleaseGuard,
+  ) -> GarageResult<(Option<DetectedState>, mpsc::UnboundedReceiver<DetectedState>)> {
     log::debug!("Subscribing zigbee2mqtt sensor to topic '{}'", &self.sensor_topic);
 
     let (detector_tx, detector_rx) = mpsc::unbounded_channel();
 
-    // read the initial state (this assumes it'll be retain and thus instantly available)
-    let initial_state = loop {
-      let publish = self.mqtt_rx.recv().await.ok_or(GarageError::MqttClosed)?;
-      if let Some(initial_state) = DetectedState::from_publish(&self.sensor_topic, publish) {
-        break initial_state;
-      }
-    };
-
     tokio::spawn(async move {
+      let _release_guard = release_guard;
+      let mut stale_expiry = self.stale_timeout.map(|timeout| Box::pin(time::sleep(timeout)));
       loop {
-        if let Some(publish) = self.mqtt_rx.recv().await {
-          if let Some(detected_state) = DetectedState::from_publish(&self.sensor_topic, publish) {
-            if detector_tx.send(detected_state).is_err() {
+        select! {
+          publish = self.mqtt_rx.recv() => {
+            let Some(publish) = publish else {
               // channel ended
               break;
+            };
+            if let Some(detected_state) = self.read_publish(publish) {
+              stale_expiry = self.stale_timeout.map(|timeout| Box::pin(time::sleep(timeout)));
+              if detector_tx.send(detected_state).is_err() {
+                // channel ended
+                break;
+              }
             }
           }
-        }
-        else {
-          // channel ended
-          break;
+
+          _ = async {
+            match &mut stale_expiry {
+              Some(expiry) => expiry.await,
+              None => future::pending().await,
+            }
+          } => {
+            log::warn!("zigbee2mqtt sensor '{}' hasn't been seen for too long, marking as stale", &self.sensor_topic);
+            stale_expiry = None;
+            self.set_stale();
+          }
+
+          // stop listening (and receiving publishes) once the door is no longer listening to us
+          _ = detector_tx.closed() => break,
         }
       }
     });
 
-    Ok((initial_state, detector_rx))
+    // the initial state comes from the sensor's retained message, which may not have arrived yet (or ever)
+    Ok((None, detector_rx))
   }
 }
 
-#[derive(Debug, Deserialize)]
-struct ContactSensorPayload {
-  /// `true` if closed
-  contact: bool,
+#[cfg(test)]
+mod tests {
+  use serde_json::json;
+
+  use super::PayloadMapping;
+  use crate::door::state::DetectedState;
+
+  fn mapping(toml: &str) -> PayloadMapping {
+    toml::from_str(toml).unwrap()
+  }
+
+  #[test]
+  fn contact_sensor_by_default() {
+    let mapping = mapping("");
+    assert_eq!(
+      mapping.detected_state(&json!({"contact": true})),
+      Some(DetectedState::Closed)
+    );
+    assert_eq!(
+      mapping.detected_state(&json!({"contact": false})),
+      Some(DetectedState::Open)
+    );
+    assert_eq!(mapping.detected_state(&json!({"battery": 100})), None);
+    assert_eq!(mapping.detected_state(&json!({"contact": "yes"})), None);
+  }
+
+  #[test]
+  fn field_can_be_a_json_pointer() {
+    let mapping = mapping(
+      r#"
+      field = "/state/tilt"
+      closed_values = ["flat"]
+      open_values = ["upright"]
+      "#,
+    );
+    assert_eq!(
+      mapping.detected_state(&json!({"state": {"tilt": "flat"}})),
+      Some(DetectedState::Closed)
+    );
+    assert_eq!(
+      mapping.detected_state(&json!({"state": {"tilt": "upright"}})),
+      Some(DetectedState::Open)
+    );
+    assert_eq!(mapping.detected_state(&json!({"tilt": "flat"})), None);
+  }
+
+  #[test]
+  fn numbers_match_regardless_of_type() {
+    let mapping = mapping("field = \"angle\"\nclosed_values = [0]\nopen_values = [90.0]");
+    assert_eq!(
+      mapping.detected_state(&json!({"angle": 0.0})),
+      Some(DetectedState::Closed)
+    );
+    assert_eq!(mapping.detected_state(&json!({"angle": 90})), Some(DetectedState::Open));
+  }
+
+  #[test]
+  fn empty_open_values_match_anything_else() {
+    let mapping = mapping("field = \"angle\"\nclosed_values = [0]\nopen_values = []");
+    assert_eq!(mapping.detected_state(&json!({"angle": 45})), Some(DetectedState::Open));
+  }
+
+  #[test]
+  fn invert_swaps_open_and_closed() {
+    let mapping = mapping("invert = true");
+    assert_eq!(
+      mapping.detected_state(&json!({"contact": true})),
+      Some(DetectedState::Open)
+    );
+    assert_eq!(
+      mapping.detected_state(&json!({"contact": false})),
+      Some(DetectedState::Closed)
+    );
+  }
 }
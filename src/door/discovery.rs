@@ -0,0 +1,329 @@
+//! Home Assistant MQTT discovery, so each door appears in Home Assistant without any manual configuration.
+//!
+//! See: https://www.home-assistant.io/integrations/mqtt/#mqtt-discovery
+
+use std::{
+  collections::HashSet,
+  fs,
+  path::{Path, PathBuf},
+};
+
+use rumqttc::QoS;
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use super::{controller::config::DoorControllerConfig, identifier::Identifier};
+use crate::{
+  error::{GarageError, GarageResult},
+  mqtt_client::{sender::PublishSender, Availability, MqttPublish},
+};
+
+/// The file in the state directory the identifiers of announced doors are saved in, so their entities can be removed
+/// once they're decommissioned
+const ANNOUNCED_DOORS_FILE: &str = "discovered-doors.json";
+
+fn default_prefix() -> String {
+  "homeassistant".to_owned()
+}
+
+fn default_node_id() -> String {
+  "mqtt-garage".to_owned()
+}
+
+fn default_device_name() -> String {
+  "Garage".to_owned()
+}
+
+/// The device every door's entities belong to
+#[derive(Debug, Deserialize, Clone)]
+pub struct DeviceConfig {
+  /// The name of the device, `Garage` by default
+  #[serde(default = "default_device_name")]
+  pub name: String,
+  pub manufacturer: Option<String>,
+  pub model: Option<String>,
+  /// The area the device is suggested to be in when first discovered
+  pub suggested_area: Option<String>,
+}
+
+impl Default for DeviceConfig {
+  fn default() -> Self {
+    DeviceConfig {
+      name: default_device_name(),
+      manufacturer: None,
+      model: None,
+      suggested_area: None,
+    }
+  }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct DiscoveryConfig {
+  /// The topic prefix Home Assistant listens for discovery configs on, `homeassistant` by default
+  #[serde(default = "default_prefix")]
+  pub prefix: String,
+  /// Uniquely identifies this service in discovery topics and entity IDs, `mqtt-garage` by default
+  #[serde(default = "default_node_id")]
+  pub node_id: String,
+  #[serde(default)]
+  pub device: DeviceConfig,
+}
+
+/// The kinds of entity announced for each door, with the suffix of their object ID
+const ENTITIES: [(&str, &str); 6] = [
+  ("cover", ""),
+  ("binary_sensor", "_stuck"),
+  ("binary_sensor", "_sensor_problem"),
+  ("sensor", "_battery"),
+  ("sensor", "_linkquality"),
+  ("sensor", "_last_seen"),
+];
+
+#[derive(Debug)]
+pub struct Discovery {
+  config: DiscoveryConfig,
+  availability_topic: String,
+  availability: Availability,
+  mqtt_tx: PublishSender,
+  /// Where the identifiers of announced doors are saved
+  announced_doors_path: PathBuf,
+}
+
+impl Discovery {
+  pub fn new(
+    config: DiscoveryConfig,
+    availability_topic: String,
+    availability: Availability,
+    mqtt_tx: PublishSender,
+    state_directory: &Path,
+  ) -> Self {
+    Discovery {
+      config,
+      availability_topic,
+      availability,
+      mqtt_tx,
+      announced_doors_path: state_directory.join(ANNOUNCED_DOORS_FILE),
+    }
+  }
+
+  fn config_topic(&self, component: &str, identifier: &Identifier, suffix: &str) -> String {
+    format!(
+      "{}/{}/{}/{}{}/config",
+      self.config.prefix, component, self.config.node_id, identifier.0, suffix
+    )
+  }
+
+  fn device(&self) -> Value {
+    let device = &self.config.device;
+    let mut block = json!({
+      "identifiers": [self.config.node_id],
+      "name": device.name,
+    });
+    // Home Assistant rejects nulls, so only include the fields which are set
+    for (key, value) in [
+      ("manufacturer", &device.manufacturer),
+      ("model", &device.model),
+      ("suggested_area", &device.suggested_area),
+    ] {
+      if let Some(value) = value {
+        block[key] = json!(value);
+      }
+    }
+    block
+  }
+
+  fn availability(&self, config: &DoorControllerConfig) -> Value {
+    let mut availability = vec![json!({
+      "topic": self.availability_topic,
+      "payload_available": self.availability.online,
+      "payload_not_available": self.availability.offline,
+    })];
+    if let Some(availability_topic) = &config.availability_topic {
+      availability.push(json!({
+        "topic": availability_topic,
+        "payload_available": self.availability.online,
+        "payload_not_available": self.availability.offline,
+      }));
+    }
+    Value::Array(availability)
+  }
+
+  /// The configs of each of a door's entities, `None` if the door doesn't have that entity
+  fn entity_configs(&self, identifier: &Identifier, config: &DoorControllerConfig) -> [Option<Value>; 6] {
+    let unique_id = |suffix: &str| format!("{}_{}{}", self.config.node_id, identifier.0, suffix);
+    let common = |suffix: &str| {
+      json!({
+        "unique_id": unique_id(suffix),
+        "object_id": unique_id(suffix),
+        "device": self.device(),
+        "availability": self.availability(config),
+        "availability_mode": "all",
+      })
+    };
+    let diagnostic = |suffix: &str, name: &str, entity: Value| {
+      config.diagnostics_topic.as_ref().map(|diagnostics_topic| {
+        merge(
+          common(suffix),
+          merge(
+            json!({
+              "name": format!("{} {}", identifier.0, name),
+              "state_topic": diagnostics_topic,
+              "entity_category": "diagnostic",
+            }),
+            entity,
+          ),
+        )
+      })
+    };
+
+    let mut cover = json!({
+      "name": identifier.0,
+      "device_class": "garage",
+      "command_topic": config.command_topic,
+      "state_topic": config.state_topic,
+      "payload_open": "OPEN",
+      "payload_close": "CLOSED",
+      // Home Assistant offers a stop command unless the payload is null
+      "payload_stop": config.remote.can_stop().then_some("STOP"),
+      "state_open": "open",
+      "state_opening": "opening",
+      "state_closed": "closed",
+      "state_closing": "closing",
+      "state_stopped": "stopped",
+    });
+    // Home Assistant rejects nulls, so only include the position topics if they're set
+    for (key, topic) in [
+      ("position_topic", &config.position_topic),
+      ("set_position_topic", &config.set_position_topic),
+    ] {
+      if let Some(topic) = topic {
+        cover[key] = json!(topic);
+      }
+    }
+
+    [
+      Some(merge(common(""), cover)),
+      config.stuck_topic.as_ref().map(|stuck_topic| {
+        merge(
+          common("_stuck"),
+          json!({
+            "name": format!("{} stuck", identifier.0),
+            "device_class": "problem",
+            "state_topic": stuck_topic,
+            "payload_on": "stuck",
+            "payload_off": "ok",
+          }),
+        )
+      }),
+      diagnostic(
+        "_sensor_problem",
+        "sensor problem",
+        json!({
+          "device_class": "problem",
+          "value_template": "{{ value_json.status }}",
+          "payload_on": "degraded",
+          "payload_off": "ok",
+        }),
+      ),
+      diagnostic(
+        "_battery",
+        "sensor battery",
+        json!({
+          "device_class": "battery",
+          "unit_of_measurement": "%",
+          "state_class": "measurement",
+          "value_template": "{{ value_json.battery }}",
+        }),
+      ),
+      diagnostic(
+        "_linkquality",
+        "sensor link quality",
+        json!({
+          "unit_of_measurement": "lqi",
+          "state_class": "measurement",
+          "icon": "mdi:signal",
+          "value_template": "{{ value_json.linkquality }}",
+        }),
+      ),
+      diagnostic(
+        "_last_seen",
+        "sensor last seen",
+        json!({
+          "device_class": "timestamp",
+          "value_template": "{{ value_json.last_seen }}",
+        }),
+      ),
+    ]
+  }
+
+  fn publish(&self, topic: String, payload: String) -> GarageResult<()> {
+    self
+      .mqtt_tx
+      .send(MqttPublish {
+        topic,
+        qos: QoS::AtLeastOnce,
+        retain: true,
+        payload,
+      })
+      .map_err(|_| GarageError::MqttClosed)
+  }
+
+  /// Announce a door's entities, removing any it no longer has
+  pub fn announce_door(&self, identifier: &Identifier, config: &DoorControllerConfig) -> GarageResult<()> {
+    for ((component, suffix), entity_config) in ENTITIES.iter().zip(self.entity_configs(identifier, config)) {
+      let topic = self.config_topic(component, identifier, suffix);
+      // an empty payload removes the entity
+      let payload = entity_config.map(|config| config.to_string()).unwrap_or_default();
+      self.publish(topic, payload)?;
+    }
+
+    Ok(())
+  }
+
+  /// Remove all of a door's entities
+  pub fn remove_door(&self, identifier: &Identifier) -> GarageResult<()> {
+    log::info!("Removing discovery configs of decommissioned door {:?}", identifier);
+    for (component, suffix) in ENTITIES {
+      self.publish(self.config_topic(component, identifier, suffix), String::new())?;
+    }
+
+    Ok(())
+  }
+
+  /// Announce every door, removing the entities of any previously announced doors which no longer exist
+  pub fn announce<'a>(
+    &self,
+    doors: impl IntoIterator<Item = (Identifier, &'a DoorControllerConfig)>,
+  ) -> GarageResult<()> {
+    let mut announced = HashSet::new();
+    for (identifier, config) in doors {
+      self.announce_door(&identifier, config)?;
+      announced.insert(identifier.0);
+    }
+
+    let previously_announced: HashSet<String> = fs::read_to_string(&self.announced_doors_path)
+      .ok()
+      .and_then(|announced| serde_json::from_str(&announced).ok())
+      .unwrap_or_default();
+    for decommissioned in previously_announced.difference(&announced) {
+      self.remove_door(&decommissioned.clone().into())?;
+    }
+
+    if let Err(err) = fs::write(
+      &self.announced_doors_path,
+      serde_json::to_string(&announced).expect("identifiers are always serializable"),
+    ) {
+      log::warn!("failed to write announced doors: {}", err);
+    }
+
+    Ok(())
+  }
+}
+
+/// Merge the fields of `extra` in to the JSON object `base`
+fn merge(mut base: Value, extra: Value) -> Value {
+  if let (Value::Object(base), Value::Object(extra)) = (&mut base, extra) {
+    base.extend(extra);
+  }
+  base
+}
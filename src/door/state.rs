@@ -1,10 +1,11 @@
 use std::{fmt, pin::Pin, str::FromStr, time::Duration};
 
-use serde::Deserialize;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
 use tokio::time::{self, Sleep};
 
 /// The state the door is trying to get to
-#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
 pub enum TargetState {
   #[serde(rename = "OPEN")]
   Open,
@@ -33,12 +34,49 @@ impl fmt::Display for TargetState {
   }
 }
 
+/// A command received on a door's command topic
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DoorCommand {
+  /// Move the door to a state
+  Move(TargetState),
+  /// Halt the door where it is, if it's travelling
+  Stop,
+  /// Suspend closing the door automatically until it's next closed
+  HoldOpen,
+  /// Open and close the door to measure how long it takes, see [`crate::door::controller::calibration`]
+  Calibrate,
+}
+
+impl FromStr for DoorCommand {
+  type Err = ();
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "STOP" => Ok(DoorCommand::Stop),
+      "HOLD_OPEN" => Ok(DoorCommand::HoldOpen),
+      "CALIBRATE" => Ok(DoorCommand::Calibrate),
+      s => TargetState::from_str(s).map(DoorCommand::Move),
+    }
+  }
+}
+
+impl fmt::Display for DoorCommand {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      DoorCommand::Move(target_state) => target_state.fmt(f),
+      DoorCommand::Stop => write!(f, "STOP"),
+      DoorCommand::HoldOpen => write!(f, "HOLD_OPEN"),
+      DoorCommand::Calibrate => write!(f, "CALIBRATE"),
+    }
+  }
+}
+
 impl PartialEq<TargetState> for State {
   fn eq(&self, other: &TargetState) -> bool {
-    match (self, other) {
-      (State::Open, TargetState::Open) | (State::Closed, TargetState::Closed) => true,
-      _ => false,
-    }
+    matches!(
+      (self, other),
+      (State::Open, TargetState::Open) | (State::Closed, TargetState::Closed)
+    )
   }
 }
 
@@ -57,6 +95,13 @@ impl fmt::Display for Stuck {
   }
 }
 
+/// How much of a travel's duration is left, if it started at `started`
+fn remaining_duration(started: DateTime<Utc>, duration: Duration) -> Duration {
+  // a start time in the future (i.e. the clock changed) counts as having just started
+  let elapsed = (Utc::now() - started).to_std().unwrap_or_default();
+  duration.saturating_sub(elapsed)
+}
+
 /// Represents a door travel where we can confirm the door has reached the target state.
 #[derive(Debug)]
 pub struct ConfirmedTravel {
@@ -64,6 +109,8 @@ pub struct ConfirmedTravel {
   /// The number of times this travel has been attempted, starting at 0
   attempt: u8,
   duration: Duration,
+  /// When the current attempt started
+  started: DateTime<Utc>,
 }
 
 impl ConfirmedTravel {
@@ -72,24 +119,48 @@ impl ConfirmedTravel {
       expiry: Box::pin(time::sleep(duration)),
       duration,
       attempt: 0,
+      started: Utc::now(),
+    }
+  }
+
+  /// Continue a travel which started at `started`, e.g. before a restart
+  pub fn resume(duration: Duration, started: DateTime<Utc>, attempt: u8) -> Self {
+    ConfirmedTravel {
+      expiry: Box::pin(time::sleep(remaining_duration(started, duration))),
+      duration,
+      attempt,
+      started,
     }
   }
 
+  pub fn attempt(&self) -> u8 {
+    self.attempt
+  }
+
+  pub fn duration(&self) -> Duration {
+    self.duration
+  }
+
+  pub fn started(&self) -> DateTime<Utc> {
+    self.started
+  }
+
   pub fn expiry_mut(&mut self) -> &mut Pin<Box<Sleep>> {
     &mut self.expiry
   }
 
   /// Renew the expiry on this travel an increment the attempt counter.
   ///
-  /// Returns `Err(())` if greater than the maximum number of attempts.
-  pub fn reattempt(&mut self, max_attempts: u8) -> Result<(), ()> {
+  /// Returns `false` if greater than the maximum number of attempts.
+  pub fn reattempt(&mut self, max_attempts: u8) -> bool {
     if self.attempt >= max_attempts {
-      Err(())
+      false
     }
     else {
       self.expiry = Box::pin(time::sleep(self.duration));
       self.attempt += 1;
-      Ok(())
+      self.started = Utc::now();
+      true
     }
   }
 }
@@ -98,39 +169,69 @@ impl ConfirmedTravel {
 #[derive(Debug)]
 pub struct AssumedTravel {
   pub(crate) expiry: Pin<Box<Sleep>>,
+  duration: Duration,
+  started: DateTime<Utc>,
 }
 
 impl AssumedTravel {
   pub fn new(duration: Duration) -> Self {
     AssumedTravel {
       expiry: Box::pin(time::sleep(duration)),
+      duration,
+      started: Utc::now(),
+    }
+  }
+
+  /// Continue a travel which started at `started`, e.g. before a restart
+  pub fn resume(duration: Duration, started: DateTime<Utc>) -> Self {
+    AssumedTravel {
+      expiry: Box::pin(time::sleep(remaining_duration(started, duration))),
+      duration,
+      started,
     }
   }
 
+  pub fn duration(&self) -> Duration {
+    self.duration
+  }
+
+  pub fn started(&self) -> DateTime<Utc> {
+    self.started
+  }
+
   pub fn expiry_mut(&mut self) -> &mut Pin<Box<Sleep>> {
     &mut self.expiry
   }
 }
 
 pub enum State {
+  /// The detector hasn't reported the door's state yet
+  Unknown,
   AttemptingOpen(ConfirmedTravel),
   /// We have to assume when the door finished opening
   Opening(AssumedTravel),
+  /// We can confirm when the door finishes opening (the door has an open limit sensor)
+  ConfirmedOpening(ConfirmedTravel),
   Open,
   StuckOpen,
   /// We can confirm when the door closes
   Closing(ConfirmedTravel),
   Closed,
   StuckClosed,
+  /// The door was stopped mid-travel, so it's partially open
+  Stopped,
 }
 
 impl fmt::Display for State {
   fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
     match self {
-      State::AttemptingOpen(_) | State::Opening(_) => write!(f, "opening"),
+      // Home Assistant treats a "None" payload as an unknown state
+      State::Unknown => write!(f, "None"),
+      State::AttemptingOpen(_) | State::Opening(_) | State::ConfirmedOpening(_) => write!(f, "opening"),
       State::Open | State::StuckOpen => write!(f, "open"),
       State::Closing(_) => write!(f, "closing"),
       State::Closed | State::StuckClosed => write!(f, "closed"),
+      State::Stopped => write!(f, "stopped"),
     }
   }
 }
@@ -138,13 +239,16 @@ impl fmt::Display for State {
 impl fmt::Debug for State {
   fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
     match self {
+      State::Unknown => write!(f, "Unknown"),
       State::AttemptingOpen(_) => write!(f, "AttemptingOpen"),
       State::Opening(_) => write!(f, "Opening"),
+      State::ConfirmedOpening(_) => write!(f, "ConfirmedOpening"),
       State::Open => write!(f, "Open"),
       State::StuckOpen => write!(f, "StuckOpen"),
       State::Closing(_) => write!(f, "Closing"),
       State::Closed => write!(f, "Closed"),
       State::StuckClosed => write!(f, "StuckClosed"),
+      State::Stopped => write!(f, "Stopped"),
     }
   }
 }
@@ -152,13 +256,20 @@ impl fmt::Debug for State {
 impl From<DetectedState> for State {
   fn from(target_state: DetectedState) -> Self {
     match target_state {
-      DetectedState::Open => State::Open,
+      DetectedState::Open | DetectedState::FullyOpen | DetectedState::MidTravel => State::Open,
       DetectedState::Closed => State::Closed,
-      DetectedState::Stuck => State::Open,
+      // neither says where the door is, so it stays unknown rather than risk moving it
+      DetectedState::Stuck | DetectedState::SensorFault => State::Unknown,
     }
   }
 }
 
+impl From<Option<DetectedState>> for State {
+  fn from(detected_state: Option<DetectedState>) -> Self {
+    detected_state.map_or(State::Unknown, State::from)
+  }
+}
+
 impl From<TargetState> for State {
   fn from(target_state: TargetState) -> Self {
     match target_state {
@@ -171,7 +282,7 @@ impl From<TargetState> for State {
 impl State {
   pub fn confirmed_travel_mut(&mut self) -> Option<&mut ConfirmedTravel> {
     match self {
-      State::AttemptingOpen(travel) | State::Closing(travel) => Some(travel),
+      State::AttemptingOpen(travel) | State::ConfirmedOpening(travel) | State::Closing(travel) => Some(travel),
       _ => None,
     }
   }
@@ -186,17 +297,24 @@ impl State {
   pub fn expiry_mut(&mut self) -> Option<&mut Pin<Box<Sleep>>> {
     match self {
       State::Opening(travel) => Some(travel.expiry_mut()),
-      State::AttemptingOpen(travel) | State::Closing(travel) => Some(travel.expiry_mut()),
+      State::AttemptingOpen(travel) | State::ConfirmedOpening(travel) | State::Closing(travel) => {
+        Some(travel.expiry_mut())
+      }
       _ => None,
     }
   }
 
   /// True if the state if opening or closing (i.e. in transition)
   pub fn is_travelling(&self) -> bool {
-    match self {
-      State::Opening(..) | State::AttemptingOpen(..) | State::Closing(..) => true,
-      _ => false,
-    }
+    matches!(
+      self,
+      State::Opening(..) | State::AttemptingOpen(..) | State::ConfirmedOpening(..) | State::Closing(..)
+    )
+  }
+
+  /// True if the detector hasn't yet reported the door's state
+  pub fn is_unknown(&self) -> bool {
+    matches!(self, State::Unknown)
   }
 
   pub fn stuck_state(&self) -> Stuck {
@@ -212,9 +330,16 @@ impl State {
 /// It can also determine if the door is likely stuck.
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub enum DetectedState {
+  /// The door is not closed, but it may not be fully open
   Open,
+  /// The door is at its open limit (requires an open limit sensor)
+  FullyOpen,
+  /// The door is between its open and closed limits (requires an open limit sensor)
+  MidTravel,
   Closed,
   Stuck,
+  /// The sensors are reporting something impossible, so the door's state is unknown
+  SensorFault,
 }
 
 impl From<TargetState> for DetectedState {
@@ -0,0 +1,383 @@
+use std::{collections::HashMap, future, path::PathBuf, time::Duration};
+
+use rumqttc::QoS;
+use tokio::{
+  sync::mpsc,
+  task::JoinHandle,
+  time::{self, Instant},
+};
+
+use super::{
+  config::DoorConfig,
+  controller::ControllerCommand,
+  detector::{self, AnyDoorDetector, Released},
+  Door, DoorContext,
+};
+use crate::{
+  error::{GarageError, GarageResult},
+  mqtt_client::{receiver::MqttSubscriber, sender::PublishSender, MqttPublish},
+};
+
+/// How long doors are given to stop and release their pins, before they're aborted
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long to wait before restarting a door after it first fails
+const MIN_RESTART_DELAY: Duration = Duration::from_secs(5);
+/// The longest to wait before restarting a door, the delay doubles each time it fails again up to this
+const MAX_RESTART_DELAY: Duration = Duration::from_secs(300);
+
+#[derive(Debug)]
+struct RunningDoor {
+  /// The configuration the door was started with (and any timings applied since)
+  config: DoorConfig<AnyDoorDetector>,
+  control_tx: mpsc::UnboundedSender<ControllerCommand>,
+  handle: JoinHandle<()>,
+  /// Completes once the door's detector has released its pins and topics
+  released: Released,
+  /// Which start of the door this is, see [`DoorFailure`]
+  generation: u64,
+  started: Instant,
+  /// Whether the door's controller has run before, so the door shouldn't move to its initial target state again
+  has_run: bool,
+  /// How long to wait before restarting the door if it fails
+  restart_delay: Duration,
+}
+
+/// A door which failed, waiting to be restarted
+#[derive(Debug)]
+struct FailedDoor {
+  config: DoorConfig<AnyDoorDetector>,
+  has_run: bool,
+  restart_at: Instant,
+  /// How long to wait before restarting the door if it fails again
+  restart_delay: Duration,
+}
+
+/// An error which ended a door, see [`DoorSupervisor::failed`]
+#[derive(Debug)]
+pub struct DoorFailure {
+  identifier: String,
+  /// Which start of the door failed, so an error from a door which has since been stopped or restarted is ignored
+  generation: u64,
+  /// Whether the door's controller had started, rather than the door failing to initialise its detector
+  controller_started: bool,
+  err: GarageError,
+}
+
+impl RunningDoor {
+  /// Wait for the door to stop once it has been told to, and for its detector to release its pins and topics.
+  ///
+  /// A door which is still busy (e.g. waiting on its detector) at the deadline is aborted, which also releases the
+  /// remote.
+  async fn stopped(mut self, identifier: &str, deadline: Instant) {
+    if time::timeout_at(deadline, &mut self.handle).await.is_err() {
+      log::warn!("Door {} didn't stop in time, aborting it", identifier);
+      self.handle.abort();
+      // wait for the door to be dropped
+      self.handle.await.ok();
+    }
+    if time::timeout_at(deadline, self.released.wait()).await.is_err() {
+      log::warn!("Door {}'s detector didn't release its pins in time", identifier);
+    }
+  }
+}
+
+/// Starts and stops doors, so they can be added, removed and reconfigured while the service is running
+#[derive(Debug)]
+pub struct DoorSupervisor {
+  context: DoorContext,
+  mqtt_tx: PublishSender,
+  mqtt_subscriber: MqttSubscriber,
+  doors: HashMap<String, RunningDoor>,
+  failed: HashMap<String, FailedDoor>,
+  /// The generation of the next door to start
+  next_generation: u64,
+  /// Errors from any of the doors, each of which is restarted on its own
+  failures_tx: mpsc::UnboundedSender<DoorFailure>,
+}
+
+impl DoorSupervisor {
+  pub fn new(
+    context: DoorContext,
+    mqtt_tx: PublishSender,
+    mqtt_subscriber: MqttSubscriber,
+  ) -> (Self, mpsc::UnboundedReceiver<DoorFailure>) {
+    let (failures_tx, failures_rx) = mpsc::unbounded_channel();
+    (
+      DoorSupervisor {
+        context,
+        mqtt_tx,
+        mqtt_subscriber,
+        doors: HashMap::new(),
+        failed: HashMap::new(),
+        next_generation: 0,
+        failures_tx,
+      },
+      failures_rx,
+    )
+  }
+
+  /// Create a door, then start it in the background as its detector may take a while to report the door's state.
+  ///
+  /// A door which fails to start, or fails once it has, is restarted on its own after a delay, see
+  /// [`DoorSupervisor::failed`]. The MQTT receiver must already be running.
+  pub async fn start(&mut self, identifier: String, config: DoorConfig<AnyDoorDetector>) {
+    self.launch(identifier, config, false, MIN_RESTART_DELAY).await;
+  }
+
+  async fn launch(
+    &mut self,
+    identifier: String,
+    config: DoorConfig<AnyDoorDetector>,
+    has_run: bool,
+    restart_delay: Duration,
+  ) {
+    let mut door_config = config.clone();
+    if has_run {
+      door_config.controller.initial_target_state = None;
+    }
+    let door = match Door::new(
+      identifier.clone().into(),
+      door_config,
+      self.mqtt_tx.clone(),
+      self.context.clone(),
+      &self.mqtt_subscriber,
+    )
+    .await
+    {
+      Ok(door) => door,
+      Err(err) => {
+        self.restart_later(identifier, config, has_run, restart_delay, err);
+        return;
+      }
+    };
+    let control_tx = door.controller_sender();
+    let (release_guard, released) = detector::release_guard();
+
+    let generation = self.next_generation;
+    self.next_generation += 1;
+    let failures_tx = self.failures_tx.clone();
+    let failed_identifier = identifier.clone();
+    let handle = tokio::spawn(async move {
+      let (controller_started, result) = match door.start_detector(release_guard).await {
+        Ok(controller) => (true, controller.listen().await),
+        // the door failed to initialise the detector
+        Err(err) => (false, Err(err)),
+      };
+      if let Err(err) = result {
+        failures_tx
+          .send(DoorFailure {
+            identifier: failed_identifier,
+            generation,
+            controller_started,
+            err,
+          })
+          .ok();
+      }
+    });
+
+    self.doors.insert(
+      identifier,
+      RunningDoor {
+        config,
+        control_tx,
+        handle,
+        released,
+        generation,
+        started: Instant::now(),
+        has_run,
+        restart_delay,
+      },
+    );
+  }
+
+  /// Mark a door which has failed as unavailable, and restart it once `delay` has passed
+  fn restart_later(
+    &mut self,
+    identifier: String,
+    config: DoorConfig<AnyDoorDetector>,
+    has_run: bool,
+    delay: Duration,
+    err: GarageError,
+  ) {
+    log::error!("Door {} failed, restarting it in {:?}: {}", identifier, delay, err);
+    self.context.monitor.set_door_state(&identifier, "failed".to_owned());
+    if let Some(availability_topic) = &config.controller.availability_topic {
+      self
+        .mqtt_tx
+        .send(MqttPublish {
+          topic: availability_topic.clone(),
+          qos: QoS::AtLeastOnce,
+          retain: true,
+          payload: self.context.availability.offline.clone(),
+        })
+        .ok();
+    }
+    self.failed.insert(
+      identifier,
+      FailedDoor {
+        config,
+        has_run,
+        restart_at: Instant::now() + delay,
+        restart_delay: (delay * 2).min(MAX_RESTART_DELAY),
+      },
+    );
+  }
+
+  /// A door has ended with an error, so wait for it to release its pins and topics then restart it after a delay.
+  ///
+  /// The other doors carry on regardless. The delay increases each time the door fails, unless it had been running for
+  /// a while.
+  pub async fn failed(&mut self, failure: DoorFailure) -> GarageResult<()> {
+    let DoorFailure {
+      identifier,
+      generation,
+      controller_started,
+      err,
+    } = failure;
+    let door = match self.doors.remove(&identifier) {
+      Some(door) if door.generation == generation => door,
+      Some(door) => {
+        // the door has already been restarted
+        self.doors.insert(identifier, door);
+        return Ok(());
+      }
+      None => return Ok(()),
+    };
+    // a door which had been running for a while is restarted as though it failed for the first time
+    let restart_delay = if door.started.elapsed() > MAX_RESTART_DELAY {
+      MIN_RESTART_DELAY
+    }
+    else {
+      door.restart_delay
+    };
+    let config = door.config.clone();
+    let has_run = door.has_run || controller_started;
+    // the door has ended, but its detector may still be releasing its pins
+    door.stopped(&identifier, Instant::now() + SHUTDOWN_TIMEOUT).await;
+    self.mqtt_subscriber.prune().await?;
+    self.restart_later(identifier, config, has_run, restart_delay, err);
+
+    Ok(())
+  }
+
+  /// Wait until a failed door is due to be restarted, which never happens if none have failed
+  pub async fn restart_due(&self) {
+    match self.failed.values().map(|door| door.restart_at).min() {
+      Some(restart_at) => time::sleep_until(restart_at).await,
+      None => future::pending().await,
+    }
+  }
+
+  /// Restart the failed doors which are due, see [`DoorSupervisor::restart_due`]
+  pub async fn restart_failed(&mut self) {
+    let now = Instant::now();
+    let mut identifiers: Vec<String> = self
+      .failed
+      .iter()
+      .filter(|(_, door)| door.restart_at <= now)
+      .map(|(identifier, _)| identifier.clone())
+      .collect();
+    identifiers.sort();
+    for identifier in identifiers {
+      let door = self.failed.remove(&identifier).expect("door failed");
+      log::info!("Restarting door {}", identifier);
+      self
+        .launch(identifier, door.config, door.has_run, door.restart_delay)
+        .await;
+    }
+  }
+
+  /// Stop a door, waiting for it to finish what it's doing (e.g. pressing the remote) and release its pins and topics
+  pub async fn stop(&mut self, identifier: &str) -> GarageResult<()> {
+    self.failed.remove(identifier);
+    if let Some(door) = self.doors.remove(identifier) {
+      log::info!("Stopping door {}", identifier);
+      door.control_tx.send(ControllerCommand::Stop).ok();
+      door.stopped(identifier, Instant::now() + SHUTDOWN_TIMEOUT).await;
+      self.mqtt_subscriber.prune().await?;
+    }
+
+    Ok(())
+  }
+
+  /// Stop every door for the service to shut down.
+  ///
+  /// The doors are stopped together, and any which are still busy (e.g. waiting on their detector) after the
+  /// timeout are aborted, which also releases the remote.
+  pub async fn stop_all(&mut self) {
+    self.failed.clear();
+    for door in self.doors.values() {
+      door.control_tx.send(ControllerCommand::Stop).ok();
+    }
+
+    let mut doors: Vec<_> = self.doors.drain().collect();
+    doors.sort_by(|(a, _), (b, _)| a.cmp(b));
+    let deadline = Instant::now() + SHUTDOWN_TIMEOUT;
+    for (identifier, door) in doors {
+      door.stopped(&identifier, deadline).await;
+    }
+  }
+
+  /// Apply a new configuration to the running doors.
+  ///
+  /// Only the doors which have changed are affected. Doors whose timings alone changed keep running with the new
+  /// timings, any other change restarts the door. Failed doors are restarted straight away.
+  pub async fn reload(
+    &mut self,
+    initialisation_timeout: Duration,
+    state_directory: PathBuf,
+    mut doors: HashMap<String, DoorConfig<AnyDoorDetector>>,
+  ) -> GarageResult<()> {
+    self.context.initialisation_timeout = initialisation_timeout;
+    self.context.state_directory = state_directory;
+
+    let mut identifiers: Vec<String> = self.doors.keys().cloned().collect();
+    identifiers.sort();
+    // doors are stopped before any are started, so the pins and topics they release can be reused
+    let mut restarted = Vec::new();
+    for (identifier, failed) in self.failed.drain() {
+      if let Some(config) = doors.remove(&identifier) {
+        restarted.push((identifier, config, failed.has_run));
+      }
+    }
+    for identifier in identifiers {
+      let running = &self.doors[&identifier];
+      match doors.remove(&identifier) {
+        None => self.stop(&identifier).await?,
+        Some(config) if config == running.config => {}
+        Some(config)
+          if config.detector == running.config.detector
+            && config.controller.eq_ignoring_timings(&running.config.controller) =>
+        {
+          log::info!("Updating timings of door {}", identifier);
+          running
+            .control_tx
+            .send(ControllerCommand::UpdateTimings(config.controller.timings()))
+            .ok();
+          self.doors.get_mut(&identifier).expect("door is running").config = config;
+        }
+        Some(config) => {
+          self.stop(&identifier).await?;
+          // the door has already been running, so it shouldn't move to its initial state again
+          restarted.push((identifier, config, true));
+        }
+      }
+    }
+
+    restarted.sort_by(|(a, ..), (b, ..)| a.cmp(b));
+    for (identifier, config, has_run) in restarted {
+      log::info!("Restarting door {} with its new configuration", identifier);
+      self.launch(identifier, config, has_run, MIN_RESTART_DELAY).await;
+    }
+
+    // whatever is left is new
+    let mut added: Vec<_> = doors.into_iter().collect();
+    added.sort_by(|(a, _), (b, _)| a.cmp(b));
+    for (identifier, config) in added {
+      log::info!("Starting new door {}", identifier);
+      self.start(identifier, config).await;
+    }
+
+    Ok(())
+  }
+}
@@ -1,7 +1,10 @@
+use std::path::PathBuf;
+
 use thiserror::Error;
 use tokio::task::JoinError;
 
-use crate::door::identifier::Identifier;
+use crate::config::validation::ConfigIssue;
+
 pub type GarageResult<T> = Result<T, GarageError>;
 
 #[derive(Debug, Error)]
@@ -15,11 +18,32 @@ pub enum GarageError {
   #[error(transparent)]
   MqttClient(#[from] rumqttc::ClientError),
   #[error(transparent)]
-  MqttConnection(#[from] rumqttc::ConnectionError),
+  MqttConnection(Box<rumqttc::ConnectionError>),
+  #[error("invalid MQTT client configuration: {0}")]
+  MqttClientConfig(String),
+  #[error("invalid MQTT topic filter: {0}")]
+  InvalidTopicFilter(String),
+  #[error("MQTT topic filter {0} overlaps {1}, which is already subscribed to exclusively")]
+  TopicConflict(String, String),
   #[error("the MQTT client has been closed")]
   MqttClosed,
   #[error(transparent)]
   JoinError(#[from] JoinError),
-  #[error("door initialisation timeout for {0:?}")]
-  DoorInitialisationTimeout(Identifier),
+  #[error(transparent)]
+  Io(#[from] std::io::Error),
+  #[error("unable to read {}: {source}", path.display())]
+  ConfigRead { path: PathBuf, source: std::io::Error },
+  #[error("unable to parse {}: {source}", path.display())]
+  ConfigParse { path: PathBuf, source: toml::de::Error },
+  #[error("invalid configuration:{}", .0.iter().map(|issue| format!("\n  {}", issue)).collect::<String>())]
+  InvalidConfig(Vec<ConfigIssue>),
+  #[error("{0}")]
+  Command(String),
+}
+
+impl From<rumqttc::ConnectionError> for GarageError {
+  fn from(err: rumqttc::ConnectionError) -> Self {
+    // the connection error is very large, box it to keep `GarageResult` small
+    GarageError::MqttConnection(Box::new(err))
+  }
 }
@@ -1,102 +1,275 @@
 #![warn(rust_2018_idioms)]
 
-use std::{fs, sync::Arc, time::Duration};
+use std::{path::Path, process::ExitCode, sync::Arc, time::Duration};
 
+use clap::Parser;
+use rumqttc::QoS;
 use simple_logger::SimpleLogger;
-use tokio::{self, select, task::JoinSet, time::sleep};
+use tokio::{
+  self, select,
+  signal::unix::{signal, Signal, SignalKind},
+  task::JoinSet,
+  time::{sleep, timeout},
+};
 
 use crate::{
+  cli::{Cli, Command},
+  clock::SystemClock,
   config::Config,
-  door::{controller::remote::mutex::RemoteMutex, Door},
-  error::GarageError,
-  mqtt_client::MqttClient,
+  door::{
+    controller::remote::mutex::RemoteMutex,
+    discovery::Discovery,
+    state::{DoorCommand, TargetState},
+    supervisor::DoorSupervisor,
+    DoorContext,
+  },
+  error::{GarageError, GarageResult},
+  mqtt_client::{sender::PublishSender, Availability, MqttClient, MqttPublish},
+  systemd::{Notifier, ServiceMonitor},
 };
 
+pub mod cli;
+pub mod clock;
 pub mod config;
 pub mod door;
 pub mod error;
 #[cfg(not(feature = "arm"))]
 mod mock_gpio;
 pub mod mqtt_client;
+pub mod systemd;
+
+/// How long to wait for outstanding messages to be sent to the broker when shutting down
+const DISCONNECT_TIMEOUT: Duration = Duration::from_secs(5);
 
 #[tokio::main]
-async fn main() {
+async fn main() -> ExitCode {
+  let cli = Cli::parse();
+
   SimpleLogger::new()
     .with_module_level("rumqttc", log::LevelFilter::Warn)
     .init()
     .unwrap();
 
+  let config_path = &cli.config;
+  let send_command =
+    |door: String, command| async move { cli::send_command(&Config::load(config_path)?, &door, command).await };
+  let result = match cli.command.unwrap_or(Command::Run) {
+    Command::Run => run_forever(config_path).await,
+    Command::CheckConfig => Config::load(config_path).and_then(|config| cli::check_config(&config)),
+    Command::Open { door } => send_command(door, DoorCommand::Move(TargetState::Open)).await,
+    Command::Close { door } => send_command(door, DoorCommand::Move(TargetState::Closed)).await,
+    Command::Stop { door } => send_command(door, DoorCommand::Stop).await,
+    Command::HoldOpen { door } => send_command(door, DoorCommand::HoldOpen).await,
+    Command::Calibrate { door } => send_command(door, DoorCommand::Calibrate).await,
+    Command::Status { door } => async { cli::print_status(&Config::load(config_path)?, &door).await }.await,
+  };
+
+  match result {
+    Ok(()) => ExitCode::SUCCESS,
+    Err(err) => {
+      eprintln!("Error: {}", err);
+      ExitCode::FAILURE
+    }
+  }
+}
+
+/// The signals which shut the service down gracefully
+struct ShutdownSignals {
+  terminate: Signal,
+  interrupt: Signal,
+}
+
+impl ShutdownSignals {
+  /// Start listening for the signals, replacing their default handling which exits immediately
+  fn new() -> GarageResult<Self> {
+    Ok(ShutdownSignals {
+      terminate: signal(SignalKind::terminate())?,
+      interrupt: signal(SignalKind::interrupt())?,
+    })
+  }
+
+  /// Wait for either signal to be received
+  async fn recv(&mut self) {
+    select! {
+      _ = self.terminate.recv() => {}
+      _ = self.interrupt.recv() => {}
+    }
+  }
+}
+
+/// Run the service, restarting it if an error occurs.
+///
+/// The configuration is re-read each time, only returning if it can't be or it isn't valid, or once the service is
+/// shut down by SIGTERM or SIGINT.
+async fn run_forever(config_path: &Path) -> GarageResult<()> {
+  let mut shutdown = ShutdownSignals::new()?;
+  let notifier = Notifier::from_env();
   loop {
-    let err = run().await;
+    let config = Config::load(config_path)?;
+    config.validate()?;
+    let err = match run(config_path, config, &mut shutdown, &notifier).await {
+      Ok(()) => return Ok(()),
+      Err(err) => err,
+    };
     log::error!("Error occurred, restarting in 5 seconds: {:?}", err);
+    notifier.status(&format!("Restarting after error: {}", err));
     // wait some time for the broker to come back online
-    sleep(Duration::from_secs(5)).await;
+    select! {
+      _ = sleep(Duration::from_secs(5)) => {}
+      _ = shutdown.recv() => {
+        log::info!("Shutting down");
+        notifier.stopping();
+        return Ok(());
+      }
+    }
   }
 }
 
-/// Run the MQTT receiver and sender and react
-/// Runs forever unless an error occurs
-async fn run() -> Result<(), GarageError> {
-  let config = fs::read_to_string("garage-config.toml").expect("unable to read garage-config.toml");
-  let config: Config = toml::from_str(&config).expect("unable to parse garage-config.toml");
+/// Announce every door to Home Assistant, if discovery is enabled
+fn announce_discovery(
+  config: &Config,
+  availability_topic: &str,
+  availability: &Availability,
+  send_channel: &PublishSender,
+) -> GarageResult<()> {
+  if let Some(discovery_config) = &config.discovery {
+    let discovery = Discovery::new(
+      discovery_config.clone(),
+      availability_topic.to_owned(),
+      availability.clone(),
+      send_channel.clone(),
+      &config.state_directory,
+    );
+    discovery.announce(
+      config
+        .doors
+        .iter()
+        .map(|(identifier, door_config)| (identifier.clone().into(), &door_config.controller)),
+    )?;
+  }
 
-  let remote_mutex = Arc::new(RemoteMutex::new());
+  Ok(())
+}
 
-  let (send_channel, mut client) = MqttClient::new("mqtt-garage", config.mqtt_client);
+/// Run the MQTT receiver and sender and react until an error occurs or the service is shut down, reloading the
+/// configuration on SIGHUP
+async fn run(
+  config_path: &Path,
+  config: Config,
+  shutdown: &mut ShutdownSignals,
+  notifier: &Notifier,
+) -> Result<(), GarageError> {
+  let client_config = config.mqtt_client.clone();
+  let (send_channel, mut client) = MqttClient::new("mqtt-garage", client_config.clone())?;
+  let availability_topic = client.availability_topic().to_owned();
+  let availability = client.availability().clone();
 
-  let mut doors = Vec::with_capacity(config.doors.len());
-  for (identifier, door_config) in config.doors {
-    doors.push(
-      Door::new(
-        identifier.into(),
-        door_config,
-        send_channel.clone(),
-        remote_mutex.clone(),
-        &mut client.receiver,
-      )
-      .await?,
-    );
-  }
-  client.announce().await.expect("failed to announce client");
+  let door_context = DoorContext {
+    remote_mutex: Arc::new(RemoteMutex::new()),
+    availability: availability.clone(),
+    initialisation_timeout: config.door_initialisation_timeout,
+    reconnected_tx: client.receiver.reconnected_tx(),
+    state_directory: config.state_directory.clone(),
+    monitor: ServiceMonitor::new(notifier.clone()),
+    clock: Arc::new(SystemClock),
+  };
+
+  announce_discovery(&config, &availability_topic, &availability, &send_channel)?;
+  client.announce().await?;
+
+  let mut hangup = signal(SignalKind::hangup())?;
 
   let mut handles = JoinSet::new();
+  let mqtt_subscriber = client.receiver.subscriber();
 
   let mut receiver = client.receiver;
-  handles.spawn(async move { receiver.receive_messages().await });
+  let heartbeat = door_context.monitor.heartbeat("MQTT receiver".to_owned());
+  handles.spawn(async move { receiver.receive_messages(heartbeat).await });
 
   let mut sender = client.sender;
   handles.spawn(async move { sender.send_messages().await });
 
+  // once the receiver and sender are running, we can start the doors
+  // doors start concurrently so a door waiting on its detector doesn't hold up the others
+  let reporter = tokio::spawn(
+    door_context
+      .monitor
+      .clone()
+      .report(config.doors.keys().cloned().collect()),
+  );
+  let (mut supervisor, mut door_failures) = DoorSupervisor::new(door_context, send_channel.clone(), mqtt_subscriber);
+  for (identifier, door_config) in config.doors {
+    supervisor.start(identifier, door_config).await;
+  }
 
-  // once the receiver and sender are running, we can start listening
-  for door in doors {
-    let identifier = door.identifier.clone();
+  // the handles will only end if an error occurs (the receiver reconnects to the broker itself), whereas a door which
+  // fails is restarted on its own
+  let err = loop {
     select! {
-      _ = tokio::time::sleep(Duration::from_secs(10)) => {
-            client.client.disconnect().await.ok();
-            return Err(GarageError::DoorInitialisationTimeout(identifier))
-          },
-      controller_detector = door.start_detector() => {
-        match controller_detector {
-          Ok((controller, detector)) => handles.spawn(async move { controller.listen(detector).await }),
+      Some(result) = handles.join_next() => {
+        break match result {
+          Ok(Err(err)) => err,
+          Ok(Ok(())) => GarageError::MqttClosed,
+          Err(err) => err.into(),
+        };
+      }
+
+      Some(failure) = door_failures.recv() => supervisor.failed(failure).await?,
+
+      _ = supervisor.restart_due() => supervisor.restart_failed().await,
+
+      Some(()) = hangup.recv() => {
+        log::info!("Reloading {}", config_path.display());
+        let new_config = match Config::load(config_path).and_then(|config| config.validate().map(|_| config)) {
+          Ok(config) => config,
           Err(err) => {
-            // the door failed to initialise the detector
-            client.client.disconnect().await.ok();
-            return Err(err)
+            log::error!("Keeping the current configuration: {}", err);
+            continue;
           }
+        };
+        if new_config.mqtt_client != client_config {
+          log::warn!("Changes to the MQTT client configuration only apply once restarted");
         }
-      },
-    };
-  }
 
-  // the handles will only end if an error occurs (most likely MQTT broker disconnection)
-  let err = handles
-    .join_next()
-    .await
-    .expect("empty JoinSet")
-    .expect("join error")
-    .unwrap_err()
-    .into();
+        announce_discovery(&new_config, &availability_topic, &availability, &send_channel)?;
+        supervisor
+          .reload(new_config.door_initialisation_timeout, new_config.state_directory, new_config.doors)
+          .await?;
+      }
+
+      _ = shutdown.recv() => {
+        log::info!("Shutting down");
+        notifier.stopping();
+        reporter.abort();
+        // the doors stop once they've finished what they're doing, marking themselves as unavailable
+        supervisor.stop_all().await;
+        send_channel
+          .send(MqttPublish {
+            topic: availability_topic.clone(),
+            qos: QoS::AtLeastOnce,
+            retain: true,
+            payload: availability.offline.clone(),
+          })
+          .map_err(|_| GarageError::MqttClosed)?;
+
+        // the sender ends once everything queued has been published and nothing else can be
+        drop(supervisor);
+        drop(send_channel);
+        let disconnected = timeout(DISCONNECT_TIMEOUT, async {
+          handles.join_next().await;
+          client.client.disconnect().await.ok();
+          // the receiver ends once the disconnect has been sent
+          handles.join_next().await;
+        })
+        .await;
+        if disconnected.is_err() {
+          log::warn!("Timed out disconnecting from the MQTT broker");
+        }
+        return Ok(());
+      }
+    }
+  };
+  reporter.abort();
   client.client.disconnect().await.ok();
   Err(err)
 }
@@ -1,10 +1,21 @@
 //! Mimics rppal's API without the need to compile to ARM and use physical hardware
 
 pub use std::fmt::Error;
-use std::fs;
+use std::{
+  fs,
+  sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+  },
+  thread,
+  time::Duration,
+};
 
 use log::{debug, warn};
 
+/// How often mock input pins are checked for changes, emulating interrupts
+const INTERRUPT_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
 pub struct Gpio;
 
 impl Gpio {
@@ -18,6 +29,21 @@ impl Gpio {
   }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Level {
+  Low,
+  High,
+}
+
+#[allow(dead_code)] // not all triggers are used, but they mirror rppal's
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Trigger {
+  Disabled,
+  RisingEdge,
+  FallingEdge,
+  Both,
+}
+
 #[derive(Debug)]
 pub struct Pin(u8);
 
@@ -27,7 +53,10 @@ impl Pin {
   }
 
   pub fn into_input_pullup(self) -> InputPin {
-    InputPin(self.0)
+    InputPin {
+      pin: self.0,
+      interrupt: None,
+    }
   }
 }
 
@@ -35,24 +64,118 @@ impl Pin {
 pub struct OutputPin(u8);
 
 impl OutputPin {
-  pub fn set_high(&self) {
-    debug!("{:?} set to high", self)
+  pub fn set_high(&mut self) {
+    #[cfg(test)]
+    {
+      *TEST_PRESSES.lock().unwrap().entry(self.0).or_default() += 1;
+    }
+    debug!("GPIO {} set to high", self.0)
   }
 
-  pub fn set_low(&self) {
-    debug!("{:?} set to low", self)
+  pub fn set_low(&mut self) {
+    debug!("GPIO {} set to low", self.0)
   }
 }
 
 
+/// Input pins are read from a `<pin number>.pin` file, containing `1` if high
 #[derive(Debug)]
-pub struct InputPin(u8);
+pub struct InputPin {
+  pin: u8,
+  /// Set to false to stop the interrupt polling thread
+  interrupt: Option<Arc<AtomicBool>>,
+}
+
+/// The levels tests have set pins to in place of their files, each read in turn (e.g. alternating to flap)
+#[cfg(test)]
+static TEST_LEVELS: std::sync::Mutex<std::collections::BTreeMap<u8, (Vec<Level>, usize)>> =
+  std::sync::Mutex::new(std::collections::BTreeMap::new());
+
+/// Make reads of `pin` cycle through `levels`, rather than reading its file
+#[cfg(test)]
+pub fn set_test_levels(pin: u8, levels: &[Level]) {
+  TEST_LEVELS.lock().unwrap().insert(pin, (levels.to_vec(), 0));
+}
+
+/// The number of times each output pin has been set high, i.e. how many times a remote button was pressed
+#[cfg(test)]
+static TEST_PRESSES: std::sync::Mutex<std::collections::BTreeMap<u8, usize>> =
+  std::sync::Mutex::new(std::collections::BTreeMap::new());
+
+/// How many times `pin` has been set high
+#[cfg(test)]
+pub fn test_presses(pin: u8) -> usize {
+  TEST_PRESSES.lock().unwrap().get(&pin).copied().unwrap_or_default()
+}
+
+fn read_pin(pin: u8) -> Level {
+  #[cfg(test)]
+  if let Some((levels, next)) = TEST_LEVELS.lock().unwrap().get_mut(&pin) {
+    let level = levels[*next % levels.len()];
+    *next += 1;
+    return level;
+  }
+
+  match fs::read_to_string(format!("{}.pin", pin)).as_deref() {
+    Ok("1") => Level::High,
+    _ => Level::Low,
+  }
+}
 
 impl InputPin {
+  pub fn read(&self) -> Level {
+    read_pin(self.pin)
+  }
+
   pub fn is_high(&self) -> bool {
-    fs::read_to_string(format!("{}.pin", self.0))
-      .ok()
-      .map(|value| value == "1")
-      .unwrap_or(false)
+    self.read() == Level::High
+  }
+
+  /// Calls `callback` from another thread whenever the pin changes, polling the pin file as there are no real
+  /// interrupts.
+  pub fn set_async_interrupt<C>(&mut self, trigger: Trigger, mut callback: C) -> Result<(), Error>
+  where
+    C: FnMut(Level) + Send + 'static,
+  {
+    self.clear_async_interrupt()?;
+
+    let running = Arc::new(AtomicBool::new(true));
+    self.interrupt = Some(running.clone());
+
+    let pin = self.pin;
+    thread::spawn(move || {
+      let mut previous_level = read_pin(pin);
+      while running.load(Ordering::Relaxed) {
+        thread::sleep(INTERRUPT_POLL_INTERVAL);
+        let level = read_pin(pin);
+        if level != previous_level {
+          previous_level = level;
+          let triggered = match trigger {
+            Trigger::Disabled => false,
+            Trigger::RisingEdge => level == Level::High,
+            Trigger::FallingEdge => level == Level::Low,
+            Trigger::Both => true,
+          };
+          if triggered {
+            callback(level);
+          }
+        }
+      }
+    });
+
+    Ok(())
+  }
+
+  pub fn clear_async_interrupt(&mut self) -> Result<(), Error> {
+    if let Some(running) = self.interrupt.take() {
+      running.store(false, Ordering::Relaxed);
+    }
+    Ok(())
+  }
+}
+
+impl Drop for InputPin {
+  fn drop(&mut self) {
+    self.clear_async_interrupt().ok();
   }
 }
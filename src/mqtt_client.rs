@@ -1,34 +1,81 @@
 use std::{
-  collections::HashMap,
   fmt::{self, Debug},
+  fs,
+  path::PathBuf,
   time::Duration,
 };
 
 use rumqttc::{AsyncClient, LastWill, MqttOptions, QoS};
 use serde::Deserialize;
-use tokio::sync::mpsc;
+use tokio::sync::{broadcast, mpsc};
 
 use self::{
   receiver::MqttReceiver,
   sender::{MqttSender, PublishSender},
+  tls::MqttTlsConfig,
 };
-use crate::error::GarageResult;
+use crate::error::{GarageError, GarageResult};
 
 pub mod receiver;
 pub mod sender;
+pub mod tls;
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Clone, PartialEq)]
 pub struct MqttClientConfig {
   /// The domain name of the broker
   pub broker_domain: String,
   /// The port of the broker, 1883 by default
   pub broker_port: u16,
-  /// The name of the MQTT topic availability states are sent on
+  /// The name of the MQTT topic availability states are sent on.
+  ///
+  /// This is the availability of the service as a whole (sent as the last will), doors can also have their own.
   pub availability_topic: String,
   /// The payload of the state indicating the door is online
   pub online_availability: String,
   /// The payload of the state indicating the door is offline
   pub offline_availability: String,
+  /// The username to authenticate with, if the broker requires it
+  pub username: Option<String>,
+  /// The password to authenticate with
+  pub password: Option<String>,
+  /// A file containing the password to authenticate with, an alternative to `password`
+  pub password_file: Option<PathBuf>,
+  /// Connect to the broker with TLS (typically on port 8883), if set
+  pub tls: Option<MqttTlsConfig>,
+}
+
+impl MqttClientConfig {
+  fn password(&self) -> GarageResult<Option<String>> {
+    match (&self.password, &self.password_file) {
+      (Some(_), Some(_)) => Err(GarageError::MqttClientConfig(
+        "only one of password and password_file can be set".to_owned(),
+      )),
+      (Some(password), None) => Ok(Some(password.clone())),
+      (None, Some(password_file)) => fs::read_to_string(password_file)
+        // editors typically add a trailing new line
+        .map(|password| Some(password.trim_end_matches(['\r', '\n']).to_owned()))
+        .map_err(|err| GarageError::MqttClientConfig(format!("unable to read {}: {}", password_file.display(), err))),
+      (None, None) => Ok(None),
+    }
+  }
+}
+
+/// The payloads availability is published with
+#[derive(Debug, Clone)]
+pub struct Availability {
+  pub online: String,
+  pub offline: String,
+}
+
+impl Availability {
+  pub fn payload(&self, available: bool) -> &str {
+    if available {
+      &self.online
+    }
+    else {
+      &self.offline
+    }
+  }
 }
 
 #[derive(Debug)]
@@ -41,7 +88,7 @@ pub struct MqttPublish {
 
 pub struct MqttClient {
   availability_topic: String,
-  online_availability: String,
+  availability: Availability,
   pub sender: MqttSender,
   pub receiver: MqttReceiver,
   pub client: AsyncClient,
@@ -54,11 +101,32 @@ impl Debug for MqttClient {
 }
 
 impl MqttClient {
-  pub fn new(id: &'static str, config: MqttClientConfig) -> (PublishSender, Self) {
-    let mut mqttoptions = MqttOptions::new(id, config.broker_domain, config.broker_port);
+  /// The options to connect to the broker with, including any credentials and TLS
+  pub fn options(id: &str, config: &MqttClientConfig) -> GarageResult<MqttOptions> {
+    let mut mqttoptions = MqttOptions::new(id, &config.broker_domain, config.broker_port);
+    match (&config.username, config.password()?) {
+      (Some(username), password) => {
+        mqttoptions.set_credentials(username, password.unwrap_or_default());
+      }
+      (None, Some(_)) => {
+        return Err(GarageError::MqttClientConfig(
+          "a password requires a username".to_owned(),
+        ))
+      }
+      (None, None) => {}
+    }
+    if let Some(tls) = &config.tls {
+      mqttoptions.set_transport(tls.transport()?);
+    }
+
+    Ok(mqttoptions)
+  }
+
+  pub fn new(id: &'static str, config: MqttClientConfig) -> GarageResult<(PublishSender, Self)> {
+    let mut mqttoptions = Self::options(id, &config)?;
     mqttoptions.set_last_will(LastWill::new(
       &config.availability_topic,
-      config.offline_availability,
+      config.offline_availability.clone(),
       QoS::AtLeastOnce,
       true,
     ));
@@ -68,15 +136,19 @@ impl MqttClient {
 
     let (send_tx, send_rx) = mpsc::unbounded_channel();
 
-    (
+    Ok((
       send_tx,
       MqttClient {
-        availability_topic: config.availability_topic,
-        online_availability: config.online_availability,
-        receiver: MqttReceiver {
-          client: client.clone(),
+        receiver: MqttReceiver::new(
+          client.clone(),
           event_loop,
-          receive_channels: HashMap::new(),
+          (config.availability_topic.clone(), config.online_availability.clone()),
+          broadcast::channel(1).0,
+        ),
+        availability_topic: config.availability_topic,
+        availability: Availability {
+          online: config.online_availability,
+          offline: config.offline_availability,
         },
         sender: MqttSender {
           client: client.clone(),
@@ -84,10 +156,23 @@ impl MqttClient {
         },
         client,
       },
-    )
+    ))
+  }
+
+  /// The name of the MQTT topic the service's availability is sent on
+  pub fn availability_topic(&self) -> &str {
+    &self.availability_topic
+  }
+
+  /// The payloads availability is published with, shared by the client and each door
+  pub fn availability(&self) -> &Availability {
+    &self.availability
   }
 
-  /// Announce our availability
+  /// Announce our availability.
+  ///
+  /// This is the availability of the service as a whole, each door also announces its own availability once it's
+  /// ready, which is only sent once this is.
   pub async fn announce(&mut self) -> GarageResult<()> {
     // announce our availability
     self
@@ -96,7 +181,7 @@ impl MqttClient {
         &self.availability_topic,
         QoS::AtLeastOnce,
         true,
-        &self.online_availability,
+        &self.availability.online,
       )
       .await
   }
@@ -1,50 +1,402 @@
-use std::collections::HashMap;
+use std::{
+  collections::HashMap,
+  fmt,
+  sync::{Arc, Mutex, MutexGuard, PoisonError},
+  time::Duration,
+};
 
-use rumqttc::{AsyncClient, Event, EventLoop, Packet, QoS};
-use tokio::sync::mpsc;
+use rumqttc::{AsyncClient, Event, EventLoop, Outgoing, Packet, Publish, QoS, Request, Subscribe};
+use tokio::{
+  sync::{broadcast, mpsc},
+  time::sleep,
+};
 
 use super::{MqttPublish, PublishSender};
-use crate::error::GarageResult;
+use crate::{
+  error::{GarageError, GarageResult},
+  systemd::Heartbeat,
+};
 
 pub type PublishReceiver = mpsc::UnboundedReceiver<MqttPublish>;
 
+/// How long to wait before reconnecting after the first connection error
+const MIN_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+/// The longest to wait before reconnecting, the delay doubles after each failed attempt up to this
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60);
+
+#[derive(Debug)]
+pub struct Subscription {
+  pub qos: QoS,
+  /// The channels with which messages received from MQTT are fowarded on, one per subscriber
+  pub channels: Vec<PublishSender>,
+  /// Whether the subscribers must be the only ones receiving messages matching this filter (e.g. door commands)
+  pub exclusive: bool,
+}
+
+/// Whether the topic filter is valid, i.e. wildcards only occupy a whole level and `#` is only the last level
+pub fn valid_filter(filter: &str) -> bool {
+  rumqttc::valid_filter(filter) && filter.split('/').all(|level| level == "+" || !level.contains('+'))
+}
+
+/// Whether the topic matches the (possibly wildcard) topic filter
+pub fn topic_matches(filter: &str, topic: &str) -> bool {
+  // a topic is a filter without wildcards, which only it matches
+  filters_overlap(filter, topic)
+}
+
+/// Whether the filter's first level is a wildcard, which doesn't match topics starting with `$` (e.g. `$SYS/...`)
+fn starts_with_wildcard(filter: &str) -> bool {
+  matches!(filter.split('/').next(), Some("+" | "#"))
+}
+
+/// Whether there is any topic that both topic filters would match
+pub fn filters_overlap(a: &str, b: &str) -> bool {
+  if (a.starts_with('$') && starts_with_wildcard(b)) || (b.starts_with('$') && starts_with_wildcard(a)) {
+    return false;
+  }
+
+  let mut a_levels = a.split('/');
+  let mut b_levels = b.split('/');
+  loop {
+    match (a_levels.next(), b_levels.next()) {
+      // `a/#` also matches `a`, so this covers filters of different lengths too
+      (Some("#"), _) | (_, Some("#")) => return true,
+      (Some(a_level), Some(b_level)) => {
+        if a_level != b_level && a_level != "+" && b_level != "+" {
+          return false;
+        }
+      }
+      (None, Some(_)) | (Some(_), None) => return false,
+      (None, None) => return true,
+    }
+  }
+}
+
+/// Subscribes to topics on behalf of the receiver.
+///
+/// This is shared with the receiver, so topics can be subscribed to (and unsubscribed from) while it's running.
+#[derive(Clone)]
+pub struct MqttSubscriber {
+  client: AsyncClient,
+  subscriptions: Arc<Mutex<HashMap<String, Subscription>>>,
+}
+
+impl fmt::Debug for MqttSubscriber {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "MqttSubscriber")
+  }
+}
+
+impl MqttSubscriber {
+  fn subscriptions(&self) -> MutexGuard<'_, HashMap<String, Subscription>> {
+    // the subscriptions are always left consistent, so a panic elsewhere doesn't matter
+    self.subscriptions.lock().unwrap_or_else(PoisonError::into_inner)
+  }
+
+  /// Subscribe to a topic filter, which may contain `+` and `#` wildcards.
+  ///
+  /// Any number of subscribers can receive the same messages, unless one of them subscribed exclusively.
+  pub async fn subscribe(&self, filter: String, qos: QoS) -> GarageResult<PublishReceiver> {
+    self.add_subscription(filter, qos, false).await
+  }
+
+  /// Subscribe to a topic filter, failing if an exclusive subscription to a different filter could receive the same
+  /// messages.
+  ///
+  /// This is used for commands, where two doors acting on the same message would be a misconfiguration unless they
+  /// deliberately share the same topic (e.g. a schedule override topic for every door), in which case each of them
+  /// receives every message.
+  pub async fn subscribe_exclusive(&self, filter: String, qos: QoS) -> GarageResult<PublishReceiver> {
+    self.add_subscription(filter, qos, true).await
+  }
+
+  async fn add_subscription(&self, filter: String, qos: QoS, exclusive: bool) -> GarageResult<PublishReceiver> {
+    if !valid_filter(&filter) {
+      return Err(GarageError::InvalidTopicFilter(filter));
+    }
+
+    let (receive_tx, receive_rx) = mpsc::unbounded_channel();
+    let needs_subscribe = {
+      let mut subscriptions = self.subscriptions();
+      // subscribers which have gone away no longer conflict
+      subscriptions.retain(|_, subscription| {
+        subscription.channels.retain(|channel| !channel.is_closed());
+        !subscription.channels.is_empty()
+      });
+
+      if exclusive {
+        if let Some(existing) = subscriptions
+          .iter()
+          .find(|(existing, subscription)| {
+            subscription.exclusive && **existing != filter && filters_overlap(existing, &filter)
+          })
+          .map(|(existing, _)| existing)
+        {
+          return Err(GarageError::TopicConflict(filter, existing.clone()));
+        }
+      }
+
+      match subscriptions.get_mut(&filter) {
+        Some(subscription) => {
+          subscription.exclusive |= exclusive;
+          subscription.channels.push(receive_tx);
+          // the broker only needs to be told about the filter again if we now need a higher QoS
+          if qos > subscription.qos {
+            subscription.qos = qos;
+            true
+          }
+          else {
+            false
+          }
+        }
+        None => {
+          subscriptions.insert(
+            filter.clone(),
+            Subscription {
+              qos,
+              channels: vec![receive_tx],
+              exclusive,
+            },
+          );
+          true
+        }
+      }
+    };
+
+    if needs_subscribe {
+      self.client.subscribe(&filter, qos).await?;
+    }
+
+    Ok(receive_rx)
+  }
+
+  /// Unsubscribe from any topic filters whose subscribers have all gone away
+  pub async fn prune(&self) -> GarageResult<()> {
+    let mut unused = Vec::new();
+    self.subscriptions().retain(|filter, subscription| {
+      subscription.channels.retain(|channel| !channel.is_closed());
+      if subscription.channels.is_empty() {
+        unused.push(filter.clone());
+        false
+      }
+      else {
+        true
+      }
+    });
+
+    for filter in unused {
+      log::debug!("Unsubscribing from unused topic {}", filter);
+      self.client.unsubscribe(filter).await?;
+    }
+
+    Ok(())
+  }
+}
+
 pub struct MqttReceiver {
-  pub(super) client: AsyncClient,
   pub event_loop: EventLoop,
-  /// The channel with which messages received from MQTT are fowarded on
-  pub receive_channels: HashMap<String, PublishSender>,
+  pub(super) subscriber: MqttSubscriber,
+  /// The availability topic and payload announced each time the connection is re-established
+  pub(super) availability: (String, String),
+  /// Notified each time the connection is re-established
+  pub(super) reconnected_tx: broadcast::Sender<()>,
 }
 
 impl MqttReceiver {
-  pub async fn subscribe(&mut self, topic: String, qos: QoS) -> GarageResult<PublishReceiver> {
-    if self.receive_channels.contains_key(&topic) {
-      panic!("attempted to subscribe to the same channel twice");
+  pub(super) fn new(
+    client: AsyncClient,
+    event_loop: EventLoop,
+    availability: (String, String),
+    reconnected_tx: broadcast::Sender<()>,
+  ) -> Self {
+    MqttReceiver {
+      event_loop,
+      subscriber: MqttSubscriber {
+        client,
+        subscriptions: Arc::default(),
+      },
+      availability,
+      reconnected_tx,
     }
+  }
 
-    self.client.subscribe(&topic, qos).await?;
-    let (receive_tx, receive_rx) = mpsc::unbounded_channel();
-    self.receive_channels.insert(topic, receive_tx);
+  /// Subscribes to topics, which can continue to be used once the receiver is running
+  pub fn subscriber(&self) -> MqttSubscriber {
+    self.subscriber.clone()
+  }
 
-    Ok(receive_rx)
+  /// Forward a message to every subscriber with a matching filter
+  fn dispatch(&self, message: Publish) {
+    let payload = match String::from_utf8(message.payload.to_vec()) {
+      Ok(payload) => payload,
+      Err(_) => {
+        log::warn!("Ignoring non-UTF-8 message on {}", message.topic);
+        return;
+      }
+    };
+
+    for (filter, subscription) in self.subscriber.subscriptions().iter_mut() {
+      if topic_matches(filter, &message.topic) {
+        // drop the channels of subscribers which have gone away
+        subscription.channels.retain(|channel| {
+          channel
+            .send(MqttPublish {
+              topic: message.topic.clone(),
+              qos: message.qos,
+              retain: message.retain,
+              payload: payload.clone(),
+            })
+            .is_ok()
+        });
+      }
+    }
+  }
+
+  /// A channel which is notified each time the connection to the broker is re-established, so any state can be
+  /// republished
+  pub fn reconnected_tx(&self) -> broadcast::Sender<()> {
+    self.reconnected_tx.clone()
   }
 
-  pub async fn receive_messages(&mut self) -> GarageResult<()> {
+  /// Queue the subscriptions and availability announcement for a new connection.
+  ///
+  /// These are added to the event loop's pending requests rather than sent via the client, as the client's request
+  /// channel is only drained while the event loop is polled (i.e. by us).
+  fn restore_session(&mut self) {
+    let mut pending: Vec<Request> = self.event_loop.pending.by_ref().collect();
+    for (topic, subscription) in self.subscriber.subscriptions().iter() {
+      pending.push(Request::Subscribe(Subscribe::new(topic, subscription.qos)));
+    }
+
+    let (availability_topic, online_availability) = &self.availability;
+    let mut announcement = Publish::new(availability_topic, QoS::AtLeastOnce, online_availability.as_bytes());
+    announcement.retain = true;
+    pending.push(Request::Publish(announcement));
+
+    self.event_loop.pending = pending.into_iter();
+  }
+
+  /// Receive messages from the broker, forwarding them on to subscribers.
+  ///
+  /// Connection errors are retried with an increasing delay, so this only ends once the client disconnects.
+  pub async fn receive_messages(&mut self, heartbeat: Heartbeat) -> GarageResult<()> {
+    let mut reconnect_delay = MIN_RECONNECT_DELAY;
+    let mut has_connected = false;
+    // there's always something to receive within the keep alive, even if it's only a ping
+    let keep_alive = self.event_loop.options.keep_alive();
+
     loop {
-      let notification = self.event_loop.poll().await?;
-      if let Event::Incoming(Packet::Publish(message)) = notification {
-        if let Some(channel) = self.receive_channels.get(&message.topic) {
-          if let Ok(payload) = String::from_utf8(message.payload.to_vec()) {
-            channel
-              .send(MqttPublish {
-                topic: message.topic,
-                qos: message.qos,
-                retain: message.retain,
-                payload,
-              })
-              .ok();
+      let event = self.event_loop.poll().await;
+      heartbeat.beat_after(keep_alive);
+      match event {
+        Ok(Event::Incoming(Packet::ConnAck(_))) => {
+          reconnect_delay = MIN_RECONNECT_DELAY;
+          if has_connected {
+            // the initial subscriptions and announcement are already queued by the client
+            log::info!("Reconnected to MQTT broker, restoring subscriptions");
+            self.restore_session();
+            self.reconnected_tx.send(()).ok();
           }
+          has_connected = true;
+        }
+
+        Ok(Event::Incoming(Packet::Publish(message))) => {
+          self.dispatch(message);
+        }
+
+        Ok(Event::Outgoing(Outgoing::Disconnect)) => {
+          // we've disconnected from the broker deliberately, so shouldn't reconnect
+          // closing the connection with anything left unread (e.g. acks) resets it, which can lose what was last sent
+          while self.event_loop.poll().await.is_ok() {}
+          log::info!("Disconnected from MQTT broker");
+          return Ok(());
+        }
+
+        Ok(_) => {}
+
+        Err(err) => {
+          log::error!("MQTT connection error, reconnecting in {:?}: {}", reconnect_delay, err);
+          heartbeat.beat_after(reconnect_delay + keep_alive);
+          sleep(reconnect_delay).await;
+          reconnect_delay = (reconnect_delay * 2).min(MAX_RECONNECT_DELAY);
         }
       }
     }
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::{filters_overlap, topic_matches, valid_filter};
+
+  #[test]
+  fn valid_filters() {
+    for (filter, valid) in [
+      ("garage/left/set", true),
+      ("+/left/set", true),
+      ("garage/+/set", true),
+      ("garage/left/+", true),
+      ("+/+/+", true),
+      ("#", true),
+      ("garage/#", true),
+      ("garage/+/#", true),
+      // `#` must be the last level
+      ("#/left/set", false),
+      ("garage/#/set", false),
+      // wildcards must be the whole level
+      ("garage/left#", false),
+      ("garage/left+/set", false),
+      ("garage/+left/set", false),
+      // empty levels are allowed, but not an empty filter
+      ("garage//set", true),
+      ("/garage", true),
+      ("garage/", true),
+      ("", false),
+      ("$SYS/#", true),
+    ] {
+      assert_eq!(valid_filter(filter), valid, "{}", filter);
+    }
+  }
+
+  #[test]
+  fn overlapping_filters() {
+    for (a, b, overlap) in [
+      ("garage/left/set", "garage/left/set", true),
+      ("garage/left/set", "garage/right/set", false),
+      ("+/left/set", "garage/left/set", true),
+      ("garage/+/set", "garage/left/set", true),
+      ("garage/left/+", "garage/left/set", true),
+      ("garage/+/set", "garage/left/+", true),
+      ("garage/+/set", "garage/left/get", false),
+      ("garage/+/set", "garage/left", false),
+      ("#", "garage/left/set", true),
+      ("garage/#", "garage/left/set", true),
+      ("garage/left/#", "garage/left", true),
+      ("garage/left/#", "garage/right/#", false),
+      ("garage/+/#", "+/left/set/now", true),
+      // `+` matches an empty level, but not a missing one
+      ("garage//set", "garage/+/set", true),
+      ("garage/", "garage/+", true),
+      ("garage", "garage/+", false),
+      ("garage//set", "garage/set", false),
+      // wildcards in the first level don't match topics starting with `$`
+      ("$SYS/broker/uptime", "#", false),
+      ("$SYS/broker/uptime", "+/broker/uptime", false),
+      ("$SYS/broker/uptime", "$SYS/#", true),
+      ("$SYS/broker/uptime", "$SYS/+/uptime", true),
+      ("$SYS/#", "#", false),
+    ] {
+      assert_eq!(filters_overlap(a, b), overlap, "{} and {}", a, b);
+      assert_eq!(filters_overlap(b, a), overlap, "{} and {}", b, a);
+    }
+  }
+
+  #[test]
+  fn matching_topics() {
+    assert!(topic_matches("garage/+/set", "garage/left/set"));
+    assert!(topic_matches("garage/#", "garage"));
+    assert!(!topic_matches("garage/+/set", "garage/left/get"));
+    assert!(topic_matches("$SYS/#", "$SYS/broker/uptime"));
+    assert!(!topic_matches("#", "$SYS/broker/uptime"));
+  }
+}
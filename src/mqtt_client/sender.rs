@@ -28,7 +28,7 @@ impl MqttSender {
           .client
           .publish(publish.topic, publish.qos, publish.retain, publish.payload)
           .await
-          .map_err(|err| GarageError::from(err))?;
+          .map_err(GarageError::from)?;
       }
       else {
         return Ok(());
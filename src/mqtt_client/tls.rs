@@ -0,0 +1,251 @@
+use std::{fs, io::BufReader, path::PathBuf, sync::Arc, time::SystemTime};
+
+use rumqttc::{TlsConfiguration, Transport};
+use rustls::{
+  client::{ServerCertVerified, ServerCertVerifier},
+  Certificate, ClientConfig, PrivateKey, RootCertStore, ServerName,
+};
+use rustls_pemfile::Item;
+use serde::Deserialize;
+
+use crate::error::{GarageError, GarageResult};
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct MqttTlsConfig {
+  /// A PEM file of the CA certificate(s) the broker's certificate is verified with.
+  ///
+  /// For brokers with publicly trusted certificates this can be the system's bundle, e.g.
+  /// `/etc/ssl/certs/ca-certificates.crt`. Required unless `insecure_skip_verify` is set.
+  pub ca_file: Option<PathBuf>,
+  /// A PEM file of the client certificate, if the broker requires one
+  pub client_cert_file: Option<PathBuf>,
+  /// A PEM file of the client certificate's private key
+  pub client_key_file: Option<PathBuf>,
+  /// Don't verify the broker's certificate at all, this should only be used for testing
+  #[serde(default)]
+  pub insecure_skip_verify: bool,
+}
+
+fn config_error(message: impl Into<String>) -> GarageError {
+  GarageError::MqttClientConfig(message.into())
+}
+
+fn read_pem(path: &PathBuf) -> GarageResult<Vec<Item>> {
+  let file = fs::File::open(path).map_err(|err| config_error(format!("unable to open {}: {}", path.display(), err)))?;
+  rustls_pemfile::read_all(&mut BufReader::new(file))
+    .map_err(|err| config_error(format!("unable to read {}: {}", path.display(), err)))
+}
+
+fn read_certificates(path: &PathBuf) -> GarageResult<Vec<Certificate>> {
+  let certificates: Vec<_> = read_pem(path)?
+    .into_iter()
+    .filter_map(|item| match item {
+      Item::X509Certificate(der) => Some(Certificate(der)),
+      _ => None,
+    })
+    .collect();
+  if certificates.is_empty() {
+    return Err(config_error(format!("no certificates found in {}", path.display())));
+  }
+  Ok(certificates)
+}
+
+fn read_private_key(path: &PathBuf) -> GarageResult<PrivateKey> {
+  read_pem(path)?
+    .into_iter()
+    .find_map(|item| match item {
+      Item::RSAKey(der) | Item::PKCS8Key(der) | Item::ECKey(der) => Some(PrivateKey(der)),
+      _ => None,
+    })
+    .ok_or_else(|| config_error(format!("no private key found in {}", path.display())))
+}
+
+/// Accepts any certificate the broker presents
+struct NoCertificateVerification;
+
+impl ServerCertVerifier for NoCertificateVerification {
+  fn verify_server_cert(
+    &self,
+    _: &Certificate,
+    _: &[Certificate],
+    _: &ServerName,
+    _: &mut dyn Iterator<Item = &[u8]>,
+    _: &[u8],
+    _: SystemTime,
+  ) -> Result<ServerCertVerified, rustls::Error> {
+    Ok(ServerCertVerified::assertion())
+  }
+}
+
+impl MqttTlsConfig {
+  /// Create the TLS transport described by this config, reading any certificates and keys
+  pub fn transport(&self) -> GarageResult<Transport> {
+    Ok(Transport::tls_with_config(TlsConfiguration::Rustls(Arc::new(
+      self.client_config()?,
+    ))))
+  }
+
+  fn client_config(&self) -> GarageResult<ClientConfig> {
+    let mut root_store = RootCertStore::empty();
+    match &self.ca_file {
+      Some(ca_file) => {
+        for certificate in read_certificates(ca_file)? {
+          root_store
+            .add(&certificate)
+            .map_err(|err| config_error(format!("invalid CA certificate in {}: {}", ca_file.display(), err)))?;
+        }
+      }
+      None if !self.insecure_skip_verify => {
+        return Err(config_error(
+          "TLS requires a ca_file unless insecure_skip_verify is set",
+        ))
+      }
+      None => {}
+    }
+
+    let builder = ClientConfig::builder()
+      .with_safe_defaults()
+      .with_root_certificates(root_store);
+    let mut config = match (&self.client_cert_file, &self.client_key_file) {
+      (Some(cert_file), Some(key_file)) => builder
+        .with_single_cert(read_certificates(cert_file)?, read_private_key(key_file)?)
+        .map_err(|err| config_error(format!("invalid client certificate: {}", err)))?,
+      (None, None) => builder.with_no_client_auth(),
+      _ => {
+        return Err(config_error(
+          "client_cert_file and client_key_file must be set together",
+        ))
+      }
+    };
+
+    if self.insecure_skip_verify {
+      log::warn!("MQTT broker's TLS certificate will not be verified");
+      config
+        .dangerous()
+        .set_certificate_verifier(Arc::new(NoCertificateVerification));
+    }
+
+    Ok(config)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{path::PathBuf, sync::Arc};
+
+  use rustls::{server::AllowAnyAuthenticatedClient, ClientConfig, ClientConnection, Connection, ServerConfig};
+
+  use super::{read_certificates, read_private_key, MqttTlsConfig};
+  use crate::error::GarageError;
+
+  fn testdata(file: &str) -> PathBuf {
+    [env!("CARGO_MANIFEST_DIR"), "testdata", "tls", file].iter().collect()
+  }
+
+  fn config(ca_file: Option<&str>, client: Option<(&str, &str)>, insecure_skip_verify: bool) -> MqttTlsConfig {
+    MqttTlsConfig {
+      ca_file: ca_file.map(testdata),
+      client_cert_file: client.map(|(cert, _)| testdata(cert)),
+      client_key_file: client.map(|(_, key)| testdata(key)),
+      insecure_skip_verify,
+    }
+  }
+
+  fn error(config: MqttTlsConfig) -> String {
+    match config.client_config() {
+      Err(GarageError::MqttClientConfig(message)) => message,
+      result => panic!("expected a config error, got {:?}", result.map(|_| ())),
+    }
+  }
+
+  /// Send everything `from` has to send to `to`
+  fn transfer(from: &mut Connection, to: &mut Connection) -> Result<(), rustls::Error> {
+    let mut buffer = Vec::new();
+    while from.wants_write() {
+      from.write_tls(&mut buffer).unwrap();
+    }
+    let mut reader = &buffer[..];
+    while !reader.is_empty() {
+      to.read_tls(&mut reader).unwrap();
+      to.process_new_packets()?;
+    }
+    Ok(())
+  }
+
+  /// Connect to a broker presenting `broker`'s certificate, which requires a client certificate signed by the CA
+  fn handshake(client_config: ClientConfig, broker: &str) -> Result<(), rustls::Error> {
+    let mut client_roots = rustls::RootCertStore::empty();
+    for certificate in read_certificates(&testdata("ca.pem")).unwrap() {
+      client_roots.add(&certificate).unwrap();
+    }
+    let server_config = ServerConfig::builder()
+      .with_safe_defaults()
+      .with_client_cert_verifier(AllowAnyAuthenticatedClient::new(client_roots))
+      .with_single_cert(
+        read_certificates(&testdata(&format!("{}.pem", broker))).unwrap(),
+        read_private_key(&testdata(&format!("{}-key.pem", broker))).unwrap(),
+      )
+      .unwrap();
+
+    let mut client = Connection::from(ClientConnection::new(
+      Arc::new(client_config),
+      "localhost".try_into().unwrap(),
+    )?);
+    let mut server = Connection::from(rustls::ServerConnection::new(Arc::new(server_config))?);
+    while client.is_handshaking() || server.is_handshaking() {
+      transfer(&mut client, &mut server)?;
+      transfer(&mut server, &mut client)?;
+    }
+    Ok(())
+  }
+
+  #[test]
+  fn connects_with_a_client_certificate() {
+    let config = config(Some("ca.pem"), Some(("client.pem", "client-key.pem")), false);
+    handshake(config.client_config().unwrap(), "broker").unwrap();
+    assert!(config.transport().is_ok());
+  }
+
+  #[test]
+  fn broker_can_reject_a_missing_client_certificate() {
+    let config = config(Some("ca.pem"), None, false);
+    assert!(handshake(config.client_config().unwrap(), "broker").is_err());
+  }
+
+  #[test]
+  fn broker_is_verified_with_the_ca() {
+    let config = config(Some("ca.pem"), Some(("client.pem", "client-key.pem")), false);
+    assert!(handshake(config.client_config().unwrap(), "untrusted").is_err());
+  }
+
+  #[test]
+  fn insecure_skip_verify_accepts_any_broker() {
+    let config = config(None, Some(("client.pem", "client-key.pem")), true);
+    handshake(config.client_config().unwrap(), "untrusted").unwrap();
+  }
+
+  #[test]
+  fn ca_is_required_unless_skipping_verification() {
+    assert!(error(config(None, None, false)).contains("requires a ca_file"));
+  }
+
+  #[test]
+  fn client_certificate_and_key_are_required_together() {
+    let mut config = config(Some("ca.pem"), Some(("client.pem", "client-key.pem")), false);
+    config.client_key_file = None;
+    assert!(error(config).contains("must be set together"));
+  }
+
+  #[test]
+  fn missing_pem_file() {
+    assert!(error(config(Some("missing.pem"), None, false)).starts_with("unable to open"));
+  }
+
+  #[test]
+  fn garbage_pem_file() {
+    assert!(error(config(Some("garbage.pem"), None, false)).starts_with("no certificates found"));
+    assert!(
+      error(config(Some("ca.pem"), Some(("client.pem", "garbage.pem")), false)).starts_with("no private key found")
+    );
+  }
+}
@@ -0,0 +1,363 @@
+//! Reporting the service's status to systemd, see sd_notify(3)
+
+use std::{
+  collections::{BTreeMap, BTreeSet},
+  env, fmt,
+  os::unix::net::{SocketAddr, UnixDatagram},
+  process,
+  sync::{Arc, Mutex, MutexGuard, PoisonError},
+  time::Duration,
+};
+
+use tokio::{
+  select,
+  sync::watch,
+  time::{self, Instant},
+};
+
+/// How long a task can go without a heartbeat when systemd's watchdog isn't enabled
+const DEFAULT_HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Sends notifications to systemd, if the service was started by it with `Type=notify`
+#[derive(Debug, Clone)]
+pub struct Notifier {
+  socket: Option<Arc<(UnixDatagram, SocketAddr)>>,
+}
+
+impl Notifier {
+  /// Connect to the socket named by `$NOTIFY_SOCKET`, doing nothing if it isn't set
+  pub fn from_env() -> Self {
+    env::var_os("NOTIFY_SOCKET")
+      .and_then(|path| {
+        Self::connect(path.to_string_lossy().as_ref())
+          .map_err(|err| log::error!("Unable to use systemd's notify socket {:?}: {}", path, err))
+          .ok()
+      })
+      .unwrap_or(Notifier { socket: None })
+  }
+
+  /// Send notifications to the socket at `path`, or the abstract socket named after an `@`
+  fn connect(path: &str) -> std::io::Result<Self> {
+    let socket = UnixDatagram::unbound()?;
+    let address = Self::address(path)?;
+    Ok(Notifier {
+      socket: Some(Arc::new((socket, address))),
+    })
+  }
+
+  fn address(path: &str) -> std::io::Result<SocketAddr> {
+    match path.strip_prefix('@') {
+      #[cfg(target_os = "linux")]
+      Some(name) => <SocketAddr as std::os::linux::net::SocketAddrExt>::from_abstract_name(name),
+      #[cfg(not(target_os = "linux"))]
+      Some(_) => Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "abstract sockets are only supported on Linux",
+      )),
+      None => SocketAddr::from_pathname(path),
+    }
+  }
+
+  /// Send `KEY=value` assignments, one per line
+  fn notify(&self, state: &str) {
+    if let Some(socket) = &self.socket {
+      let (socket, address) = socket.as_ref();
+      if let Err(err) = socket.send_to_addr(state.as_bytes(), address) {
+        log::warn!("Unable to notify systemd: {}", err);
+      }
+    }
+  }
+
+  /// The service has started
+  pub fn ready(&self) {
+    self.notify("READY=1");
+  }
+
+  /// The service is shutting down
+  pub fn stopping(&self) {
+    self.notify("STOPPING=1");
+  }
+
+  /// A single line describing what the service is doing
+  pub fn status(&self, status: &str) {
+    self.notify(&format!("STATUS={}", status.replace('\n', " ")));
+  }
+
+  /// The service is still healthy
+  fn watchdog(&self) {
+    self.notify("WATCHDOG=1");
+  }
+
+  /// How often systemd expects to be told the service is healthy, if its watchdog is enabled for this process
+  fn watchdog_timeout() -> Option<Duration> {
+    let pid = env::var("WATCHDOG_PID").ok();
+    if pid.is_some_and(|pid| pid != process::id().to_string()) {
+      // the watchdog is for another process (e.g. the one that started us)
+      return None;
+    }
+
+    let usec = env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    Some(Duration::from_micros(usec)).filter(|timeout| !timeout.is_zero())
+  }
+}
+
+/// When each task is next due to beat, by name
+type Heartbeats = Arc<Mutex<BTreeMap<String, Instant>>>;
+
+fn lock(heartbeats: &Heartbeats) -> MutexGuard<'_, BTreeMap<String, Instant>> {
+  heartbeats.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Proof a long-running task is still making progress, removed once the task ends (i.e. the heartbeat is dropped)
+pub struct Heartbeat {
+  name: String,
+  /// How long the task can go without beating
+  timeout: Duration,
+  heartbeats: Heartbeats,
+}
+
+impl fmt::Debug for Heartbeat {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "Heartbeat ({})", self.name)
+  }
+}
+
+impl Heartbeat {
+  /// The task is making progress
+  pub fn beat(&self) {
+    self.beat_after(Duration::ZERO);
+  }
+
+  /// The task is making progress, but is about to wait for `delay` before it can beat again
+  pub fn beat_after(&self, delay: Duration) {
+    lock(&self.heartbeats).insert(self.name.clone(), Instant::now() + delay + self.timeout);
+  }
+
+  /// How often an otherwise idle task should beat
+  pub fn interval(&self) -> Duration {
+    self.timeout / 2
+  }
+}
+
+impl Drop for Heartbeat {
+  fn drop(&mut self) {
+    lock(&self.heartbeats).remove(&self.name);
+  }
+}
+
+/// Collects the state of the doors and the heartbeats of the service's tasks, reporting them to systemd
+#[derive(Debug, Clone)]
+pub struct ServiceMonitor {
+  notifier: Notifier,
+  /// The state of each door which has been initialised
+  door_states: Arc<watch::Sender<BTreeMap<String, String>>>,
+  heartbeats: Heartbeats,
+  watchdog_timeout: Option<Duration>,
+}
+
+impl ServiceMonitor {
+  pub fn new(notifier: Notifier) -> Self {
+    ServiceMonitor {
+      notifier,
+      door_states: Arc::new(watch::channel(BTreeMap::new()).0),
+      heartbeats: Arc::default(),
+      watchdog_timeout: Notifier::watchdog_timeout(),
+    }
+  }
+
+  /// Start tracking a task's heartbeat, which must beat at least every [`Heartbeat::interval`]
+  pub fn heartbeat(&self, name: String) -> Heartbeat {
+    // systemd is pinged every half timeout, so a stalled task has to be noticed within the other half
+    let timeout = self
+      .watchdog_timeout
+      .map_or(DEFAULT_HEARTBEAT_TIMEOUT, |timeout| timeout / 2);
+    let heartbeat = Heartbeat {
+      name,
+      timeout,
+      heartbeats: self.heartbeats.clone(),
+    };
+    heartbeat.beat();
+    heartbeat
+  }
+
+  /// A door has been initialised or changed state
+  pub fn set_door_state(&self, door: &str, state: String) {
+    self
+      .door_states
+      .send_if_modified(|states| states.insert(door.to_owned(), state.clone()).as_ref() != Some(&state));
+  }
+
+  /// A door has stopped
+  pub fn remove_door(&self, door: &str) {
+    self
+      .door_states
+      .send_if_modified(|states| states.remove(door).is_some());
+  }
+
+  /// The tasks which are overdue a heartbeat
+  fn stalled(&self) -> Vec<String> {
+    let now = Instant::now();
+    lock(&self.heartbeats)
+      .iter()
+      .filter(|(_, due)| **due < now)
+      .map(|(name, _)| name.clone())
+      .collect()
+  }
+
+  /// Report to systemd until the service stops.
+  ///
+  /// The service is ready once each of `doors` has been initialised, after which the doors' states are reported as
+  /// they change. The watchdog is pinged only while every task's heartbeat is on time, so systemd restarts the service
+  /// if any of them stall.
+  pub async fn report(self, mut doors: BTreeSet<String>) {
+    let mut door_states = self.door_states.subscribe();
+    let mut watchdog = time::interval(
+      self
+        .watchdog_timeout
+        .map_or(DEFAULT_HEARTBEAT_TIMEOUT, |timeout| timeout / 2),
+    );
+    let mut stalled = Vec::new();
+    if doors.is_empty() {
+      self.notifier.ready();
+    }
+
+    loop {
+      select! {
+        Ok(()) = door_states.changed() => {
+          let states = door_states.borrow_and_update().clone();
+          if !doors.is_empty() {
+            doors.retain(|door| !states.contains_key(door));
+            if doors.is_empty() {
+              log::info!("All doors initialised");
+              self.notifier.ready();
+            }
+          }
+
+          let summary: Vec<String> = states.iter().map(|(door, state)| format!("{}: {}", door, state)).collect();
+          self.notifier.status(&summary.join(", "));
+        }
+
+        _ = watchdog.tick() => {
+          let now_stalled = self.stalled();
+          if now_stalled != stalled && !now_stalled.is_empty() {
+            log::error!("Stalled, not responding: {}", now_stalled.join(", "));
+          }
+          stalled = now_stalled;
+
+          if stalled.is_empty() && self.watchdog_timeout.is_some() {
+            self.notifier.watchdog();
+          }
+        }
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{collections::BTreeSet, path::PathBuf, time::Duration};
+
+  use tokio::{net::UnixDatagram, time};
+
+  use super::{Notifier, ServiceMonitor};
+
+  /// A socket standing in for systemd's, removed once dropped
+  struct NotifySocket {
+    path: PathBuf,
+    socket: UnixDatagram,
+  }
+
+  impl NotifySocket {
+    fn bind(name: &str) -> Self {
+      let path = std::env::temp_dir().join(format!("mqtt-garage-{}-{}.sock", name, std::process::id()));
+      std::fs::remove_file(&path).ok();
+      let socket = UnixDatagram::bind(&path).unwrap();
+      NotifySocket { path, socket }
+    }
+
+    fn notifier(&self) -> Notifier {
+      Notifier::connect(self.path.to_str().unwrap()).unwrap()
+    }
+
+    async fn recv(&self) -> String {
+      let mut buf = [0; 1024];
+      let len = time::timeout(Duration::from_secs(5), self.socket.recv(&mut buf))
+        .await
+        .expect("no notification was sent")
+        .unwrap();
+      String::from_utf8(buf[..len].to_vec()).unwrap()
+    }
+
+    /// Whether anything is sent within `duration`, after discarding what has already been sent
+    async fn is_silent_for(&self, duration: Duration) -> bool {
+      let mut buf = [0; 1024];
+      while self.socket.try_recv(&mut buf).is_ok() {}
+      time::timeout(duration, self.socket.recv(&mut buf)).await.is_err()
+    }
+  }
+
+  impl Drop for NotifySocket {
+    fn drop(&mut self) {
+      std::fs::remove_file(&self.path).ok();
+    }
+  }
+
+  #[tokio::test]
+  async fn notifications_are_sent_to_the_socket() {
+    let socket = NotifySocket::bind("notify");
+    let notifier = socket.notifier();
+
+    notifier.ready();
+    assert_eq!(socket.recv().await, "READY=1");
+    notifier.status("left: open\nright: closed");
+    assert_eq!(socket.recv().await, "STATUS=left: open right: closed");
+    notifier.watchdog();
+    assert_eq!(socket.recv().await, "WATCHDOG=1");
+    notifier.stopping();
+    assert_eq!(socket.recv().await, "STOPPING=1");
+  }
+
+  #[tokio::test]
+  async fn ready_once_doors_are_initialised() {
+    let socket = NotifySocket::bind("ready");
+    let monitor = ServiceMonitor {
+      watchdog_timeout: Some(Duration::from_millis(200)),
+      ..ServiceMonitor::new(socket.notifier())
+    };
+    let _heartbeat = monitor.heartbeat("door left".to_owned());
+    tokio::spawn(monitor.clone().report(BTreeSet::from(["left".to_owned()])));
+
+    // nothing has stalled, so the watchdog is pinged straight away
+    assert_eq!(socket.recv().await, "WATCHDOG=1");
+
+    monitor.set_door_state("left", "closed".to_owned());
+    let mut notifications = Vec::new();
+    while notifications.len() < 2 {
+      let notification = socket.recv().await;
+      if notification != "WATCHDOG=1" {
+        notifications.push(notification);
+      }
+    }
+    assert_eq!(notifications, ["READY=1", "STATUS=left: closed"]);
+  }
+
+  #[tokio::test]
+  async fn watchdog_not_pinged_while_a_task_is_stalled() {
+    let socket = NotifySocket::bind("stalled");
+    let monitor = ServiceMonitor {
+      watchdog_timeout: Some(Duration::from_millis(200)),
+      ..ServiceMonitor::new(socket.notifier())
+    };
+    let heartbeat = monitor.heartbeat("door left".to_owned());
+    tokio::spawn(monitor.clone().report(BTreeSet::new()));
+    assert_eq!(socket.recv().await, "READY=1");
+    assert_eq!(socket.recv().await, "WATCHDOG=1");
+
+    // the heartbeat has to beat within half the watchdog's timeout
+    time::sleep(Duration::from_millis(150)).await;
+    assert!(socket.is_silent_for(Duration::from_millis(300)).await);
+
+    heartbeat.beat();
+    assert_eq!(socket.recv().await, "WATCHDOG=1");
+  }
+}
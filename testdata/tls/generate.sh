@@ -0,0 +1,32 @@
+#!/bin/sh
+# Regenerates the certificates used by the TLS tests: a CA, a broker and client certificate signed by it, and a
+# self-signed broker certificate which the CA doesn't trust
+set -e
+cd "$(dirname "$0")"
+
+key() {
+  openssl genpkey -algorithm EC -pkeyopt ec_paramgen_curve:P-256 -out "$1"
+}
+
+key ca-key.pem
+openssl req -x509 -new -key ca-key.pem -days 36500 -subj "/CN=mqtt-garage test CA" \
+  -addext "basicConstraints=critical,CA:TRUE" -addext "keyUsage=critical,keyCertSign,cRLSign" -out ca.pem
+
+sign() {
+  key "$1-key.pem"
+  openssl req -new -key "$1-key.pem" -subj "/CN=$2" -out "$1.csr"
+  printf "basicConstraints=CA:FALSE\nkeyUsage=critical,digitalSignature\nextendedKeyUsage=%s\nsubjectAltName=DNS:%s\n" \
+    "$3" "$2" > "$1.ext"
+  openssl x509 -req -in "$1.csr" -CA ca.pem -CAkey ca-key.pem -CAcreateserial -days 36500 -extfile "$1.ext" \
+    -out "$1.pem"
+  rm "$1.csr" "$1.ext"
+}
+
+sign broker localhost serverAuth
+sign client garage clientAuth
+rm ca-key.pem ca.srl
+
+key untrusted-key.pem
+openssl req -x509 -new -key untrusted-key.pem -days 36500 -subj "/CN=localhost" \
+  -addext "basicConstraints=critical,CA:FALSE" -addext "extendedKeyUsage=serverAuth" \
+  -addext "subjectAltName=DNS:localhost" -out untrusted.pem